@@ -0,0 +1,77 @@
+// Compares reading the pages of a BTreeStore with seek and read and through a memory map of the store file.
+// Lookups and scans read the keys in place in both cases (the pages of the cache or of the mapping), no page is parsed.
+// cargo bench --features mmap [-- <number of keys>]
+use std::{env, hint::black_box, time::{Duration, Instant}};
+
+use algos_test::page_based_bplustree::btree_store::{BTreeStore, IndexMode, PagerMode};
+use tempfile::NamedTempFile;
+
+const MAX_DEGREE: u16 = 64;
+// a small cache, so that most pages are read from the file
+const CACHE_PAGES: usize = 16;
+const LOOKUPS: u32 = 200_000;
+const ROUNDS: usize = 5;
+const INSERT_BATCH: usize = 10_000;
+
+// Pseudo random keys in 0..number_of_keys, the same for every run
+fn keys(number_of_keys: u32) -> impl Iterator<Item = u32> {
+    let mut seed: u32 = 17;
+    (0..LOOKUPS).map(move |_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        seed % number_of_keys
+    })
+}
+
+// Best time of several rounds
+fn measure(mut run: impl FnMut()) -> Duration {
+    (0..ROUNDS).map(|_| {
+        let start = Instant::now();
+        run();
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn report(name: &str, operations: u32, file: Duration, mmap: Duration) {
+    let per_operation = |duration: Duration| duration.as_nanos() as f64 / operations as f64;
+    println!("{:<11} file {:>8.0} ns/op   mmap {:>8.0} ns/op   speedup {:.2}",
+        name, per_operation(file), per_operation(mmap), file.as_secs_f64() / mmap.as_secs_f64());
+}
+
+fn bench(pager_mode: PagerMode, number_of_keys: u32) {
+    let temp = NamedTempFile::new().unwrap();
+    let mut btree = BTreeStore::<u32>::open_with_pager_mode(temp.path(), MAX_DEGREE, IndexMode::Unique, pager_mode, CACHE_PAGES).unwrap();
+    for batch in (0..number_of_keys).collect::<Vec<_>>().chunks(INSERT_BATCH) {
+        let mut transaction = btree.begin();
+        for key in batch {
+            transaction.insert(key, *key).unwrap();
+        }
+        transaction.commit().unwrap();
+    }
+    btree.checkpoint().unwrap();
+
+    let mut times = Vec::new();
+    for mmap in [false, true] {
+        btree.set_mmap(mmap);
+        let find = measure(|| {
+            for key in keys(number_of_keys) {
+                black_box(btree.find(key).unwrap());
+            }
+        });
+        let scan = measure(|| {
+            black_box(btree.range(..).unwrap().count());
+        });
+        times.push((find, scan));
+    }
+
+    report(&format!("{:?} find", pager_mode), LOOKUPS, times[0].0, times[1].0);
+    report(&format!("{:?} scan", pager_mode), number_of_keys, times[0].1, times[1].1);
+}
+
+fn main() {
+    // cargo bench passes --bench
+    let number_of_keys = env::args().skip(1).find_map(|arg| arg.parse().ok()).unwrap_or(1_000_000);
+    println!("{} keys, max degree {}, {} cached pages", number_of_keys, MAX_DEGREE, CACHE_PAGES);
+    for pager_mode in [PagerMode::Wal, PagerMode::Shadow] {
+        bench(pager_mode, number_of_keys);
+    }
+}
@@ -0,0 +1,451 @@
+use std::{env, fmt::Display, fs::File, io::{self, Write}, ops::Bound, path::Path, process::ExitCode};
+
+use algos_test::page_based_bplustree::{btree_store::{BTreeStore, FORMAT_VERSION, IndexMode, PagerMode, StoreMetaData}, key::{self, StoreKey}};
+
+const USAGE: &str = "\
+Usage: btree [--keys u32|u64|i64|string|hex] <command> <file> [arguments]
+
+Commands:
+  create <file> [--degree <n>] [--multimap] [--shadow]
+                                              create a new store (--shadow: shadow paging instead of a WAL)
+  get <file> <key>                            print the values of the key
+  put <file> <key> <value>                    insert or overwrite a value
+  del <file> <key> [<value>]                  delete the key (or a single value of a multimap)
+  scan <file> [<from> [<to>]]                 print all entries from (inclusive) to (exclusive)
+  dump-page <file> <page_id>                  print the content of a page
+  header <file>                               print the header of the store
+  check <file>                                check the tree and the free list
+  stats <file>                                print the size of the tree
+
+The key type is taken from the header of the store, --keys overrides it and must match the key type of the store.
+New stores have u32 keys by default, hex keys are raw bytes.
+Only put and del write the store, the other commands open it read-only.";
+
+const DEFAULT_MAX_DEGREE: u16 = 16;
+const DEFAULT_KEYS: &str = "u32";
+
+// The key types of the command line with the tags of their stores (see StoreKey::TYPE_ID)
+const KEY_TYPES: [(&str, u32); 5] = [
+    ("u32", u32::TYPE_ID), ("u64", u64::TYPE_ID), ("i64", i64::TYPE_ID), ("string", String::TYPE_ID), ("hex", Vec::<u8>::TYPE_ID),
+];
+
+// Keys, which can be given on the command line
+trait CliKey: StoreKey {
+    fn parse(arg: &str) -> Result<Self, String>;
+    fn format(&self) -> String;
+}
+
+macro_rules! number_key {
+    ($($t:ty),*) => {
+        $(
+            impl CliKey for $t {
+                fn parse(arg: &str) -> Result<Self, String> {
+                    arg.parse().map_err(|e| format!("Invalid key {}: {}", arg, e))
+                }
+
+                fn format(&self) -> String {
+                    self.to_string()
+                }
+            }
+        )*
+    };
+}
+
+number_key!(u32, u64, i64);
+
+impl CliKey for String {
+    fn parse(arg: &str) -> Result<Self, String> {
+        Ok(arg.to_owned())
+    }
+
+    fn format(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl CliKey for Vec<u8> {
+    fn parse(arg: &str) -> Result<Self, String> {
+        parse_hex(arg)
+    }
+
+    fn format(&self) -> String {
+        hex(self)
+    }
+}
+
+fn parse_hex(arg: &str) -> Result<Vec<u8>, String> {
+    if !arg.len().is_multiple_of(2) {
+        return Err(format!("Invalid hex key {}: odd number of digits", arg));
+    }
+    (0..arg.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&arg[i..i + 2], 16).map_err(|e| format!("Invalid hex key {}: {}", arg, e)))
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn option(value: &Option<u32>) -> String {
+    value.map_or("none".to_owned(), |value| value.to_string())
+}
+
+fn parse<T: std::str::FromStr<Err: Display>>(arg: &str, name: &str) -> Result<T, String> {
+    arg.parse().map_err(|e| format!("Invalid {} {}: {}", name, arg, e))
+}
+
+// Command line arguments without the program name
+struct Args {
+    keys: Option<String>, // None: the key type of the store
+    degree: u16,
+    multimap: bool,
+    shadow: bool,
+    positional: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = Args { keys: None, degree: DEFAULT_MAX_DEGREE, multimap: false, shadow: false, positional: Vec::new() };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--keys" => parsed.keys = Some(args.next().ok_or("--keys needs a key type")?.clone()),
+                "--degree" => parsed.degree = parse(args.next().ok_or("--degree needs a number")?, "degree")?,
+                "--multimap" => parsed.multimap = true,
+                "--shadow" => parsed.shadow = true,
+                _ => parsed.positional.push(arg.clone()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn get(&self, index: usize, name: &str) -> Result<&str, String> {
+        self.positional.get(index).map(String::as_str).ok_or_else(|| format!("Missing argument <{}>\n\n{}", name, USAGE))
+    }
+}
+
+// The key type recorded in the header of the store
+fn keys_of_store(path: &Path) -> Result<&'static str, String> {
+    let key_type = *StoreMetaData::read(path).map_err(|e| e.to_string())?.key_type();
+    KEY_TYPES.iter().find(|(_, type_id)| *type_id == key_type).map(|(keys, _)| *keys)
+        .ok_or_else(|| format!("{} has keys of the type {:#010x}, which the command line does not support", path.display(), key_type))
+}
+
+fn run(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let args = Args::parse(args)?;
+    let keys = match &args.keys {
+        Some(keys) => keys.as_str(),
+        None if args.get(0, "command")? == "create" => DEFAULT_KEYS,
+        None => keys_of_store(Path::new(args.get(1, "file")?))?,
+    };
+    match keys {
+        "u32" => run_command::<u32>(&args, out),
+        "u64" => run_command::<u64>(&args, out),
+        "i64" => run_command::<i64>(&args, out),
+        "string" => run_command::<String>(&args, out),
+        "hex" => run_command::<Vec<u8>>(&args, out),
+        keys => Err(format!("Unknown key type {}", keys)),
+    }
+}
+
+fn run_command<K: CliKey>(args: &Args, out: &mut dyn Write) -> Result<(), String> {
+    let command = args.get(0, "command")?;
+    let path = Path::new(args.get(1, "file")?);
+    let write_error = |e: io::Error| e.to_string();
+
+    if command == "create" {
+        if path.exists() {
+            return Err(format!("{} already exists", path.display()));
+        }
+        File::create(path).map_err(|e| e.to_string())?;
+        let index_mode = if args.multimap { IndexMode::NonUnique } else { IndexMode::Unique };
+        let pager_mode = if args.shadow { PagerMode::Shadow } else { PagerMode::Wal };
+        let store = BTreeStore::<K>::open_with_pager_mode(path, args.degree, index_mode, pager_mode, 1).map_err(|e| e.to_string())?;
+        return writeln!(out, "created {} (max degree {}, page size {}, {:?})", path.display(), args.degree, store.page_size(), index_mode).map_err(write_error);
+    }
+
+    // the inspecting commands do not write the store, so they do not recover it either
+    let mut store = match command {
+        "put" | "del" => BTreeStore::<K>::open_existing(path),
+        _ => BTreeStore::<K>::open_read_only(path),
+    }.map_err(|e| e.to_string())?;
+    match command {
+        "get" => {
+            let key = K::parse(args.get(2, "key")?)?;
+            for value in store.find_all(key).map_err(|e| e.to_string())? {
+                writeln!(out, "{}", value.map_err(|e| e.to_string())?).map_err(write_error)?;
+            }
+        },
+        "put" => {
+            let key = K::parse(args.get(2, "key")?)?;
+            let value = parse(args.get(3, "value")?, "value")?;
+            if let Some(previous) = store.insert(key, value).map_err(|e| e.to_string())? {
+                writeln!(out, "replaced {}", previous).map_err(write_error)?;
+            }
+        },
+        "del" => {
+            let key = K::parse(args.get(2, "key")?)?;
+            let deleted = match args.positional.get(3) {
+                Some(value) => store.delete_one(key, parse(value, "value")?).map_err(|e| e.to_string())?,
+                None => store.delete(key).map_err(|e| e.to_string())?.is_some(),
+            };
+            if !deleted {
+                return Err("Key not found".to_owned());
+            }
+        },
+        "scan" => {
+            let from = args.positional.get(2).map(|arg| K::parse(arg)).transpose()?;
+            let to = args.positional.get(3).map(|arg| K::parse(arg)).transpose()?;
+            let range = (from.map_or(Bound::Unbounded, Bound::Included), to.map_or(Bound::Unbounded, Bound::Excluded));
+            for entry in store.range(range).map_err(|e| e.to_string())? {
+                let (key, value) = entry.map_err(|e| e.to_string())?;
+                writeln!(out, "{}\t{}", key.format(), value).map_err(write_error)?;
+            }
+        },
+        "dump-page" => dump_page(&store, parse(args.get(2, "page_id")?, "page id")?, out)?,
+        "header" => {
+            let meta_data = store.meta_data();
+            writeln!(out, "format_version: {}", FORMAT_VERSION).map_err(write_error)?;
+            writeln!(out, "page_size: {}", meta_data.page_size()).map_err(write_error)?;
+            match meta_data.key_width() {
+                0 => writeln!(out, "key_width: variable").map_err(write_error)?,
+                width => writeln!(out, "key_width: {}", width).map_err(write_error)?,
+            }
+            writeln!(out, "key_type: {:#010x}", meta_data.key_type()).map_err(write_error)?;
+            writeln!(out, "max_degree: {}", meta_data.max_degree()).map_err(write_error)?;
+            writeln!(out, "number_of_pages: {}", meta_data.number_of_pages()).map_err(write_error)?;
+            writeln!(out, "first_deleted_page: {}", option(meta_data.first_deleted_page())).map_err(write_error)?;
+            writeln!(out, "root: {}", option(meta_data.root())).map_err(write_error)?;
+            writeln!(out, "index_mode: {:?}", meta_data.index_mode()).map_err(write_error)?;
+            writeln!(out, "pager_mode: {:?}", meta_data.pager_mode()).map_err(write_error)?;
+            if *meta_data.pager_mode() == PagerMode::Shadow {
+                writeln!(out, "sequence: {}", meta_data.sequence()).map_err(write_error)?;
+                writeln!(out, "page_table: {}", option(meta_data.page_table())).map_err(write_error)?;
+            }
+        },
+        "check" => {
+            let report = store.check();
+            for problem in report.problems() {
+                writeln!(out, "error: {:?}", problem).map_err(write_error)?;
+            }
+            if !report.leaked_pages().is_empty() {
+                writeln!(out, "error: leaked pages {:?}", report.leaked_pages()).map_err(write_error)?;
+            }
+            for warning in report.warnings() {
+                writeln!(out, "warning: {:?}", warning).map_err(write_error)?;
+            }
+            if !report.is_ok() {
+                return Err(format!("{} is inconsistent", path.display()));
+            }
+            writeln!(out, "ok: {} entries in {} pages", report.entries(), report.tree_pages()).map_err(write_error)?;
+        },
+        "stats" => {
+            let report = store.check();
+            let file_size = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+            writeln!(out, "file_size: {}", file_size).map_err(write_error)?;
+            writeln!(out, "page_size: {}", store.page_size()).map_err(write_error)?;
+            writeln!(out, "pages: {}", report.number_of_pages()).map_err(write_error)?;
+            writeln!(out, "tree_pages: {}", report.tree_pages()).map_err(write_error)?;
+            writeln!(out, "free_pages: {}", report.free_pages().len()).map_err(write_error)?;
+            writeln!(out, "leaked_pages: {}", report.leaked_pages().len()).map_err(write_error)?;
+            writeln!(out, "depth: {}", report.depth()).map_err(write_error)?;
+            writeln!(out, "entries: {}", report.entries()).map_err(write_error)?;
+        },
+        command => return Err(format!("Unknown command {}\n\n{}", command, USAGE)),
+    }
+
+    Ok(())
+}
+
+fn dump_page<K: CliKey>(store: &BTreeStore<K>, page_id: u32, out: &mut dyn Write) -> Result<(), String> {
+    let page = store.read_page(page_id).map_err(|e| e.to_string())?;
+    let write_error = |e: io::Error| e.to_string();
+
+    writeln!(out, "page_id: {}", page.id()).map_err(write_error)?;
+    writeln!(out, "deleted: {}", page.deleted()).map_err(write_error)?;
+    if *page.deleted() {
+        return writeln!(out, "next_deleted_page: {}", option(page.next_deleted_page())).map_err(write_error);
+    }
+    writeln!(out, "kind: {}", if page.is_leaf() { "leaf" } else { "internal" }).map_err(write_error)?;
+    writeln!(out, "used_bytes: {}/{}", page.used_bytes(), page.capacity()).map_err(write_error)?;
+    if page.is_leaf() {
+        writeln!(out, "next_leaf: {}", option(page.next_leaf())).map_err(write_error)?;
+    } else {
+        writeln!(out, "first_child: {}", page.children()[0]).map_err(write_error)?;
+    }
+
+    writeln!(out, "cells: {}", page.keys().len()).map_err(write_error)?;
+    for (i, encoded_key) in page.keys().iter().enumerate() {
+        // the key of a multimap entry is followed by its value
+        let key_size = match store.index_mode() {
+            IndexMode::Unique => encoded_key.len(),
+            IndexMode::NonUnique => encoded_key.len().saturating_sub(4),
+        };
+        let decoded = key::decode::<K>(&encoded_key[..key_size]).map_or("?".to_owned(), |key| key.format());
+        let pointer = if page.is_leaf() { format!("value {}", page.values()[i]) } else { format!("child {}", page.children()[i + 1]) };
+        writeln!(out, "  {}: {} ({}) -> {}", i, hex(encoded_key), decoded, pointer).map_err(write_error)?;
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() || args[0] == "--help" {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    match run(&args, &mut io::stdout()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(msg) => {
+            eprintln!("error: {}", msg);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use algos_test::page_based_bplustree::{btree_store::BTreeStore, wal::Wal};
+    use tempfile::TempDir;
+
+    use super::run;
+
+    fn btree(args: &str) -> Result<String, String> {
+        let args = args.split_whitespace().map(String::from).collect::<Vec<_>>();
+        let mut out = Vec::new();
+        run(&args, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn create_put_get_scan_and_delete() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("store");
+        let file = file.display();
+
+        btree(&format!("create {} --degree 4", file)).unwrap();
+        assert!(btree(&format!("create {}", file)).is_err());
+        for key in 0..20 {
+            btree(&format!("put {} {} {}", file, key, key * 10)).unwrap();
+        }
+        assert_eq!(btree(&format!("put {} 3 33", file)).unwrap(), "replaced 30\n");
+
+        assert_eq!(btree(&format!("get {} 3", file)).unwrap(), "33\n");
+        assert_eq!(btree(&format!("get {} 30", file)).unwrap(), "");
+        assert_eq!(btree(&format!("scan {} 17", file)).unwrap(), "17\t170\n18\t180\n19\t190\n");
+        assert_eq!(btree(&format!("scan {} 2 4", file)).unwrap(), "2\t20\n3\t33\n");
+
+        btree(&format!("del {} 3", file)).unwrap();
+        assert!(btree(&format!("del {} 3", file)).is_err());
+        assert_eq!(btree(&format!("scan {} 2 5", file)).unwrap(), "2\t20\n4\t40\n");
+
+        assert!(btree(&format!("get {} x", file)).is_err());
+        assert!(btree(&format!("frobnicate {}", file)).is_err());
+    }
+
+    #[test]
+    fn inspect_header_pages_and_stats() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("store");
+        let file = file.display();
+
+        btree(&format!("--keys string create {} --degree 8 --multimap", file)).unwrap();
+        for (name, value) in [("bob", 1), ("alice", 2), ("bob", 3)] {
+            btree(&format!("--keys string put {} {} {}", file, name, value)).unwrap();
+        }
+        assert_eq!(btree(&format!("--keys string get {} bob", file)).unwrap(), "1\n3\n");
+        btree(&format!("--keys string del {} bob 1", file)).unwrap();
+        assert_eq!(btree(&format!("--keys string scan {}", file)).unwrap(), "\"alice\"\t2\n\"bob\"\t3\n");
+
+        // the key type is taken from the header, a different one can't be forced
+        assert!(btree(&format!("--keys u32 header {}", file)).unwrap_err().contains("keys of variable length, not of 4 bytes"));
+        assert_eq!(btree(&format!("scan {}", file)).unwrap(), "\"alice\"\t2\n\"bob\"\t3\n");
+        let header = btree(&format!("header {}", file)).unwrap();
+        assert!(header.contains("format_version: 5\n"));
+        assert!(header.contains("key_type: 0x00000008\n"));
+        assert!(header.contains("key_width: variable\n"));
+        assert!(header.contains("max_degree: 8\n"));
+        assert!(header.contains("number_of_pages: 1\n"));
+        assert!(header.contains("root: 0\n"));
+        assert!(header.contains("index_mode: NonUnique\n"));
+        assert!(header.contains("pager_mode: Wal\n"));
+
+        let page = btree(&format!("dump-page {} 0", file)).unwrap();
+        assert!(page.contains("kind: leaf\n"));
+        assert!(page.contains("cells: 2\n"));
+        assert!(page.contains("  1: 626f62000100000003 (\"bob\") -> value 3\n"), "{}", page);
+        assert!(btree(&format!("dump-page {} 1", file)).is_err());
+
+        assert!(btree(&format!("check {}", file)).unwrap().starts_with("ok: 2 entries in 1 pages"));
+        let stats = btree(&format!("stats {}", file)).unwrap();
+        assert!(stats.contains("entries: 2\n"));
+        assert!(stats.contains("depth: 1\n"));
+    }
+
+    #[test]
+    fn check_fails_on_leaked_pages() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("store");
+        btree(&format!("create {} --degree 4", file.display())).unwrap();
+        for key in 0..50 {
+            btree(&format!("put {} {} {}", file.display(), key, key)).unwrap();
+        }
+        for key in 0..40 {
+            btree(&format!("del {} {}", file.display(), key)).unwrap();
+        }
+        assert!(btree(&format!("check {}", file.display())).is_ok());
+
+        // forget the free list (see the file design in btree_store.rs)
+        let mut bytes = fs::read(&file).unwrap();
+        bytes[24..28].copy_from_slice(&u32::MAX.to_be_bytes());
+        let checksum = crc32fast::hash(&bytes[..50]);
+        bytes[50..54].copy_from_slice(&checksum.to_be_bytes());
+        fs::write(&file, &bytes).unwrap();
+        assert!(btree(&format!("check {}", file.display())).is_err());
+    }
+
+    #[test]
+    fn inspecting_commands_do_not_write() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("store");
+        let wal = Wal::path(&file);
+        btree(&format!("create {} --degree 4", file.display())).unwrap();
+        btree(&format!("put {} 1 10", file.display())).unwrap();
+
+        // the last put is only in the WAL, as if the process had crashed
+        let checkpointed = fs::read(&file).unwrap();
+        let mut store = BTreeStore::<u32>::open_existing(&file).unwrap();
+        store.insert(2, 20).unwrap();
+        let records = fs::read(&wal).unwrap();
+        drop(store);
+        fs::write(&file, &checkpointed).unwrap();
+        fs::write(&wal, &records).unwrap();
+
+        assert_eq!(btree(&format!("scan {}", file.display())).unwrap(), "1\t10\n2\t20\n");
+        assert_eq!(btree(&format!("get {} 2", file.display())).unwrap(), "20\n");
+        for command in ["header", "check", "stats", "dump-page"] {
+            btree(&format!("{} {} 0", command, file.display())).unwrap();
+        }
+        assert_eq!(fs::read(&file).unwrap(), checkpointed);
+        assert_eq!(fs::read(&wal).unwrap(), records);
+
+        // put recovers the store
+        btree(&format!("put {} 3 30", file.display())).unwrap();
+        assert!(!wal.exists());
+        assert_eq!(btree(&format!("scan {}", file.display())).unwrap(), "1\t10\n2\t20\n3\t30\n");
+    }
+
+    #[test]
+    fn open_only_existing_stores() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("store");
+        assert!(btree(&format!("header {}", file.display())).is_err());
+        fs::write(&file, b"").unwrap();
+        assert!(btree(&format!("header {}", file.display())).is_err());
+        assert!(btree(&format!("--keys float get {} 1", file.display())).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::{mem, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+
+use crate::simple_bplustree::DuplicateKeyError;
+
+// Every node is protected by its own latch, the latches are always taken from the root downwards.
+type Latch<K, V> = Arc<RwLock<Node<K, V>>>;
+
+struct Node<K, V> {
+    keys: Vec<K>,
+    values: Vec<V>, // only in leaves
+    children: Vec<Latch<K, V>>, // only in internal nodes
+}
+
+impl<K: Ord + Clone, V> Node<K, V> {
+    fn new() -> Self {
+        Node { keys: Vec::new(), values: Vec::new(), children: Vec::new() }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    fn is_full(&self, max_degree: usize) -> bool {
+        self.keys.len() >= max_degree - 1
+    }
+
+    // Index of the child, which contains the key. Equal keys are right of the separator.
+    fn child_index(&self, key: &K) -> usize {
+        self.keys.partition_point(|k| k <= key)
+    }
+
+    // The left half stays in this node. Returns the key for the parent and the right half.
+    fn split(&mut self) -> (K, Node<K, V>) {
+        let middle = self.keys.len() / 2;
+        let mut right_keys = self.keys.split_off(middle);
+
+        if self.is_leaf() {
+            let right_values = self.values.split_off(middle);
+            // the key stays in the right leaf and is copied into the parent
+            let separator = right_keys[0].clone();
+            (separator, Node { keys: right_keys, values: right_values, children: Vec::new() })
+        } else {
+            let right_children = self.children.split_off(middle + 1);
+            // the key moves up into the parent
+            let separator = right_keys.remove(0);
+            (separator, Node { keys: right_keys, values: Vec::new(), children: right_children })
+        }
+    }
+
+    // Returns the previous value of an existing key. If overwrite is not set, the key and value are handed back instead.
+    fn insert_key_value(&mut self, key: K, value: V, overwrite: bool) -> Result<Option<V>, (K, V)> {
+        match self.keys.binary_search(&key) {
+            Ok(i) if overwrite => Ok(Some(mem::replace(&mut self.values[i], value))),
+            Ok(_) => Err((key, value)),
+            Err(i) => {
+                self.keys.insert(i, key);
+                self.values.insert(i, value);
+                Ok(None)
+            },
+        }
+    }
+}
+
+// Lock coupling (crabbing): the latch of the child is taken, before the latch of the parent is released.
+// This way a thread never sees a node, which is modified by another thread.
+fn find_in<K: Ord + Clone, V: Clone>(node: RwLockReadGuard<'_, Node<K, V>>, key: &K) -> Option<V> {
+    if node.is_leaf() {
+        return node.keys.binary_search(key).ok().map(|i| node.values[i].clone());
+    }
+
+    let child = Arc::clone(&node.children[node.child_index(key)]);
+    let child_node = child.read().unwrap();
+    drop(node);
+    find_in(child_node, key)
+}
+
+// A full child is split, while the parent is still latched exclusively. Afterwards the child has room for a promoted key,
+// so the parent is never needed again and is released, before the insert goes down (at most two latches are held).
+fn insert_in<K: Ord + Clone, V>(mut node: RwLockWriteGuard<'_, Node<K, V>>, key: K, value: V, overwrite: bool, max_degree: usize) -> Result<Option<V>, (K, V)> {
+    if node.is_leaf() {
+        return node.insert_key_value(key, value, overwrite);
+    }
+
+    let mut index = node.child_index(&key);
+    if node.children[index].read().unwrap().is_full(max_degree) {
+        let (separator, right) = node.children[index].write().unwrap().split();
+        let insert_right = key >= separator;
+        node.keys.insert(index, separator);
+        node.children.insert(index + 1, Arc::new(RwLock::new(right)));
+        if insert_right {
+            index += 1;
+        }
+    }
+
+    let child = Arc::clone(&node.children[index]);
+    let child_node = child.write().unwrap();
+    drop(node);
+    insert_in(child_node, key, value, overwrite, max_degree)
+}
+
+fn collect_entries<K: Clone, V: Clone>(node: &Node<K, V>, out: &mut Vec<(K, V)>) {
+    if node.children.is_empty() {
+        out.extend(node.keys.iter().cloned().zip(node.values.iter().cloned()));
+    } else {
+        for child in &node.children {
+            collect_entries(&child.read().unwrap(), out);
+        }
+    }
+}
+
+// B+ tree for several threads, which insert and look up keys at the same time.
+// Lookups latch shared, inserts latch exclusive, but only the node they are in and its parent.
+// Threads working in different subtrees do not block each other.
+// Deleting is not supported: a refill has to latch the siblings of a node, which breaks the top-down order of the latches.
+pub struct ConcurrentBTree<K, V> {
+    root: RwLock<Latch<K, V>>, // latched, while the root is replaced by a split
+    max_degree: usize,
+}
+
+impl<K: Ord + Clone, V: Clone> ConcurrentBTree<K, V> {
+    pub fn new(max_degree: usize) -> Self {
+        ConcurrentBTree {
+            root: RwLock::new(Arc::new(RwLock::new(Node::new()))),
+            max_degree,
+        }
+    }
+
+    pub fn find(&self, key: &K) -> Option<V> {
+        let root_pointer = self.root.read().unwrap();
+        let root = Arc::clone(&root_pointer);
+        let root_node = root.read().unwrap();
+        drop(root_pointer);
+        find_in(root_node, key)
+    }
+
+    // Inserts or overwrites the value of the key. Returns the previous value.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        match self.insert_with(key, value, true) {
+            Ok(previous) => previous,
+            Err(_) => unreachable!("An existing key is overwritten"),
+        }
+    }
+
+    // Keeps the value of an existing key. Returns true, if the key has been inserted.
+    pub fn insert_if_absent(&self, key: K, value: V) -> bool {
+        self.insert_with(key, value, false).is_ok()
+    }
+
+    // Fails, if the key already exists. The error hands the key and the value back.
+    pub fn try_insert(&self, key: K, value: V) -> Result<(), DuplicateKeyError<K, V>> {
+        self.insert_with(key, value, false)
+            .map(|_| ())
+            .map_err(|(key, value)| DuplicateKeyError { key, value })
+    }
+
+    fn insert_with(&self, key: K, value: V, overwrite: bool) -> Result<Option<V>, (K, V)> {
+        loop {
+            let root_pointer = self.root.read().unwrap();
+            let root = Arc::clone(&root_pointer);
+            let root_node = root.write().unwrap();
+            if !root_node.is_full(self.max_degree) {
+                drop(root_pointer);
+                return insert_in(root_node, key, value, overwrite, self.max_degree);
+            }
+
+            drop(root_node);
+            drop(root_pointer);
+            self.split_root();
+        }
+    }
+
+    // The old root becomes the left child of a new root
+    fn split_root(&self) {
+        let mut root_pointer = self.root.write().unwrap();
+        let old_root = Arc::clone(&root_pointer);
+        let mut old_root_node = old_root.write().unwrap();
+        // another thread may have split the root in the meantime
+        if !old_root_node.is_full(self.max_degree) {
+            return;
+        }
+
+        let (separator, right) = old_root_node.split();
+        drop(old_root_node);
+        *root_pointer = Arc::new(RwLock::new(Node {
+            keys: vec![separator],
+            values: Vec::new(),
+            children: vec![old_root, Arc::new(RwLock::new(right))],
+        }));
+    }
+
+    // All entries in key order. Concurrent inserts in parts of the tree, which have already been visited, are not included.
+    pub fn entries(&self) -> Vec<(K, V)> {
+        let root = Arc::clone(&self.root.read().unwrap());
+        let mut entries = Vec::new();
+        collect_entries(&root.read().unwrap(), &mut entries);
+        entries
+    }
+
+    #[cfg(test)]
+    fn validate(&self) {
+        fn validate_node<K: Ord + Clone, V>(node: &Node<K, V>, min_key: Option<&K>, max_key: Option<&K>, max_degree: usize) -> usize {
+            assert!(node.keys.len() < max_degree, "Node is overfull");
+            assert!(node.keys.windows(2).all(|pair| pair[0] < pair[1]), "Keys must be sorted");
+            assert!(min_key.is_none_or(|min_key| node.keys.iter().all(|k| k >= min_key)), "Key is less than the separator");
+            assert!(max_key.is_none_or(|max_key| node.keys.iter().all(|k| k < max_key)), "Key is not less than the separator");
+            if node.is_leaf() {
+                assert_eq!(node.keys.len(), node.values.len(), "Every key must have a value in a leaf");
+                return 1;
+            }
+
+            assert_eq!(node.children.len(), node.keys.len() + 1, "Internal node must have one more children than keys");
+            let depths = node.children.iter().enumerate().map(|(i, child)| {
+                let child_min = if i == 0 { min_key } else { Some(&node.keys[i - 1]) };
+                let child_max = node.keys.get(i).or(max_key);
+                validate_node(&child.read().unwrap(), child_min, child_max, max_degree)
+            }).collect::<Vec<_>>();
+            assert!(depths.windows(2).all(|pair| pair[0] == pair[1]), "All leaves must be at the same depth");
+            depths[0] + 1
+        }
+
+        let root = Arc::clone(&self.root.read().unwrap());
+        validate_node(&root.read().unwrap(), None, None, self.max_degree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::{Arc, Barrier}, thread};
+
+    use crate::simple_bplustree::DuplicateKeyError;
+
+    use super::ConcurrentBTree;
+
+    #[test]
+    fn behaves_like_a_btreemap() {
+        let btree = ConcurrentBTree::new(4);
+        let mut expected = BTreeMap::new();
+        for i in 0..2000u32 {
+            let key = (i * 7919) % 500;
+            assert_eq!(btree.insert(key, i), expected.insert(key, i));
+        }
+        btree.validate();
+
+        for key in 0..600 {
+            assert_eq!(btree.find(&key), expected.get(&key).copied());
+        }
+        assert_eq!(btree.entries(), expected.into_iter().collect::<Vec<_>>());
+
+        assert!(!btree.insert_if_absent(3, 0));
+        assert!(btree.insert_if_absent(1000, 0));
+        assert_eq!(btree.try_insert(1000, 1), Err(DuplicateKeyError { key: 1000, value: 1 }));
+        assert_eq!(btree.find(&1000), Some(0));
+    }
+
+    #[test]
+    fn parallel_inserts_and_finds() {
+        const THREADS: u32 = 8;
+        const KEYS_PER_THREAD: u32 = 2000;
+
+        let btree = Arc::new(ConcurrentBTree::new(5));
+        let barrier = Arc::new(Barrier::new(THREADS as usize * 2));
+
+        let mut handles = Vec::new();
+        for thread in 0..THREADS {
+            // writers insert interleaved keys, so that they work on the same leaves
+            let writer_btree = Arc::clone(&btree);
+            let writer_barrier = Arc::clone(&barrier);
+            handles.push(thread::spawn(move || {
+                writer_barrier.wait();
+                for i in 0..KEYS_PER_THREAD {
+                    let key = i * THREADS + thread;
+                    assert_eq!(writer_btree.insert(key, key * 2), None);
+                    assert_eq!(writer_btree.find(&key), Some(key * 2));
+                }
+            }));
+
+            // readers see a key either not yet or with its value
+            let reader_btree = Arc::clone(&btree);
+            let reader_barrier = Arc::clone(&barrier);
+            handles.push(thread::spawn(move || {
+                reader_barrier.wait();
+                for i in 0..KEYS_PER_THREAD {
+                    let key = (i * 31 + thread) % (KEYS_PER_THREAD * THREADS);
+                    if let Some(value) = reader_btree.find(&key) {
+                        assert_eq!(value, key * 2);
+                    }
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        btree.validate();
+        let entries = btree.entries();
+        assert_eq!(entries.len(), (KEYS_PER_THREAD * THREADS) as usize);
+        assert!(entries.iter().enumerate().all(|(i, (key, value))| *key == i as u32 && *value == key * 2));
+    }
+
+    #[test]
+    fn parallel_upserts_of_the_same_keys() {
+        let btree = Arc::new(ConcurrentBTree::new(4));
+        let handles = (0..4u32).map(|thread| {
+            let btree = Arc::clone(&btree);
+            thread::spawn(move || {
+                for key in 0..1000u32 {
+                    btree.insert(key, thread);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        btree.validate();
+        let entries = btree.entries();
+        assert_eq!(entries.iter().map(|(key, _)| *key).collect::<Vec<_>>(), (0..1000).collect::<Vec<_>>());
+        assert!(entries.iter().all(|(_, value)| *value < 4));
+    }
+}
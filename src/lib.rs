@@ -1,2 +1,3 @@
+pub mod concurrent_bplustree;
 pub mod page_based_bplustree;
 pub mod simple_bplustree;
@@ -1,16 +1,36 @@
-use std::{cell::RefCell, fs::{self, File, OpenOptions}, io::{Read, Seek, Write}, path::Path, rc::Rc, u32};
+use std::{borrow, collections::{BTreeMap, VecDeque}, fs::{self, File, OpenOptions}, io::{self, Read, Seek, Write}, marker::PhantomData, ops::{Bound, ControlFlow, RangeBounds}, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard, RwLock, atomic::{AtomicBool, Ordering}}};
 
+use derive_getters::Getters;
 use thiserror::Error;
 
-use crate::page_based_bplustree::{get_u32_be_bytes_from_option, node::NodePage, read_u32_with_null};
+#[cfg(feature = "mmap")]
+use crate::page_based_bplustree::mmap::MappedFile;
+use crate::page_based_bplustree::{buffer_pool::{BufferPool, BufferPoolStats}, check::{CheckReport, check_store}, get_u32_be_bytes_from_option, key::{self, StoreKey}, node::NodePage, read_u32_with_null, shadow::{self, PageTable, PinnedPageTable}, snapshot::Snapshot, wal::{Wal, WalError, WalRecord, write_at}};
 
 // File design:
 
-// Metadata header => 14 Bytes
+// Metadata header => 54 Bytes
+// 4 bytes: magic bytes "BPTS"
+// 2 bytes: format version
+// 2 bytes: size of the metadata header
+// 2 bytes: size of the page header
+// 4 bytes: page_size (derived from max_degree at creation, see page_size, and read from here afterwards)
+// 2 bytes: key width (size of every encoded key, 0 for keys of variable length)
+// 2 bytes: value width
 // 2 bytes: max_degree
-// 4 bytes: number_of_pages (max: u32:MAX - 1)
+// 4 bytes: number_of_pages (max: u32:MAX - 1, the file offsets of the pages are 64 bit)
 // 4 bytes: first_deleted_page (u32::MAX for INVALID / NULL)
+pub(crate) const POS_HEADER_FIRST_DELETED_PAGE: usize = 24;
 // 4 bytes: root (u32::MAX for INVALID / NULL)
+pub(crate) const POS_HEADER_ROOT: usize = 28;
+// 1 byte: index mode (0x00: unique, 0x01: non-unique, the value is appended to every key)
+// 1 byte: pager mode (0x00: pages are written in place and journaled in the WAL, 0x01: shadow paging, see shadow.rs)
+// 8 bytes: sequence number of the last commit (shadow paging only)
+// 4 bytes: root slot of the page table (shadow paging only, u32::MAX for INVALID / NULL)
+// 4 bytes: key type (StoreKey::TYPE_ID)
+// 4 bytes: CRC32 of all previous bytes of the header
+// A file is only opened, if the magic bytes, the format version, the header sizes and the widths match this implementation
+// and the key type matches the key type of the BTreeStore.
 // -----------------------------------
 // Page
 // Meta-Section:
@@ -20,50 +40,250 @@ const POS_PAGE_ID: usize = 0;
 const POS_DELETED: usize = 4;
 // 4 bytes: next_deleted_page (number of next deleted page, u32::MAX for INVALID / NULL)
 const POS_NEXT_DELETED_PAGE: usize = 5;
-// Node-Section:
-// 9 x 4 bytes: keys
-// 10 x 4 bytes: pageIds
-// 9 x 4 bytes: values
+// 4 bytes: next_leaf (page_id of the right sibling leaf, u32::MAX for INVALID / NULL)
+pub(crate) const POS_NEXT_LEAF: usize = 9;
+// 2 bytes: number of cells (keys) in the page
+const POS_CELL_COUNT: usize = 13;
+// 2 bytes: start of the cell content area (cells grow from the end of the page towards the slots)
+const POS_CELL_CONTENT: usize = 15;
+// 4 bytes: first (leftmost) child of an internal node (u32::MAX for leaves)
+pub(crate) const POS_FIRST_CHILD: usize = 17;
+// 4 bytes: CRC32 of the page without these 4 bytes, verified whenever the page is read from the file
+pub(crate) const POS_CHECKSUM: usize = 21;
+// Node-Section (slotted page):
+// 2 bytes per cell: slot with the offset of the cell, slots are sorted by key
+// ...free space...
+// cells:
+//   2 bytes: key length
+//   n bytes: key (encoded, see key.rs)
+//   4 bytes: value (leaf) or the child right of the key (internal node)
+// The number of cells is stored in the header, so keys and values can use every u32, only page ids use u32::MAX as NULL.
+
+pub(crate) const PAGE_HEADER_SIZE: usize = 25;
+pub(crate) const META_DATA_HEADER_SIZE: usize = 54;
+pub(crate) const CHECKSUM_SIZE: usize = 4;
+
+const MAGIC: &[u8; 4] = b"BPTS";
+// must be increased with every incompatible change of the file design
+pub const FORMAT_VERSION: u16 = 5;
+
+const SLOT_SIZE: usize = 2;
+const KEY_LENGTH_SIZE: usize = 2;
+const POINTER_SIZE: usize = 4;
+
+// Bytes needed to store a key with its slot and its value / child
+pub fn cell_size(key: &[u8]) -> usize {
+    SLOT_SIZE + KEY_LENGTH_SIZE + key.len() + POINTER_SIZE
+}
 
-// just for playing around, should be encoded in meta data header.
-const PAGE_HEADER_SIZE: usize = 9;
-const META_DATA_HEADER_SIZE: usize = 14;
+// The page is sized by max_degree cells of keys of key_size bytes, the actual number of keys depends on their length
+pub(crate) fn page_size(max_degree: u16, key_size: usize) -> usize {
+    PAGE_HEADER_SIZE + max_degree as usize * (cell_size(&[]) + key_size)
+}
 
-fn key_offset() -> usize {
-    PAGE_HEADER_SIZE
+fn read_u16(data: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes(data[offset..offset + 2].try_into().unwrap()) as usize
 }
 
-fn key_array_size(max_degree: u16) -> usize {
-    ((max_degree - 1) * 4) as usize
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
 }
 
-fn children_offset(max_degree: u16) -> usize {
-    key_offset() + key_array_size(max_degree)
+fn meta_data_to_bytes(store_meta_data: &StoreMetaData) -> Vec<u8> {
+    let mut metadata_bytes = [0u8; META_DATA_HEADER_SIZE];
+    metadata_bytes[0..4].copy_from_slice(MAGIC);
+    metadata_bytes[4..6].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    metadata_bytes[6..8].copy_from_slice(&(META_DATA_HEADER_SIZE as u16).to_be_bytes());
+    metadata_bytes[8..10].copy_from_slice(&(PAGE_HEADER_SIZE as u16).to_be_bytes());
+    metadata_bytes[10..14].copy_from_slice(&store_meta_data.page_size.to_be_bytes());
+    metadata_bytes[14..16].copy_from_slice(&store_meta_data.key_width.to_be_bytes());
+    metadata_bytes[16..18].copy_from_slice(&(POINTER_SIZE as u16).to_be_bytes());
+    metadata_bytes[18..20].copy_from_slice(&store_meta_data.max_degree.to_be_bytes());
+    metadata_bytes[20..24].copy_from_slice(&store_meta_data.number_of_pages.to_be_bytes());
+    metadata_bytes[POS_HEADER_FIRST_DELETED_PAGE..POS_HEADER_FIRST_DELETED_PAGE + 4].copy_from_slice(&get_u32_be_bytes_from_option(&store_meta_data.first_deleted_page));
+    metadata_bytes[POS_HEADER_ROOT..POS_HEADER_ROOT + 4].copy_from_slice(&get_u32_be_bytes_from_option(&store_meta_data.root));
+    metadata_bytes[32] = match store_meta_data.index_mode {
+        IndexMode::Unique => 0,
+        IndexMode::NonUnique => 1,
+    };
+    metadata_bytes[33] = match store_meta_data.pager_mode {
+        PagerMode::Wal => 0,
+        PagerMode::Shadow => 1,
+    };
+    metadata_bytes[34..42].copy_from_slice(&store_meta_data.sequence.to_be_bytes());
+    metadata_bytes[42..46].copy_from_slice(&get_u32_be_bytes_from_option(&store_meta_data.page_table));
+    metadata_bytes[46..50].copy_from_slice(&store_meta_data.key_type.to_be_bytes());
+    let checksum = crc32fast::hash(&metadata_bytes[..META_DATA_HEADER_SIZE - CHECKSUM_SIZE]);
+    metadata_bytes[META_DATA_HEADER_SIZE - CHECKSUM_SIZE..].copy_from_slice(&checksum.to_be_bytes());
+    metadata_bytes.to_vec()
 }
 
-fn children_array_size(max_degree: u16) -> usize {
-    max_degree as usize * 4
+// CRC32 of the page without its checksum
+pub(crate) fn page_checksum(page: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&page[..POS_CHECKSUM]);
+    hasher.update(&page[POS_CHECKSUM + CHECKSUM_SIZE..]);
+    hasher.finalize()
 }
 
-fn values_offset(max_degree: u16) -> usize {
-    children_offset(max_degree) + children_array_size(max_degree)
+// Reads the metadata header, fails if the file has not been written by this implementation
+fn meta_data_from_bytes(metadata_bytes: &[u8]) -> Result<StoreMetaData, BTreeStoreError> {
+    if metadata_bytes.len() < MAGIC.len() || &metadata_bytes[0..4] != MAGIC {
+        return Err(BTreeStoreError::Incompatible { msg: "File is not a BTreeStore (magic bytes are missing)".to_owned() });
+    }
+    if metadata_bytes.len() < 6 || read_u16(metadata_bytes, 4) != FORMAT_VERSION as usize {
+        let version = metadata_bytes.get(4..6).map_or("unknown".to_owned(), |_| read_u16(metadata_bytes, 4).to_string());
+        return Err(BTreeStoreError::Incompatible { msg: format!("BTreeStore has the format version {}, only version {} is supported", version, FORMAT_VERSION) });
+    }
+    if metadata_bytes.len() < META_DATA_HEADER_SIZE {
+        return Err(BTreeStoreError::Incompatible { msg: "Metadata header of the BTreeStore is truncated".to_owned() });
+    }
+
+    let header_sizes = (read_u16(metadata_bytes, 6), read_u16(metadata_bytes, 8));
+    if header_sizes != (META_DATA_HEADER_SIZE, PAGE_HEADER_SIZE) {
+        return Err(BTreeStoreError::Incompatible { msg: format!(
+            "BTreeStore has a metadata header of {} bytes and page headers of {} bytes, expected {} and {} bytes",
+            header_sizes.0, header_sizes.1, META_DATA_HEADER_SIZE, PAGE_HEADER_SIZE
+        ) });
+    }
+    // the checksum is at the end of the header, so it can only be verified, if the header has the expected size
+    let checksum = read_u32(metadata_bytes, META_DATA_HEADER_SIZE - CHECKSUM_SIZE);
+    if crc32fast::hash(&metadata_bytes[..META_DATA_HEADER_SIZE - CHECKSUM_SIZE]) != checksum {
+        return Err(CorruptionError::HeaderChecksum.into());
+    }
+    let value_width = read_u16(metadata_bytes, 16);
+    if value_width != POINTER_SIZE {
+        return Err(BTreeStoreError::Incompatible { msg: format!("BTreeStore has values of {} bytes, expected {} bytes", value_width, POINTER_SIZE) });
+    }
+    let page_size = read_u32(metadata_bytes, 10);
+    if (page_size as usize) < PAGE_HEADER_SIZE || page_size > u16::MAX as u32 {
+        return Err(BTreeStoreError::Incompatible { msg: format!("BTreeStore has an invalid page size of {} bytes", page_size) });
+    }
+
+    let index_mode = match metadata_bytes[32] {
+        0 => IndexMode::Unique,
+        1 => IndexMode::NonUnique,
+        mode => return Err(BTreeStoreError::Incompatible { msg: format!("Unknown index mode {}", mode) }),
+    };
+    let pager_mode = match metadata_bytes[33] {
+        0 => PagerMode::Wal,
+        1 => PagerMode::Shadow,
+        mode => return Err(BTreeStoreError::Incompatible { msg: format!("Unknown pager mode {}", mode) }),
+    };
+
+    Ok(StoreMetaData {
+        page_size,
+        key_width: read_u16(metadata_bytes, 14) as u16,
+        max_degree: read_u16(metadata_bytes, 18) as u16,
+        number_of_pages: read_u32(metadata_bytes, 20),
+        first_deleted_page: read_u32_with_null(read_u32(metadata_bytes, POS_HEADER_FIRST_DELETED_PAGE)),
+        root: read_u32_with_null(read_u32(metadata_bytes, POS_HEADER_ROOT)),
+        index_mode,
+        pager_mode,
+        sequence: u64::from_be_bytes(metadata_bytes[34..42].try_into().unwrap()),
+        page_table: read_u32_with_null(read_u32(metadata_bytes, 42)),
+        key_type: read_u32(metadata_bytes, 46),
+        changed: false,
+    })
 }
 
-fn meta_data_to_bytes(store_meta_data: &StoreMetaData) -> Vec<u8> {
-    let mut metadata_bytes = [0u8; META_DATA_HEADER_SIZE];
-    metadata_bytes[0..2].copy_from_slice(&store_meta_data.max_degree.to_be_bytes());
-    metadata_bytes[2..6].copy_from_slice(&store_meta_data.number_of_pages.to_be_bytes());
-    metadata_bytes[6..10].copy_from_slice(&get_u32_be_bytes_from_option(&store_meta_data.first_deleted_page));
-    metadata_bytes[10..14].copy_from_slice(&get_u32_be_bytes_from_option(&store_meta_data.root));
-    metadata_bytes.to_vec()
+// Reads the header at the start of the file
+fn read_header(file: &mut File, file_path: &Path) -> Result<StoreMetaData, BTreeStoreError> {
+    // with the second copy of the header of a shadow paged store
+    let mut metadata_bytes = Vec::with_capacity(2 * META_DATA_HEADER_SIZE);
+    Read::by_ref(file).take(2 * META_DATA_HEADER_SIZE as u64).read_to_end(&mut metadata_bytes)
+        .map_err(|source| BTreeStoreError::Io { context: "Cannot read meta data from file".to_owned(), source })?;
+    select_meta_data(&metadata_bytes).map_err(|err| match err {
+        BTreeStoreError::Incompatible { msg } => BTreeStoreError::Incompatible { msg: format!("Cannot open {}: {}", file_path.display(), msg) },
+        err => err,
+    })
+}
+
+// A shadow paged store has a second copy of the header at the start of the first page (see shadow.rs).
+// The valid copy with the greater sequence number is the current one, the other one may have been torn by a crash.
+fn select_meta_data(bytes: &[u8]) -> Result<StoreMetaData, BTreeStoreError> {
+    let first = meta_data_from_bytes(&bytes[..bytes.len().min(META_DATA_HEADER_SIZE)]);
+    let second = bytes.get(META_DATA_HEADER_SIZE..2 * META_DATA_HEADER_SIZE)
+        .and_then(|bytes| meta_data_from_bytes(bytes).ok())
+        .filter(|meta_data| meta_data.pager_mode == PagerMode::Shadow);
+
+    match (first, second) {
+        (Ok(first), Some(second)) if first.pager_mode == PagerMode::Shadow && second.sequence > first.sequence => Ok(second),
+        (Ok(first), _) => Ok(first),
+        (Err(BTreeStoreError::Corrupt(CorruptionError::HeaderChecksum)), Some(second)) => Ok(second),
+        (Err(err), _) => Err(err),
+    }
+}
+
+// Key width of the header: the size of every encoded key, 0 for keys of variable length
+fn key_width<K: StoreKey>() -> u16 {
+    K::WIDTH.map_or(0, |width| width as u16)
+}
+
+// Size of the keys, which the pages of a new store are sized for: fixed width keys by their width, keys of variable length like u32 keys.
+// A non-unique index appends the value to the keys.
+fn sized_key_size<K: StoreKey>(index_mode: IndexMode) -> usize {
+    let key_size = K::WIDTH.unwrap_or(size_of::<u32>());
+    match index_mode {
+        IndexMode::Unique => key_size,
+        IndexMode::NonUnique => key_size + VALUE_SUFFIX_SIZE,
+    }
+}
+
+fn describe_key_width(key_width: u16) -> String {
+    match key_width {
+        0 => "of variable length".to_owned(),
+        width => format!("of {} bytes", width),
+    }
+}
+
+// Size of the value, which is appended to the keys in a non-unique index
+const VALUE_SUFFIX_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexMode {
+    Unique,
+    // A key can have several values. Every (key, value) pair is stored as its own entry,
+    // the value is appended to the encoded key, so that the values of a key are ordered.
+    NonUnique,
+}
+
+// When the writes of the store are synced to the disk. Crashes of the process are survived in every mode,
+// the mode decides, what survives a crash of the operating system or a power loss.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SyncMode {
+    // Every commit is synced to the WAL, written into the store file and synced again. Slowest, the WAL stays empty.
+    Always,
+    // Every commit is synced to the WAL, the store file is synced on checkpoint
+    #[default]
+    OnCommit,
+    // Nothing is synced, except by sync(). The latest commits can be lost and the store can become inconsistent.
+    Never,
+}
+
+// How the store survives a crash in the middle of a commit. The mode is recorded at creation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PagerMode {
+    // Pages are overwritten in place, every commit is written to the WAL before
+    Wal,
+    // Changed pages are written to free pages of the file, a commit becomes visible by switching the header (see shadow.rs)
+    Shadow,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Getters)]
 pub struct StoreMetaData {
+    page_size: u32,
+    key_width: u16,
     max_degree: u16,
     number_of_pages: u32, // in total: with deleted pages
     first_deleted_page: Option<u32>,
     root: Option<u32>,
+    index_mode: IndexMode,
+    pager_mode: PagerMode,
+    sequence: u64, // number of commits of a shadow paged store, 0 otherwise
+    page_table: Option<u32>, // root slot of the page table of a shadow paged store
+    key_type: u32, // StoreKey::TYPE_ID
+    #[getter(skip)]
     changed: bool, // will not be serialized, is only a flag, if NodePager has changed the meta data
 }
 
@@ -83,115 +303,307 @@ impl StoreMetaData {
         self.changed = true;
     }
 
+    // Reads the header of a store without opening it, e.g. to find the type of its keys
+    pub fn read(file_path: &Path) -> Result<Self, BTreeStoreError> {
+        let mut file = File::open(file_path)
+            .map_err(|source| BTreeStoreError::Io { context: format!("Cannot open {}", file_path.display()), source })?;
+        read_header(&mut file, file_path)
+    }
+
     
 }
 
-impl From<(Vec<u8>, u16)> for NodePage {
-    fn from(value_degree_tupel: (Vec<u8>, u16)) -> Self {
-        let value = value_degree_tupel.0;
-        let max_degree = value_degree_tupel.1;
-
-        let page_id = u32::from_be_bytes(value[POS_PAGE_ID..POS_PAGE_ID + 4].try_into().unwrap());
+// Borrowed view of a page image, which has the page size of the store. Its checksum has been verified, but a page with
+// a valid checksum can still be broken by a bug, so every offset is checked against the page.
+// Lookups and scans read the keys and pointers in place, the page can be a page of the cache or of the memory mapped store file.
+struct PageView<'a> {
+    data: &'a [u8],
+    page_id: u32,
+    cell_count: usize,
+}
 
-        if page_id == u32::MAX {
-            panic!("Read a page with INVALID id.");
+impl<'a> PageView<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, CorruptionError> {
+        let page_id = read_u32(data, POS_PAGE_ID);
+        if page_id == u32::MAX || data.len() < PAGE_HEADER_SIZE {
+            return Err(CorruptionError::MalformedPage { page_id });
+        }
+        let cell_count = read_u16(data, POS_CELL_COUNT);
+        if PAGE_HEADER_SIZE + cell_count * SLOT_SIZE > data.len() {
+            return Err(CorruptionError::MalformedPage { page_id });
         }
 
-        let deleted = match value[POS_DELETED] {
-            0 => false,
-            _ => true,
-        };
+        Ok(PageView { data, page_id, cell_count })
+    }
 
-        let next_deleted_page = read_u32_with_null(
-            u32::from_be_bytes(value[POS_NEXT_DELETED_PAGE..POS_NEXT_DELETED_PAGE + 4].try_into().unwrap())
-        );
+    fn malformed(&self) -> CorruptionError {
+        CorruptionError::MalformedPage { page_id: self.page_id }
+    }
 
-        let mut keys = Vec::new();
-        let key_offset = key_offset();
-        for k in 0..(max_degree - 1) {
-            let next_offset = key_offset + (k as usize * 4);
-            let next_key = read_u32_with_null(u32::from_be_bytes(value[next_offset..(next_offset + 4)].try_into().unwrap()));
-            if let Some(next_key) = next_key {
-                keys.push(next_key);
-            } else {
-                break;
-            }
+    fn first_child(&self) -> Option<u32> {
+        read_u32_with_null(read_u32(self.data, POS_FIRST_CHILD))
+    }
+
+    fn next_leaf(&self) -> Option<u32> {
+        read_u32_with_null(read_u32(self.data, POS_NEXT_LEAF))
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.first_child().is_none()
+    }
+
+    // The key and the pointer (value of a leaf, child right of the key of an internal node) of the cell in slot
+    fn cell(&self, slot: usize) -> Result<(&'a [u8], u32), CorruptionError> {
+        let cell_offset = read_u16(self.data, PAGE_HEADER_SIZE + slot * SLOT_SIZE);
+        let key_offset = cell_offset + KEY_LENGTH_SIZE;
+        if key_offset > self.data.len() {
+            return Err(self.malformed());
         }
-        
-        let mut children = Vec::new();
-        
-        let child_offset = children_offset(max_degree);
-        for c in 0..max_degree {
-            let next_offset = child_offset + (c as usize * 4);
-            let next_child = read_u32_with_null(u32::from_be_bytes(value[next_offset..(next_offset + 4)].try_into().unwrap()));
-            if let Some(next_child) = next_child {
-                children.push(next_child);
-            } else {
-                break;
-            }
+        let key_length = read_u16(self.data, cell_offset);
+        if key_offset + key_length + POINTER_SIZE > self.data.len() {
+            return Err(self.malformed());
         }
-        
-        let mut values = Vec::new();
-        
-        let value_offset = values_offset(max_degree);
-        for v in 0..(max_degree - 1) {
-            let next_offset = value_offset + (v as usize * 4);
-            let next_value = read_u32_with_null(u32::from_be_bytes(value[next_offset..(next_offset + 4)].try_into().unwrap()));
-            if let Some(next_value) = next_value {
-                values.push(next_value);
-            } else {
-                break;
+
+        Ok((&self.data[key_offset..key_offset + key_length], read_u32(self.data, key_offset + key_length)))
+    }
+
+    // Binary search on the slots, which are sorted by key: Ok with the slot of the key, Err with the slot it would be inserted at
+    fn search(&self, key: &[u8]) -> Result<Result<usize, usize>, CorruptionError> {
+        let (mut low, mut high) = (0, self.cell_count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.cell(middle)?.0.cmp(key) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Equal => return Ok(Ok(middle)),
+                std::cmp::Ordering::Greater => high = middle,
             }
         }
 
-        NodePage::new_from_store(page_id, deleted, next_deleted_page, keys, children, values, max_degree as usize)
+        Ok(Err(low))
+    }
+
+    // The child of an internal node, which contains the key (or would contain it), the leftmost child if key is None
+    fn child(&self, key: Option<&[u8]>) -> Result<u32, CorruptionError> {
+        let index = match key.map(|key| self.search(key)).transpose()? {
+            None => 0,
+            Some(Ok(slot)) => slot + 1,
+            Some(Err(slot)) => slot,
+        };
+        match index {
+            0 => self.first_child().ok_or_else(|| self.malformed()),
+            index => Ok(self.cell(index - 1)?.1),
+        }
+    }
+
+    // Parses the page into a NodePage, which owns copies of the keys
+    fn to_node_page(&self) -> Result<NodePage, CorruptionError> {
+        let mut keys = Vec::with_capacity(self.cell_count);
+        let mut pointers = Vec::with_capacity(self.cell_count);
+        for slot in 0..self.cell_count {
+            let (key, pointer) = self.cell(slot)?;
+            keys.push(key.to_vec());
+            pointers.push(pointer);
+        }
+
+        // internal nodes store the leftmost child in the header and the other children in the cells
+        let (children, values) = match self.first_child() {
+            Some(first_child) => {
+                let mut children = vec![first_child];
+                children.extend(pointers);
+                (children, Vec::new())
+            },
+            None => (Vec::new(), pointers),
+        };
+        let deleted = self.data[POS_DELETED] != 0;
+        let next_deleted_page = read_u32_with_null(read_u32(self.data, POS_NEXT_DELETED_PAGE));
+
+        Ok(NodePage::new_from_store(self.page_id, deleted, next_deleted_page, keys, children, values, self.data.len() - PAGE_HEADER_SIZE, self.next_leaf()))
+    }
+}
+
+impl TryFrom<&[u8]> for NodePage {
+    type Error = CorruptionError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        PageView::new(value)?.to_node_page()
     }
 }
 
+// Keeps the last commit intact, if the process crashes in the middle of the next one (see PagerMode)
+enum Journal {
+    Wal(Wal),
+    Shadow(Arc<Mutex<PageTable>>),
+    // a read-only view of a commit of a shadow paged store (see BTreeStore::snapshot and BTreeStore::open_read_only)
+    Snapshot(PinnedPageTable),
+    // a store with a WAL, which has been opened read-only: the pages of the committed WAL records, they replace the pages of the file
+    ReadOnly(BTreeMap<u32, Vec<u8>>),
+}
+
 pub struct NodePager {
-    file: RefCell<File>,
-    meta_data: Rc<RefCell<StoreMetaData>>
+    file: Mutex<File>,
+    meta_data: Arc<RwLock<StoreMetaData>>,
+    journal: Journal,
+    dirty_pages: Mutex<BTreeMap<u32, Vec<u8>>>, // page images of the running transaction, written to the file on commit
+    buffer_pool: Mutex<BufferPool>, // committed pages are written back to the file on eviction or checkpoint
+    sync_mode: SyncMode,
+    checkpoint_pending: AtomicBool, // a checkpoint after a commit has failed, see checkpoint_after_commit
+    #[cfg(feature = "mmap")]
+    mapped_file: Option<Mutex<MappedFile>>, // locked after the file, see BTreeStore::set_mmap
+}
+
+// Data in the store file, which has not been written by the store: bit rot, torn or foreign writes
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum CorruptionError {
+    #[error("Checksum of page {page_id} does not match its content")]
+    PageChecksum { page_id: u32 },
+    #[error("Page {page_id} contains the page id {stored_page_id}")]
+    PageIdMismatch { page_id: u32, stored_page_id: u32 },
+    #[error("Page {page_id} cannot be parsed")]
+    MalformedPage { page_id: u32 },
+    #[error("Page {page_id} is used, but has been deleted")]
+    DeletedPage { page_id: u32 },
+    #[error("Free list is broken: page {page_id} is not deleted")]
+    BrokenFreeList { page_id: u32 },
+    #[error("Key {key:?} in page {page_id} cannot be decoded")]
+    UndecodableKey { page_id: u32, key: Vec<u8> },
+    #[error("Checksum of the metadata header does not match its content")]
+    HeaderChecksum,
+    #[error("Page table of the shadow paged store is broken")]
+    BrokenPageTable,
+}
+
+impl CorruptionError {
+    // The corrupt page, None for the metadata header and the page table
+    pub fn page_id(&self) -> Option<u32> {
+        match self {
+            CorruptionError::PageChecksum { page_id }
+                | CorruptionError::PageIdMismatch { page_id, .. }
+                | CorruptionError::MalformedPage { page_id }
+                | CorruptionError::DeletedPage { page_id }
+                | CorruptionError::BrokenFreeList { page_id }
+                | CorruptionError::UndecodableKey { page_id, .. } => Some(*page_id),
+            CorruptionError::HeaderChecksum | CorruptionError::BrokenPageTable => None,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
-#[error("NodePager error: {msg}")]
-pub struct NodePagerError {
-    msg: String
+pub enum NodePagerError {
+    #[error("{context}: {source}")]
+    Io { context: String, #[source] source: io::Error },
+    #[error("Store is corrupt: {0}")]
+    Corrupt(#[from] CorruptionError),
+    #[error("NodePage {page_id} does not fit into a page")]
+    Full { page_id: u32 },
+    #[error("Page id {page_id} is invalid")]
+    InvalidPageId { page_id: u32 },
+    #[error("Store has reached the maximum of {number_of_pages} pages")]
+    TooManyPages { number_of_pages: u32 },
+    #[error("Store has been opened read-only")]
+    ReadOnly,
+    #[error(transparent)]
+    Wal(#[from] WalError),
+}
+
+// u32::MAX is the NULL page id
+const MAX_NUMBER_OF_PAGES: u32 = u32::MAX - 1;
+
+// Files grow beyond 4 GiB long before the page ids run out, so the offset is computed in 64 bit
+pub(crate) fn page_offset(page_size: u32, page_id: u32) -> u64 {
+    META_DATA_HEADER_SIZE as u64 + page_size as u64 * page_id as u64
+}
+
+pub(crate) fn write_page_image(file: &mut File, page_size: u32, page_id: u32, page: &[u8]) -> io::Result<()> {
+    write_at(file, page_offset(page_size, page_id), page)
+}
+
+// Detects bit rot and pages, which have been written to the wrong place
+fn verify_page(page_id: u32, data: &[u8]) -> Result<(), NodePagerError> {
+    if read_u32(data, POS_CHECKSUM) != page_checksum(data) {
+        return Err(CorruptionError::PageChecksum { page_id }.into());
+    }
+    let stored_page_id = read_u32(data, POS_PAGE_ID);
+    if stored_page_id != page_id {
+        return Err(CorruptionError::PageIdMismatch { page_id, stored_page_id }.into());
+    }
+
+    Ok(())
+}
+
+// Writes the pages and the meta data of a committed record into the store file
+fn apply_record(file: &mut File, record: &WalRecord) -> Result<(), NodePagerError> {
+    for (page_id, page) in &record.pages {
+        write_page_image(file, record.page_size, *page_id, page)
+            .map_err(|source| NodePagerError::Io { context: format!("Cannot write NodePage {}", page_id), source })?;
+    }
+    write_at(file, 0, &record.meta_data)
+        .map_err(|source| NodePagerError::Io { context: "Cannot save StoreMetaData".to_owned(), source })
+}
+
+// Writes all committed records of the WAL into the store file, syncs the file and truncates the WAL.
+// Records are full page images, so records which have already been written can be applied again.
+fn replay(file: &mut File, wal: &Wal) -> Result<(), NodePagerError> {
+    let (records, _) = wal.records()?;
+    for record in &records {
+        apply_record(file, record)?;
+    }
+    file.sync_data()
+        .map_err(|source| NodePagerError::Io { context: "Cannot sync store file".to_owned(), source })?;
+    wal.clear()?;
+
+    Ok(())
 }
 
 
 impl NodePager {
-    fn new(file: File, meta_data: Rc<RefCell<StoreMetaData>>) -> Self {
+    fn new(file: File, meta_data: Arc<RwLock<StoreMetaData>>, journal: Journal, cache_pages: usize) -> Self {
         NodePager { 
-            file: RefCell::new(file),
+            file: Mutex::new(file),
             meta_data,
+            journal,
+            dirty_pages: Mutex::new(BTreeMap::new()),
+            buffer_pool: Mutex::new(BufferPool::new(cache_pages)),
+            sync_mode: SyncMode::default(),
+            checkpoint_pending: AtomicBool::new(false),
+            #[cfg(feature = "mmap")]
+            mapped_file: None,
         }
     }
 
     pub fn page_size(&self) -> u32 {
-        let meta_data = self.meta_data.borrow();
-        let children = (meta_data.max_degree * 4) as u32;
-        let keys = ((meta_data.max_degree - 1) * 4) as u32;
-        let values = ((meta_data.max_degree - 1) * 4) as u32;
+        self.meta_data.read().unwrap().page_size
+    }
+
+    fn read_only(&self) -> bool {
+        matches!(self.journal, Journal::Snapshot(_) | Journal::ReadOnly(_))
+    }
 
-        children + keys + values + PAGE_HEADER_SIZE as u32
+    // The page of a committed WAL record of a store, which has been opened read-only
+    fn replayed_page(&self, page_id: u32) -> Option<&[u8]> {
+        match &self.journal {
+            Journal::ReadOnly(pages) => pages.get(&page_id).map(Vec::as_slice),
+            _ => None,
+        }
     }
 
     pub fn write_page(&self, node: &NodePage) -> Result<(), NodePagerError> {
         if !*node.changed().borrow() {
             return Ok(());
         }
+        if self.read_only() {
+            return Err(NodePagerError::ReadOnly);
+        }
         // TODO: flag "changed" needed for node, so that the content will only be written, if the content has changed.
         if *node.id() == u32::MAX {
-            return Err(NodePagerError { msg: "Cannot save page with the id 0xFFFFFFFF".to_owned() });
+            return Err(NodePagerError::InvalidPageId { page_id: u32::MAX });
         }
-        if *node.deleted() {
-            return Err(NodePagerError { msg: "Cannot write deleted pages. Use delete for this operation".to_owned() });
+
+        if node.used_bytes() > *node.capacity() {
+            return Err(NodePagerError::Full { page_id: *node.id() });
         }
-        
-        let meta_data = self.meta_data.borrow();
-        let mut file= self.file.borrow_mut();
-        let mut data = vec![0xFF; self.page_size() as usize];
+
+        let page_size = self.page_size() as usize;
+        let mut data = vec![0; page_size];
         
         // build page header
         data[POS_PAGE_ID..POS_PAGE_ID + 4].copy_from_slice(&node.id().to_be_bytes());
@@ -200,31 +612,30 @@ impl NodePager {
             false => 0,
         };
         data[POS_NEXT_DELETED_PAGE..POS_NEXT_DELETED_PAGE + 4].copy_from_slice(&get_u32_be_bytes_from_option(node.next_deleted_page()));
+        data[POS_NEXT_LEAF..POS_NEXT_LEAF + 4].copy_from_slice(&get_u32_be_bytes_from_option(node.next_leaf()));
+        data[POS_CELL_COUNT..POS_CELL_COUNT + 2].copy_from_slice(&(node.keys().len() as u16).to_be_bytes());
+        data[POS_FIRST_CHILD..POS_FIRST_CHILD + 4].copy_from_slice(&get_u32_be_bytes_from_option(&node.children().first().copied()));
 
         // build Node
-        let key_offset = key_offset();
+        let mut cell_offset = page_size;
         for (i, k) in node.keys().iter().enumerate() {
-            let current_offset = key_offset + (i * 4);
-            data[current_offset..(current_offset + 4)].copy_from_slice(&k.to_be_bytes());
-        }
+            let pointer = if node.is_leaf() { node.values()[i] } else { node.children()[i + 1] };
+            cell_offset -= cell_size(k) - SLOT_SIZE;
 
-        let child_offset = children_offset(meta_data.max_degree);
-        for (i, c) in node.children().iter().enumerate() {
-            let current_offset = child_offset + (i * 4);
-            data[current_offset..(current_offset + 4)].copy_from_slice(&c.to_be_bytes());
-        }
+            let key_offset = cell_offset + KEY_LENGTH_SIZE;
+            data[cell_offset..key_offset].copy_from_slice(&(k.len() as u16).to_be_bytes());
+            data[key_offset..key_offset + k.len()].copy_from_slice(k);
+            data[key_offset + k.len()..key_offset + k.len() + POINTER_SIZE].copy_from_slice(&pointer.to_be_bytes());
 
-        let values_offset = values_offset(meta_data.max_degree);
-        for (i, v) in node.values().iter().enumerate() {
-            let current_offset = values_offset + (i * 4);
-            data[current_offset..(current_offset + 4)].copy_from_slice(&v.to_be_bytes());
+            let slot_offset = PAGE_HEADER_SIZE + i * SLOT_SIZE;
+            data[slot_offset..slot_offset + SLOT_SIZE].copy_from_slice(&(cell_offset as u16).to_be_bytes());
         }
+        data[POS_CELL_CONTENT..POS_CELL_CONTENT + 2].copy_from_slice(&(cell_offset as u16).to_be_bytes());
+        let checksum = page_checksum(&data);
+        data[POS_CHECKSUM..POS_CHECKSUM + CHECKSUM_SIZE].copy_from_slice(&checksum.to_be_bytes());
 
-        let offset = META_DATA_HEADER_SIZE as u32 + (self.page_size() * node.id());
-        file.seek(std::io::SeekFrom::Start(offset as u64))
-            .map_err(|_| NodePagerError { msg: "Cannot go to offset (read_page error)".to_owned() })?;
-        file.write(&data)
-            .map_err(|e| NodePagerError { msg: format!("Cannot write NodePage: {}", e)})?;
+        // the page is written to the file on commit
+        self.dirty_pages.lock().unwrap().insert(*node.id(), data);
 
         *node.changed().borrow_mut() = false;
 
@@ -232,51 +643,168 @@ impl NodePager {
     }
 
     pub fn read_page(&self, page_id: u32) -> Result<NodePage, NodePagerError> {
-        let mut file= self.file.borrow_mut();
+        self.with_page(page_id, |page| Ok(page.to_node_page()?))
+    }
+
+    // Uses the page in place: the page of the running transaction, of the cache, of the replayed WAL or of the mapping
+    // (which caches the pages of the file). Pages, which are read from the file, are cached.
+    fn with_page<T>(&self, page_id: u32, use_page: impl FnOnce(PageView) -> Result<T, NodePagerError>) -> Result<T, NodePagerError> {
+        if let Some(data) = self.dirty_pages.lock().unwrap().get(&page_id) {
+            return use_page(PageView::new(data)?);
+        }
+        if let Some(data) = self.buffer_pool.lock().unwrap().get(page_id) {
+            return use_page(PageView::new(data)?);
+        }
+        if let Some(data) = self.replayed_page(page_id) {
+            return use_page(PageView::new(data)?);
+        }
+        #[cfg(feature = "mmap")]
+        if let Some(mapped_file) = &self.mapped_file {
+            return self.read_mapped_page(mapped_file, page_id, |data| use_page(PageView::new(data)?));
+        }
+
+        let data = self.read_page_from_file(page_id)?;
+        let result = use_page(PageView::new(&data)?);
+        self.cache_page(page_id, data, false);
+
+        result
+    }
+
+    // Uses the leaf, which contains the key (or would contain it), the leftmost leaf if key is None.
+    // Only the keys of the path are compared, no page is parsed.
+    fn with_leaf<T>(&self, root: u32, key: Option<&[u8]>, use_leaf: impl FnOnce(PageView) -> Result<T, NodePagerError>) -> Result<T, NodePagerError> {
+        let mut use_leaf = Some(use_leaf);
+        let mut page_id = root;
+        loop {
+            let step = self.with_page(page_id, |page| match page.is_leaf() {
+                true => Ok(ControlFlow::Break(use_leaf.take().unwrap()(page)?)),
+                false => Ok(ControlFlow::Continue(page.child(key)?)),
+            })?;
+            match step {
+                ControlFlow::Break(result) => return Ok(result),
+                ControlFlow::Continue(child) => page_id = child,
+            }
+        }
+    }
+
+    fn find(&self, root: u32, key: &[u8]) -> Result<Option<u32>, NodePagerError> {
+        self.with_leaf(root, Some(key), |leaf| match leaf.search(key)? {
+            Ok(slot) => Ok(Some(leaf.cell(slot)?.1)),
+            Err(_) => Ok(None),
+        })
+    }
+
+    // The position of the page in the store file
+    fn slot(&self, page_id: u32) -> Result<u32, NodePagerError> {
+        match &self.journal {
+            Journal::Wal(_) | Journal::ReadOnly(_) => Ok(page_id),
+            Journal::Shadow(page_table) => page_table.lock().unwrap().slot(page_id).ok_or(NodePagerError::InvalidPageId { page_id }),
+            Journal::Snapshot(page_table) => page_table.slot(page_id).ok_or(NodePagerError::InvalidPageId { page_id }),
+        }
+    }
+
+    fn read_page_from_file(&self, page_id: u32) -> Result<Vec<u8>, NodePagerError> {
+        if let Some(data) = self.replayed_page(page_id) {
+            return Ok(data.to_vec());
+        }
+        #[cfg(feature = "mmap")]
+        if let Some(mapped_file) = &self.mapped_file {
+            return self.read_mapped_page(mapped_file, page_id, |data| Ok(data.to_vec()));
+        }
+
+        let slot = self.slot(page_id)?;
+        let mut file= self.file.lock().unwrap();
         let mut data = vec![0; self.page_size() as usize];
-        let offset = META_DATA_HEADER_SIZE as u32 + (self.page_size() * page_id);
-        file.seek(std::io::SeekFrom::Start(offset as u64))
-            .map_err(|_| NodePagerError { msg: "Cannot go to offset (read_page error)".to_owned() })?;
+        file.seek(std::io::SeekFrom::Start(page_offset(self.page_size(), slot)))
+            .map_err(|source| NodePagerError::Io { context: format!("Cannot go to NodePage {}", page_id), source })?;
 
         file.read_exact(&mut data)
-            .map_err(|e| NodePagerError { msg: format!("Cannot read data (read_page). {}", e)})?;
+            .map_err(|source| NodePagerError::Io { context: format!("Cannot read NodePage {}", page_id), source })?;
+        verify_page(page_id, &data)?;
+
+        Ok(data)
+    }
+
+    // The page is used, while the file is locked, so that it is not written in the meantime
+    #[cfg(feature = "mmap")]
+    fn read_mapped_page<T>(&self, mapped_file: &Mutex<MappedFile>, page_id: u32, use_page: impl FnOnce(&[u8]) -> Result<T, NodePagerError>) -> Result<T, NodePagerError> {
+        let slot = self.slot(page_id)?;
+        let page_size = self.page_size();
+        let file = self.file.lock().unwrap();
+        let mut mapped_file = mapped_file.lock().unwrap();
+        let data = mapped_file.read(&file, page_offset(page_size, slot), page_size as usize)
+            .map_err(|source| NodePagerError::Io { context: format!("Cannot read NodePage {}", page_id), source })?;
+        verify_page(page_id, data)?;
+
+        use_page(data)
+    }
 
-        Ok((data, self.meta_data.borrow().max_degree).into())
+    fn cache_page(&self, page_id: u32, data: Vec<u8>, dirty: bool) {
+        let page_size = self.page_size();
+        let mut file = self.file.lock().unwrap();
+        self.buffer_pool.lock().unwrap().insert(page_id, data, dirty, |page_id, data| write_page_image(&mut file, page_size, page_id, data));
+    }
+
+    // Keeps the page in the cache, until it is unpinned
+    pub fn pin(&self, page_id: u32) -> Result<(), NodePagerError> {
+        if !self.buffer_pool.lock().unwrap().contains(page_id) {
+            let data = self.read_page_from_file(page_id)?;
+            self.cache_page(page_id, data, false);
+        }
+        self.buffer_pool.lock().unwrap().pin(page_id);
+
+        Ok(())
+    }
+
+    pub fn unpin(&self, page_id: u32) {
+        self.buffer_pool.lock().unwrap().unpin(page_id);
+    }
+
+    pub fn cache_stats(&self) -> BufferPoolStats {
+        self.buffer_pool.lock().unwrap().stats()
     }
 
     pub fn delete_page(&self, page_id: u32) -> Result<(), NodePagerError> {
         if page_id == u32::MAX {
-            return Err(NodePagerError { msg: "Cannot delete page_id 0xFFFFFFFF".to_owned() });
+            return Err(NodePagerError::InvalidPageId { page_id });
         }
 
-        let first_deleted_page = self.meta_data.borrow().first_deleted_page;
+        let first_deleted_page = self.meta_data.read().unwrap().first_deleted_page;
         let mut node = self.read_page(page_id)?;
+        if *node.deleted() {
+            return Err(CorruptionError::DeletedPage { page_id }.into());
+        }
+        // the deleted page links to the previous head of the free list, so that the list survives reopening
         node.delete_page(first_deleted_page);
-        self.meta_data.borrow_mut().set_first_deleted_page(Some(*node.id()));
+        self.write_page(&node)?;
+        self.meta_data.write().unwrap().set_first_deleted_page(Some(*node.id()));
 
         Ok(())
     }
 
     pub fn allocate_new_page(&self) -> Result<NodePage, NodePagerError> {
         // Is there a deleted page?
-        let first_deleted = self.meta_data.borrow().first_deleted_page;
+        let first_deleted = self.meta_data.read().unwrap().first_deleted_page;
         if let Some(first_deleted) = first_deleted {
-            match self.read_page(first_deleted) {
-                Ok(mut allocated) => {
-                    self.meta_data.borrow_mut().set_first_deleted_page(*allocated.next_deleted_page());
-                    allocated.reallocate();
-                    self.write_page(&allocated)?;
-                    return Ok(allocated);
-                },
-                Err(e) => 
-                    return Err(
-                        NodePagerError { msg: format!("Failed to reallocate page with ID = {}, err = {}", first_deleted, e)}
-                    ),
-            };
+            let mut allocated = self.read_page(first_deleted)?;
+            if !*allocated.deleted() {
+                return Err(CorruptionError::BrokenFreeList { page_id: first_deleted }.into());
+            }
+            self.meta_data.write().unwrap().set_first_deleted_page(*allocated.next_deleted_page());
+            allocated.reallocate();
+            self.write_page(&allocated)?;
+            // is likely to change after allocation
+            *allocated.changed().borrow_mut() = true;
+            Ok(allocated)
         } else {
-            self.meta_data.borrow_mut().inc_number_of_pages();
-            let next_id = self.meta_data.borrow().number_of_pages - 1;
-            let node = NodePage::new(self.meta_data.borrow().max_degree as usize, next_id);
+            let mut meta_data = self.meta_data.write().unwrap();
+            if meta_data.number_of_pages >= MAX_NUMBER_OF_PAGES {
+                return Err(NodePagerError::TooManyPages { number_of_pages: meta_data.number_of_pages });
+            }
+            meta_data.inc_number_of_pages();
+            let next_id = meta_data.number_of_pages - 1;
+            drop(meta_data);
+            let node = NodePage::new(self.page_size() as usize - PAGE_HEADER_SIZE, next_id);
             self.write_page(&node)?;
             // is likely to change after allocation
             *node.changed().borrow_mut() = true;
@@ -284,182 +812,1134 @@ impl NodePager {
         }
 
     }
+
+    // Commits the running transaction: the changed pages and the meta data are appended to the WAL as a single record,
+    // afterwards they are written to the store file. The transaction is committed, as soon as this returns Ok,
+    // the checkpoint of SyncMode::Always follows separately (see checkpoint_after_commit).
+    pub fn commit(&self) -> Result<(), NodePagerError> {
+        if self.dirty_pages.lock().unwrap().is_empty() && !self.meta_data.read().unwrap().changed {
+            return Ok(());
+        }
+
+        let dirty = match &self.journal {
+            Journal::Wal(wal) => {
+                let record = WalRecord {
+                    page_size: self.page_size(),
+                    pages: self.dirty_pages.lock().unwrap().iter().map(|(id, page)| (*id, page.clone())).collect(),
+                    meta_data: meta_data_to_bytes(&self.meta_data.read().unwrap()),
+                };
+                wal.append(&record)?;
+                if self.sync_mode != SyncMode::Never {
+                    wal.sync()?;
+                }
+                // the pages are written to the file on eviction or checkpoint
+                true
+            },
+            Journal::Shadow(page_table) => {
+                self.commit_shadow(page_table)?;
+                false
+            },
+            Journal::Snapshot(_) | Journal::ReadOnly(_) => return Err(NodePagerError::ReadOnly),
+        };
+
+        // the transaction is committed
+        let pages = std::mem::take(&mut *self.dirty_pages.lock().unwrap());
+        for (page_id, page) in pages {
+            self.cache_page(page_id, page, dirty);
+        }
+        self.meta_data.write().unwrap().changed = false;
+
+        Ok(())
+    }
+
+    // Checkpoint of SyncMode::Always, which follows a commit. It is best-effort: the commit is already durable in the WAL,
+    // a failed checkpoint is recorded and retried after the next commit (in every sync mode) and on drop.
+    pub fn checkpoint_after_commit(&self) {
+        if (self.sync_mode == SyncMode::Always || self.checkpoint_pending()) && self.checkpoint().is_err() {
+            self.checkpoint_pending.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn checkpoint_pending(&self) -> bool {
+        self.checkpoint_pending.load(Ordering::Relaxed)
+    }
+
+    // The pages are written to free slots, the commit becomes visible with the header of the new page table
+    fn commit_shadow(&self, page_table: &Mutex<PageTable>) -> Result<(), NodePagerError> {
+        let page_size = self.page_size();
+        let mut file = self.file.lock().unwrap();
+        let mut page_table = page_table.lock().unwrap();
+        let mut meta_data = self.meta_data.read().unwrap().clone();
+        let (mut next, replaced) = page_table.write(&mut file, page_size, &self.dirty_pages.lock().unwrap(), meta_data.number_of_pages)
+            .map_err(|source| NodePagerError::Io { context: "Cannot write shadow pages".to_owned(), source })?;
+        // the pages must be on the disk, before the header points to them
+        self.sync_file(&file)?;
+
+        meta_data.sequence = next.sequence();
+        meta_data.page_table = next.root_slot();
+        write_at(&mut file, next.header_offset(page_size), &meta_data_to_bytes(&meta_data))
+            .map_err(|source| NodePagerError::Io { context: "Cannot save StoreMetaData".to_owned(), source })?;
+        self.sync_file(&file)?;
+
+        // the previous commit is not needed anymore
+        next.release(replaced);
+        *page_table = next;
+        let mut shared_meta_data = self.meta_data.write().unwrap();
+        shared_meta_data.sequence = meta_data.sequence;
+        shared_meta_data.page_table = meta_data.page_table;
+
+        Ok(())
+    }
+
+    fn sync_file(&self, file: &File) -> Result<(), NodePagerError> {
+        if self.sync_mode == SyncMode::Never {
+            return Ok(());
+        }
+        file.sync_data()
+            .map_err(|source| NodePagerError::Io { context: "Cannot sync store file".to_owned(), source })
+    }
+
+    // Discards all pages changed by the running transaction
+    pub fn rollback(&self) {
+        self.dirty_pages.lock().unwrap().clear();
+    }
+
+    // Writes all committed pages and the meta data into the store file, the WAL is kept.
+    // Shadow paging writes the pages on commit.
+    pub fn flush(&self) -> Result<(), NodePagerError> {
+        match self.journal {
+            Journal::Wal(_) => self.write_back().map(|_| ()),
+            Journal::Shadow(_) | Journal::Snapshot(_) | Journal::ReadOnly(_) => Ok(()),
+        }
+    }
+
+    // Writes all committed pages and the meta data into the store file and truncates the WAL.
+    // The store file is synced before, unless the sync mode is Never.
+    pub fn checkpoint(&self) -> Result<(), NodePagerError> {
+        self.checkpoint_with(self.sync_mode != SyncMode::Never)
+    }
+
+    // Checkpoint, which syncs in every sync mode
+    pub fn sync(&self) -> Result<(), NodePagerError> {
+        self.checkpoint_with(true)
+    }
+
+    fn checkpoint_with(&self, sync: bool) -> Result<(), NodePagerError> {
+        let file = match self.journal {
+            Journal::Wal(_) => self.write_back()?,
+            Journal::Shadow(_) => self.file.lock().unwrap(),
+            Journal::Snapshot(_) | Journal::ReadOnly(_) => return Ok(()),
+        };
+        if sync {
+            file.sync_data()
+                .map_err(|source| NodePagerError::Io { context: "Cannot sync store file".to_owned(), source })?;
+        }
+        if let Journal::Wal(wal) = &self.journal {
+            wal.clear()?;
+        }
+        self.checkpoint_pending.store(false, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    // Returns the locked store file, so that nothing is written between the write back and a following sync
+    fn write_back(&self) -> Result<MutexGuard<'_, File>, NodePagerError> {
+        let page_size = self.page_size();
+        let mut file = self.file.lock().unwrap();
+        self.buffer_pool.lock().unwrap().flush(|page_id, data| write_page_image(&mut file, page_size, page_id, data))
+            .map_err(|source| NodePagerError::Io { context: "Cannot write NodePage".to_owned(), source })?;
+        write_at(&mut file, 0, &meta_data_to_bytes(&self.meta_data.read().unwrap()))
+            .map_err(|source| NodePagerError::Io { context: "Cannot save StoreMetaData".to_owned(), source })?;
+
+        Ok(file)
+    }
 }
 
-pub struct BTreeStore {
+// Number of pages, which are cached by default
+pub const DEFAULT_CACHE_PAGES: usize = 64;
+
+// Pages of a bulk load are filled up to this share of their capacity, the rest is left for later inserts
+pub const DEFAULT_FILL_FACTOR: f64 = 0.9;
+// A bulk load commits its pages in batches, so that they do not pile up in memory
+const BULK_LOAD_BATCH_PAGES: usize = 64;
+
+// Keys are stored in their encoded form (see key.rs), K defaults to u32
+pub struct BTreeStore<K = u32> {
     pager: NodePager,
-    meta_data: Rc<RefCell<StoreMetaData>>,
+    meta_data: Arc<RwLock<StoreMetaData>>,
+    pinned_root: Mutex<Option<u32>>, // the root is needed by every operation, so it is pinned in the cache
+    file_path: PathBuf, // snapshots read the store file with their own handle
+    key_type: PhantomData<K>,
 }
 
 #[derive(Debug, Error)]
-#[error("B+ Tree error: {msg}")]
-pub struct BTreeStoreError {
-    msg: String
+pub enum BTreeStoreError {
+    #[error("{context}: {source}")]
+    Io { context: String, #[source] source: io::Error },
+    #[error("Store is corrupt: {0}")]
+    Corrupt(#[from] CorruptionError),
+    #[error("NodePage {page_id} does not fit into a page")]
+    Full { page_id: u32 },
+    #[error("Key of {key_size} bytes exceeds the maximum key size of {max_key_size} bytes")]
+    KeyTooLarge { key_size: usize, max_key_size: usize },
+    #[error("Page {page_id} does not exist")]
+    InvalidPageId { page_id: u32 },
+    #[error("Store has reached the maximum of {number_of_pages} pages")]
+    TooManyPages { number_of_pages: u32 },
+    #[error("Key already exists")]
+    DuplicateKey,
+    #[error("Store has been opened read-only")]
+    ReadOnly,
+    // snapshots of an earlier open pin slots of the store file
+    #[error("Store has snapshots, which must be dropped before it is opened again")]
+    SnapshotsAlive,
+    // the file has not been created by this implementation or with other settings
+    #[error("{msg}")]
+    Incompatible { msg: String },
+    #[error("{msg}")]
+    InvalidArgument { msg: String },
+    #[error(transparent)]
+    Wal(#[from] WalError),
 }
 
 impl From<NodePagerError> for BTreeStoreError {
     fn from(value: NodePagerError) -> Self {
-        Self {
-            msg: format!("BTreeStoreError occurred. err={}", value),
+        match value {
+            NodePagerError::Io { context, source } => Self::Io { context, source },
+            NodePagerError::Corrupt(corruption) => Self::Corrupt(corruption),
+            NodePagerError::Full { page_id } => Self::Full { page_id },
+            NodePagerError::InvalidPageId { page_id } => Self::InvalidPageId { page_id },
+            NodePagerError::TooManyPages { number_of_pages } => Self::TooManyPages { number_of_pages },
+            NodePagerError::ReadOnly => Self::ReadOnly,
+            NodePagerError::Wal(wal_error) => Self::Wal(wal_error),
         }
     }
 }
 
 impl BTreeStore {
     pub fn new(file_path: &Path, max_degree: u16) -> Result<Self, BTreeStoreError> {
+        Self::open(file_path, max_degree)
+    }
+}
+
+impl<K: StoreKey> BTreeStore<K> {
+    // Opens (or creates) a store with keys of type K.
+    // max_degree sizes the pages: a page has room for max_degree keys of K (u32 keys for keys of variable length), shorter keys need less space.
+    pub fn open(file_path: &Path, max_degree: u16) -> Result<Self, BTreeStoreError> {
+        Self::open_with(file_path, max_degree, IndexMode::Unique, DEFAULT_CACHE_PAGES)
+    }
+
+    // Like open, but caches up to cache_pages pages
+    pub fn open_with_cache(file_path: &Path, max_degree: u16, cache_pages: usize) -> Result<Self, BTreeStoreError> {
+        Self::open_with(file_path, max_degree, IndexMode::Unique, cache_pages)
+    }
+
+    // Opens (or creates) a non-unique index, which stores several values per key. The pages have room for the appended values.
+    pub fn open_multimap(file_path: &Path, max_degree: u16) -> Result<Self, BTreeStoreError> {
+        Self::open_with(file_path, max_degree, IndexMode::NonUnique, DEFAULT_CACHE_PAGES)
+    }
+
+    // The index mode is recorded at creation, an existing store must be opened with the same mode.
+    // A new store uses a WAL, an existing store keeps the pager mode of its header.
+    pub fn open_with(file_path: &Path, max_degree: u16, index_mode: IndexMode, cache_pages: usize) -> Result<Self, BTreeStoreError> {
+        Self::open_store(file_path, max_degree, Some(index_mode), None, cache_pages)
+    }
+
+    // Opens (or creates) a store, which uses shadow paging instead of a WAL
+    pub fn open_shadow_paged(file_path: &Path, max_degree: u16) -> Result<Self, BTreeStoreError> {
+        Self::open_with_pager_mode(file_path, max_degree, IndexMode::Unique, PagerMode::Shadow, DEFAULT_CACHE_PAGES)
+    }
+
+    // Like open_with, but an existing store must have been created with the pager mode as well
+    pub fn open_with_pager_mode(file_path: &Path, max_degree: u16, index_mode: IndexMode, pager_mode: PagerMode, cache_pages: usize) -> Result<Self, BTreeStoreError> {
+        Self::open_store(file_path, max_degree, Some(index_mode), Some(pager_mode), cache_pages)
+    }
+
+    // Opens a store, which has been created before, with the max degree and the modes of its header
+    pub fn open_existing(file_path: &Path) -> Result<Self, BTreeStoreError> {
+        Self::open_store(file_path, 4, None, None, DEFAULT_CACHE_PAGES)
+    }
+
+    // index_mode None only opens existing stores and takes the mode from the header.
+    // pager_mode None takes the mode from the header, new stores use a WAL.
+    fn open_store(file_path: &Path, max_degree: u16, index_mode: Option<IndexMode>, pager_mode: Option<PagerMode>, cache_pages: usize) -> Result<Self, BTreeStoreError> {
+        if cache_pages == 0 {
+            return Err(BTreeStoreError::InvalidArgument { msg: "BTreeStore must cache at least one page".to_owned() });
+        }
         if max_degree < 4 {
-            return Err(BTreeStoreError { msg: "BTreeStore must have at least a max degree of 4".to_owned() });
+            return Err(BTreeStoreError::InvalidArgument { msg: "BTreeStore must have at least a max degree of 4".to_owned() });
+        }
+        // cell offsets are stored in 2 bytes
+        let key_size = sized_key_size::<K>(index_mode.unwrap_or(IndexMode::Unique));
+        if page_size(max_degree, key_size) > u16::MAX as usize {
+            return Err(BTreeStoreError::InvalidArgument { msg: format!("BTreeStore must have a max degree of at most {}", (u16::MAX as usize - PAGE_HEADER_SIZE) / (cell_size(&[]) + key_size)) });
         }
 
         // check if file already exists
         let store_meta_data;
-        let file_meta_data = fs::metadata(file_path)
-            .map_err(|err| BTreeStoreError { msg: err.to_string() })?;
-        let file_size = file_meta_data.len();
-
-        let file = match OpenOptions::new().read(true).write(true).open(file_path) {
-            Ok(mut f) if file_size >= META_DATA_HEADER_SIZE as u64 => {
-                let mut metadata_bytes = [0u8; META_DATA_HEADER_SIZE];
-                f.read_exact(&mut metadata_bytes).expect("Cannot read meta data from file");
+        fs::metadata(file_path)
+            .map_err(|source| BTreeStoreError::Io { context: format!("Cannot open {}", file_path.display()), source })?;
+        if shadow::has_snapshots(file_path) {
+            return Err(BTreeStoreError::SnapshotsAlive);
+        }
 
-                let max_degree = u16::from_be_bytes(metadata_bytes[0..2].try_into().unwrap());
-                let number_of_pages = u32::from_be_bytes(metadata_bytes[2..6].try_into().unwrap());
-                let first_deleted_page = u32::from_be_bytes(metadata_bytes[6..10].try_into().unwrap());
-                let root = u32::from_be_bytes(metadata_bytes[10..14].try_into().unwrap());
+        // operations in the WAL might not have reached the store file before a crash
+        let wal_path = Wal::path(file_path);
+        if wal_path.exists() {
+            let wal = Wal::open(&wal_path)?;
+            if !wal.is_empty() {
+                let mut f = OpenOptions::new().read(true).write(true).open(file_path)
+                    .map_err(|source| BTreeStoreError::Io { context: format!("Cannot open {}", file_path.display()), source })?;
+                replay(&mut f, &wal)?;
+            }
+        }
 
-                store_meta_data = StoreMetaData {
-                    max_degree,
-                    number_of_pages,
-                    first_deleted_page: read_u32_with_null(first_deleted_page),
-                    root: read_u32_with_null(root),
-                    changed: false,
-                };
+        let file_meta_data = fs::metadata(file_path)
+            .map_err(|source| BTreeStoreError::Io { context: format!("Cannot open {}", file_path.display()), source })?;
+        let file_size = file_meta_data.len();
 
+        let mut file = match OpenOptions::new().read(true).write(true).open(file_path) {
+            Ok(mut f) if file_size > 0 => {
+                store_meta_data = Self::read_meta_data(&mut f, file_path, index_mode, pager_mode)?;
                 f
             }
             _ => {
+                let Some(index_mode) = index_mode else {
+                    return Err(BTreeStoreError::Incompatible { msg: format!("{} is not a BTreeStore", file_path.display()) });
+                };
                 let mut f = OpenOptions::new()
                     .read(true)
                     .write(true)
                     .create(true)
+                    .truncate(true)
                     .open(file_path)
-                    .expect("Failed to create file");
+                    .map_err(|source| BTreeStoreError::Io { context: format!("Cannot create {}", file_path.display()), source })?;
 
                 store_meta_data = StoreMetaData { 
+                    page_size: page_size(max_degree, key_size) as u32,
+                    key_width: key_width::<K>(),
                     max_degree,
                     number_of_pages: 0,
                     first_deleted_page: None,
                     root: None,
+                    index_mode,
+                    pager_mode: pager_mode.unwrap_or(PagerMode::Wal),
+                    sequence: 0,
+                    page_table: None,
+                    key_type: K::TYPE_ID,
                     changed: false,
                 };
                 
                 let metadata_bytes = meta_data_to_bytes(&store_meta_data);
 
-                f.write_all(&metadata_bytes).expect("Failed to write metadata");
-                f.flush().expect("Failed to flush metadata on INIT");
+                f.write_all(&metadata_bytes)
+                    .and_then(|_| f.flush())
+                    .map_err(|source| BTreeStoreError::Io { context: "Cannot write meta data".to_owned(), source })?;
 
                 f
             }
         };
 
-        let rc_meta_data = Rc::new(RefCell::new(store_meta_data));
+        let journal = match store_meta_data.pager_mode {
+            PagerMode::Wal => Journal::Wal(Wal::open(&wal_path)?),
+            PagerMode::Shadow => Journal::Shadow(Arc::new(Mutex::new(PageTable::load(
+                &mut file, store_meta_data.page_size, store_meta_data.page_table, store_meta_data.number_of_pages, store_meta_data.sequence
+            )?))),
+        };
+        let shared_meta_data = Arc::new(RwLock::new(store_meta_data));
+
+        let store = BTreeStore { 
+            pager: NodePager::new(file, Arc::clone(&shared_meta_data), journal, cache_pages), 
+            meta_data: shared_meta_data,
+            pinned_root: Mutex::new(None),
+            file_path: file_path.to_path_buf(),
+            key_type: PhantomData,
+        };
+        store.pin_root()?;
 
-        Ok(BTreeStore { 
-            pager: NodePager::new(file, Rc::clone(&rc_meta_data)), 
-            meta_data: rc_meta_data
-        })
+        Ok(store)
     }
 
-    #[allow(dead_code)]
-    fn page_size(&self) -> u32 {
-        self.pager.page_size()
+    // Reads the header of an existing store and checks, that it has been created with K and the given modes
+    fn read_meta_data(file: &mut File, file_path: &Path, index_mode: Option<IndexMode>, pager_mode: Option<PagerMode>) -> Result<StoreMetaData, BTreeStoreError> {
+        let store_meta_data = read_header(file, file_path)?;
+
+        if index_mode.is_some_and(|index_mode| index_mode != store_meta_data.index_mode) {
+            return Err(BTreeStoreError::Incompatible { msg: format!("BTreeStore has been created with index mode {:?}", store_meta_data.index_mode) });
+        }
+        if pager_mode.is_some_and(|pager_mode| pager_mode != store_meta_data.pager_mode) {
+            return Err(BTreeStoreError::Incompatible { msg: format!("BTreeStore has been created with pager mode {:?}", store_meta_data.pager_mode) });
+        }
+        if store_meta_data.key_width != key_width::<K>() {
+            return Err(BTreeStoreError::Incompatible { msg: format!(
+                "BTreeStore has been created with keys {}, not {}",
+                describe_key_width(store_meta_data.key_width), describe_key_width(key_width::<K>())
+            ) });
+        }
+        if store_meta_data.key_type != K::TYPE_ID {
+            return Err(BTreeStoreError::Incompatible { msg: format!(
+                "BTreeStore has been created with the key type {:#010x}, not {:#010x} ({})",
+                store_meta_data.key_type, K::TYPE_ID, std::any::type_name::<K>()
+            ) });
+        }
+
+        Ok(store_meta_data)
     }
 
-    pub fn find(&self, key: u32) -> Result<Option<u32>, BTreeStoreError> {
-        let root = self.root()?;
+    // Opens a store, which has been created before, without writing to it: the file is opened read-only, the committed
+    // records of the WAL are applied in memory only and nothing is written on drop. Changes fail with BTreeStoreError::ReadOnly.
+    // The store must not be changed by others, while it is open.
+    pub fn open_read_only(file_path: &Path) -> Result<Self, BTreeStoreError> {
+        let mut file = OpenOptions::new().read(true).open(file_path)
+            .map_err(|source| BTreeStoreError::Io { context: format!("Cannot open {}", file_path.display()), source })?;
+        let mut store_meta_data = Self::read_meta_data(&mut file, file_path, None, None)?;
+
+        let journal = match store_meta_data.pager_mode {
+            PagerMode::Wal => {
+                let records = Wal::read_records(&Wal::path(file_path))?;
+                if let Some(record) = records.last() {
+                    store_meta_data = meta_data_from_bytes(&record.meta_data)?;
+                }
+                // later records replace the pages of earlier ones
+                Journal::ReadOnly(records.into_iter().flat_map(|record| record.pages).collect())
+            },
+            PagerMode::Shadow => Journal::Snapshot(PinnedPageTable::new(Arc::new(Mutex::new(PageTable::load(
+                &mut file, store_meta_data.page_size, store_meta_data.page_table, store_meta_data.number_of_pages, store_meta_data.sequence
+            )?)))),
+        };
+        let shared_meta_data = Arc::new(RwLock::new(store_meta_data));
+
+        let store = BTreeStore {
+            pager: NodePager::new(file, Arc::clone(&shared_meta_data), journal, DEFAULT_CACHE_PAGES),
+            meta_data: shared_meta_data,
+            pinned_root: Mutex::new(None),
+            file_path: file_path.to_path_buf(),
+            key_type: PhantomData,
+        };
+        store.pin_root()?;
 
-        Ok(root.find(&self.pager, key))
+        Ok(store)
     }
 
-    pub fn insert(&mut self, key: u32, value: u32) -> Result<(), BTreeStoreError> {
-        let mut root = self.root()?;
-        if root.is_full() {
-            let (lnode, rnode, root_key) = root.split(&self.pager);
-            let mut new_root = self.pager.allocate_new_page()
-                .map_err(|_| BTreeStoreError { msg: "Cannot allocate new page (op: insert)".to_owned() })?;
-            new_root.keys_mut().push(root_key);
-            new_root.children_mut().push(*lnode.id());
-            new_root.children_mut().push(*rnode.id());
+    // Creates the tree from entries sorted by key, see bulk_load_with_fill_factor
+    pub fn bulk_load(file_path: &Path, max_degree: u16, entries: impl IntoIterator<Item = (K, u32)>) -> Result<Self, BTreeStoreError> {
+        Self::bulk_load_with_fill_factor(file_path, max_degree, DEFAULT_FILL_FACTOR, entries)
+    }
 
-            self.meta_data.borrow_mut().root = Some(*new_root.id());
-            // TODO: new root
-            root = new_root;
+    // Creates the tree from entries sorted by key (without duplicates). The leaves are built from left to right,
+    // each filled up to fill_factor of its capacity, afterwards the internal levels are built bottom-up.
+    // The store must be empty. If the entries are not sorted, the load fails and the store stays empty.
+    pub fn bulk_load_with_fill_factor(file_path: &Path, max_degree: u16, fill_factor: f64, entries: impl IntoIterator<Item = (K, u32)>) -> Result<Self, BTreeStoreError> {
+        if !(0.5..=1.0).contains(&fill_factor) {
+            return Err(BTreeStoreError::InvalidArgument { msg: format!("Fill factor must be between 0.5 and 1.0, got {}", fill_factor) });
+        }
+        let store = Self::open(file_path, max_degree)?;
+        if store.meta_data.read().unwrap().root.is_some() {
+            return Err(BTreeStoreError::InvalidArgument { msg: "bulk_load needs an empty store".to_owned() });
+        }
+
+        let empty_meta_data = store.meta_data.read().unwrap().clone();
+        let result = store.build_levels(fill_factor, entries);
+        if result.is_err() {
+            // forget the pages of the committed batches as well
+            store.pager.rollback();
+            *store.meta_data.write().unwrap() = StoreMetaData { changed: true, ..empty_meta_data };
+            store.pager.commit()?;
+        }
+        let root = result?;
+
+        if let Some(root) = root {
+            store.meta_data.write().unwrap().set_root(root);
+            store.pager.commit()?;
+            store.pin_root()?;
+        }
+
+        Ok(store)
+    }
+
+    // Returns the root of the built tree, None if there are no entries
+    fn build_levels(&self, fill_factor: f64, entries: impl IntoIterator<Item = (K, u32)>) -> Result<Option<u32>, BTreeStoreError> {
+        let mut leaves = LevelBuilder::new(&self.pager, true, fill_factor);
+        let mut previous_key: Option<Vec<u8>> = None;
+        for (key, value) in entries {
+            let key = key::encode(&key);
+            if key.len() > self.max_key_size() {
+                return Err(BTreeStoreError::KeyTooLarge { key_size: key.len(), max_key_size: self.max_key_size() });
+            }
+            if previous_key.as_ref().is_some_and(|previous_key| *previous_key >= key) {
+                return Err(BTreeStoreError::InvalidArgument { msg: "Entries of bulk_load must be sorted by key without duplicates".to_owned() });
+            }
+            previous_key = Some(key.clone());
+
+            leaves.push(key, value)?;
+        }
+
+        let mut level = leaves.finish()?;
+        while level.len() > 1 {
+            let mut internal_nodes = LevelBuilder::new(&self.pager, false, fill_factor);
+            for (key, child) in level {
+                internal_nodes.push(key, child)?;
+            }
+            level = internal_nodes.finish()?;
+        }
+
+        Ok(level.first().map(|(_, root)| *root))
+    }
+
+    pub fn page_size(&self) -> u32 {
+        self.pager.page_size()
+    }
+
+    // The header of the store (with the changes of committed transactions, which might not be checkpointed yet)
+    pub fn meta_data(&self) -> StoreMetaData {
+        self.meta_data.read().unwrap().clone()
+    }
+
+    // Reads a page for inspection, the page is not part of a transaction
+    pub fn read_page(&self, page_id: u32) -> Result<NodePage, BTreeStoreError> {
+        let number_of_pages = self.meta_data.read().unwrap().number_of_pages;
+        if page_id >= number_of_pages {
+            return Err(BTreeStoreError::InvalidPageId { page_id });
+        }
+
+        Ok(self.pager.read_page(page_id)?)
+    }
+
+    // Longest encoded key, which can be stored
+    pub fn max_key_size(&self) -> usize {
+        let max_key_size = (self.page_size() as usize - PAGE_HEADER_SIZE) / 4 - cell_size(&[]);
+        match self.index_mode() {
+            IndexMode::Unique => max_key_size,
+            IndexMode::NonUnique => max_key_size - VALUE_SUFFIX_SIZE,
+        }
+    }
+
+    pub fn index_mode(&self) -> IndexMode {
+        self.meta_data.read().unwrap().index_mode
+    }
+
+    // The key as it is stored in the pages
+    fn entry_key(&self, key: &K, value: u32) -> Vec<u8> {
+        let mut entry_key = key::encode(key);
+        if self.index_mode() == IndexMode::NonUnique {
+            entry_key.extend_from_slice(&value.to_be_bytes());
+        }
+        entry_key
+    }
+
+    // Returns the (smallest) value of the key
+    pub fn find(&self, key: impl borrow::Borrow<K>) -> Result<Option<u32>, BTreeStoreError> {
+        if self.index_mode() == IndexMode::NonUnique {
+            return self.find_all(key)?.next().transpose();
+        }
+
+        match self.meta_data.read().unwrap().root {
+            Some(root) => Ok(self.pager.find(root, &key::encode(borrow::Borrow::borrow(&key)))?),
+            None => Ok(None),
+        }
+    }
+
+    // Returns all values of the key in ascending order. A unique index returns at most one value.
+    pub fn find_all(&self, key: impl borrow::Borrow<K>) -> Result<impl Iterator<Item = Result<u32, BTreeStoreError>> + '_, BTreeStoreError> {
+        let key = borrow::Borrow::borrow(&key);
+        let range = match self.index_mode() {
+            IndexMode::Unique => self.range_encoded(Bound::Included(key::encode(key)), Bound::Included(key::encode(key)))?,
+            IndexMode::NonUnique => self.range_encoded(Bound::Included(self.entry_key(key, 0)), Bound::Included(self.entry_key(key, u32::MAX)))?,
+        };
+
+        Ok(range.map(|entry| entry.map(|(_, value)| value)))
+    }
+
+    // Inserts or overwrites the value of the key. Returns the previous value.
+    pub fn insert(&mut self, key: impl borrow::Borrow<K>, value: u32) -> Result<Option<u32>, BTreeStoreError> {
+        let mut transaction = self.begin();
+        let previous = transaction.insert(key, value)?;
+        transaction.commit()?;
+
+        Ok(previous)
+    }
+
+    // Keeps the value of an existing key. Returns true, if the key has been inserted.
+    pub fn insert_if_absent(&mut self, key: impl borrow::Borrow<K>, value: u32) -> Result<bool, BTreeStoreError> {
+        let mut transaction = self.begin();
+        let inserted = transaction.insert_if_absent(key, value)?;
+        transaction.commit()?;
+
+        Ok(inserted)
+    }
+
+    // Fails, if the key already exists
+    pub fn try_insert(&mut self, key: impl borrow::Borrow<K>, value: u32) -> Result<(), BTreeStoreError> {
+        let mut transaction = self.begin();
+        transaction.try_insert(key, value)?;
+        transaction.commit()
+    }
+
+    // Deletes the key with all its values. Returns the (smallest) deleted value.
+    pub fn delete(&mut self, key: impl borrow::Borrow<K>) -> Result<Option<u32>, BTreeStoreError> {
+        let mut transaction = self.begin();
+        let res = transaction.delete(key)?;
+        transaction.commit()?;
+
+        Ok(res)
+    }
+
+    // Deletes a single (key, value) pair. Returns false, if the key does not have this value.
+    pub fn delete_one(&mut self, key: impl borrow::Borrow<K>, value: u32) -> Result<bool, BTreeStoreError> {
+        let mut transaction = self.begin();
+        let deleted = transaction.delete_one(key, value)?;
+        transaction.commit()?;
+
+        Ok(deleted)
+    }
+
+    // Starts a transaction, all changes become visible to other readers of the file after the commit
+    pub fn begin(&mut self) -> Transaction<'_, K> {
+        let meta_data = self.meta_data.read().unwrap().clone();
+
+        Transaction {
+            store: self,
+            meta_data,
+            finished: false,
+        }
+    }
+
+    // Returns an iterator over all (key, value) pairs within the range, in ascending key order.
+    // Walks the leaves via their next_leaf pointers.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Result<Range<'_, K>, BTreeStoreError> {
+        let (start, end) = match self.index_mode() {
+            IndexMode::Unique => (range.start_bound().map(key::encode), range.end_bound().map(key::encode)),
+            // all entries of a key are greater than the encoded key and less than the key with the greatest value
+            IndexMode::NonUnique => (
+                match range.start_bound() {
+                    Bound::Included(key) => Bound::Included(key::encode(key)),
+                    Bound::Excluded(key) => Bound::Excluded(self.entry_key(key, u32::MAX)),
+                    Bound::Unbounded => Bound::Unbounded,
+                },
+                match range.end_bound() {
+                    Bound::Included(key) => Bound::Included(self.entry_key(key, u32::MAX)),
+                    Bound::Excluded(key) => Bound::Excluded(key::encode(key)),
+                    Bound::Unbounded => Bound::Unbounded,
+                },
+            ),
+        };
+
+        self.range_encoded(start, end)
+    }
+
+    fn range_encoded(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Result<Range<'_, K>, BTreeStoreError> {
+        let value_suffix = match self.index_mode() {
+            IndexMode::Unique => 0,
+            IndexMode::NonUnique => VALUE_SUFFIX_SIZE,
+        };
+        let mut range = Range {
+            pager: &self.pager,
+            entries: VecDeque::new(),
+            next_leaf: None,
+            end,
+            value_suffix,
+        };
+        let root = self.meta_data.read().unwrap().root;
+        if let Some(root) = root {
+            let start_key = match &start {
+                Bound::Included(key) | Bound::Excluded(key) => Some(key.as_slice()),
+                Bound::Unbounded => None,
+            };
+            self.pager.with_leaf(root, start_key, |leaf| Ok(range.read_entries(&leaf, start.as_ref().map(Vec::as_slice))?))?;
+        }
+
+        Ok(range)
+    }
+
+    // Writes the committed operations into the store file and truncates the WAL
+    pub fn checkpoint(&self) -> Result<(), BTreeStoreError> {
+        Ok(self.pager.checkpoint()?)
+    }
+
+    // Writes the committed operations into the store file without syncing it. The WAL is kept for recovery.
+    pub fn flush(&self) -> Result<(), BTreeStoreError> {
+        Ok(self.pager.flush()?)
+    }
+
+    // Checkpoint, which syncs the store file in every sync mode. Afterwards all committed operations are on the disk.
+    pub fn sync(&self) -> Result<(), BTreeStoreError> {
+        Ok(self.pager.sync()?)
+    }
+
+    pub fn sync_mode(&self) -> SyncMode {
+        self.pager.sync_mode
+    }
+
+    // True, if the checkpoint after a commit of SyncMode::Always has failed. The committed operations are in the WAL,
+    // the checkpoint is retried after the next commit, by checkpoint() and on drop.
+    pub fn checkpoint_pending(&self) -> bool {
+        self.pager.checkpoint_pending()
+    }
+
+    // The sync mode is not recorded in the store file, it applies to the commits from now on
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.pager.sync_mode = sync_mode;
+    }
+
+    pub fn cache_stats(&self) -> BufferPoolStats {
+        self.pager.cache_stats()
+    }
+
+    // Reads the pages through a memory map of the store file instead of seek and read. Mapped pages are not copied
+    // into the cache, which keeps only the pages of the running transaction and the pinned root (and the pages of commits,
+    // which have not been written back yet). Lookups and scans read the keys and values in place (see PageView),
+    // only changes parse the pages into NodePages.
+    #[cfg(feature = "mmap")]
+    pub fn set_mmap(&mut self, mmap: bool) {
+        self.pager.mapped_file = mmap.then(|| Mutex::new(MappedFile::default()));
+    }
+
+    #[cfg(feature = "mmap")]
+    pub fn mmap(&self) -> bool {
+        self.pager.mapped_file.is_some()
+    }
+
+    // Read-only view of the last commit, later commits do not change it. The slots of its pages are not reused,
+    // until the snapshot has been dropped. Only shadow paged stores write the commits to new slots.
+    // The snapshot can outlive the store, but the store file can't be opened again (except read-only) until it has been dropped.
+    pub fn snapshot(&self) -> Result<Snapshot<K>, BTreeStoreError> {
+        let Journal::Shadow(page_table) = &self.pager.journal else {
+            return Err(BTreeStoreError::Incompatible { msg: "Snapshots need a shadow paged store".to_owned() });
+        };
+        let file = OpenOptions::new().read(true).open(&self.file_path)
+            .map_err(|source| BTreeStoreError::Io { context: format!("Cannot open {}", self.file_path.display()), source })?;
+        // the meta data belongs to the pinned commit, as commits need the store exclusively
+        let meta_data = Arc::new(RwLock::new(self.meta_data()));
+        let journal = Journal::Snapshot(PinnedPageTable::new(Arc::clone(page_table)));
+        shadow::register_snapshot_table(&self.file_path, page_table);
+
+        let pager = NodePager::new(file, Arc::clone(&meta_data), journal, DEFAULT_CACHE_PAGES);
+        // the snapshot reads its pages like the store
+        #[cfg(feature = "mmap")]
+        let pager = NodePager { mapped_file: self.pager.mapped_file.as_ref().map(|_| Mutex::default()), ..pager };
+
+        let store = BTreeStore {
+            pager,
+            meta_data,
+            pinned_root: Mutex::new(None),
+            file_path: self.file_path.clone(),
+            key_type: PhantomData,
+        };
+        store.pin_root()?;
+
+        Ok(Snapshot::new(store))
+    }
+
+    // Checks the structure of the tree and the free list (see CheckReport)
+    pub fn check(&self) -> CheckReport {
+        // the pages are read with the meta data, so it must not be locked during the check
+        let meta_data = self.meta_data();
+        check_store(&self.pager, meta_data.root, meta_data.first_deleted_page, meta_data.number_of_pages)
+    }
+
+    // Pins the current root and unpins the previous one
+    fn pin_root(&self) -> Result<(), BTreeStoreError> {
+        let root = self.meta_data.read().unwrap().root;
+        let mut pinned_root = self.pinned_root.lock().unwrap();
+        if root != *pinned_root {
+            if let Some(pinned_root) = *pinned_root {
+                self.pager.unpin(pinned_root);
+            }
+            if let Some(root) = root {
+                self.pager.pin(root)?;
+            }
+            *pinned_root = root;
+        }
+
+        Ok(())
+    }
+
+    pub fn root(&mut self) -> Result<NodePage, BTreeStoreError> {
+        match self.read_root()? {
+            Some(root) => Ok(root),
+            None => {
+                let new_root = self.pager.allocate_new_page()?;
+                self.meta_data.write().unwrap().set_root(*new_root.id());
+                self.pager.commit()?;
+                self.pin_root()?;
+                self.pager.checkpoint_after_commit();
+                Ok(new_root)
+            },
+        }
+    }
+
+    // Returns None, if nothing has been inserted yet
+    fn read_root(&self) -> Result<Option<NodePage>, BTreeStoreError> {
+        let root = self.meta_data.read().unwrap().root;
+
+        match root {
+            Some(root_id) => Ok(Some(self.pager.read_page(root_id)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<K> Drop for BTreeStore<K> {
+    fn drop(&mut self) {
+        // the WAL is not needed anymore, after all operations are in the store file
+        if self.pager.checkpoint().is_ok() && let Journal::Wal(wal) = &self.pager.journal {
+            let _ = wal.remove();
+        }
+    }
+}
+
+// (key, pointer) of a bulk loaded node, see LevelBuilder
+type LevelEntry = (Vec<u8>, u32);
+
+// Builds one level of the tree from left to right (see BTreeStore::bulk_load).
+// Entries are (key, value) pairs for leaves and (smallest key of the subtree, child) pairs for internal nodes,
+// the key of the first child of an internal node moves up to the parent.
+// The last two nodes stay open, so that an underfull last node can be merged with or refilled from its left neighbour.
+struct LevelBuilder<'a> {
+    pager: &'a NodePager,
+    leaf: bool,
+    fill_factor: f64,
+    previous: Option<(NodePage, Vec<LevelEntry>)>,
+    current: Option<(NodePage, Vec<LevelEntry>)>,
+    parent_entries: Vec<LevelEntry>,
+}
+
+impl<'a> LevelBuilder<'a> {
+    fn new(pager: &'a NodePager, leaf: bool, fill_factor: f64) -> Self {
+        LevelBuilder { pager, leaf, fill_factor, previous: None, current: None, parent_entries: Vec::new() }
+    }
+
+    // Bytes of the cells, which the entries occupy in a page
+    fn used_bytes(&self, entries: &[LevelEntry]) -> usize {
+        let skip = if self.leaf { 0 } else { 1 };
+        entries.iter().skip(skip).map(|(key, _)| cell_size(key)).sum()
+    }
+
+    fn push(&mut self, key: Vec<u8>, pointer: u32) -> Result<(), BTreeStoreError> {
+        let is_full = match &self.current {
+            Some((page, entries)) => {
+                let used = self.used_bytes(entries) + cell_size(&key);
+                let target = (*page.capacity() as f64 * self.fill_factor) as usize;
+                used > *page.capacity() || (used > target && used - cell_size(&key) >= page.min_bytes())
+            },
+            None => false,
+        };
+        if is_full {
+            let next = self.pager.allocate_new_page()?;
+            let full = self.current.replace((next, Vec::new()));
+            if let Some((page, entries)) = self.previous.take() {
+                let next_leaf = full.as_ref().map(|(full_page, _)| *full_page.id());
+                self.write(page, entries, next_leaf)?;
+            }
+            self.previous = full;
+        }
+        if self.current.is_none() {
+            self.current = Some((self.pager.allocate_new_page()?, Vec::new()));
+        }
+        self.current.as_mut().unwrap().1.push((key, pointer));
+
+        Ok(())
+    }
+
+    // Writes the open nodes and returns the entries of the parent level
+    fn finish(mut self) -> Result<Vec<LevelEntry>, BTreeStoreError> {
+        match (self.previous.take(), self.current.take()) {
+            (Some((previous, mut previous_entries)), Some((current, current_entries))) => {
+                let underfull = self.used_bytes(&current_entries) < current.min_bytes() || (!self.leaf && current_entries.len() < 3);
+                if !underfull {
+                    let next_leaf = Some(*current.id());
+                    self.write(previous, previous_entries, next_leaf)?;
+                    self.write(current, current_entries, None)?;
+                } else {
+                    previous_entries.extend(current_entries);
+                    if self.used_bytes(&previous_entries) <= *previous.capacity() {
+                        self.pager.delete_page(*current.id())?;
+                        self.write(previous, previous_entries, None)?;
+                    } else {
+                        // both nodes get half of the bytes
+                        let half = self.used_bytes(&previous_entries) / 2;
+                        let mut middle = 1;
+                        while middle < previous_entries.len() - 1 && self.used_bytes(&previous_entries[..middle + 1]) <= half {
+                            middle += 1;
+                        }
+                        let current_entries = previous_entries.split_off(middle);
+                        let next_leaf = Some(*current.id());
+                        self.write(previous, previous_entries, next_leaf)?;
+                        self.write(current, current_entries, None)?;
+                    }
+                }
+            },
+            (None, Some((current, current_entries))) => self.write(current, current_entries, None)?,
+            _ => {},
+        }
+        self.pager.commit()?;
+
+        Ok(self.parent_entries)
+    }
+
+    fn write(&mut self, mut page: NodePage, entries: Vec<LevelEntry>, next_leaf: Option<u32>) -> Result<(), BTreeStoreError> {
+        self.parent_entries.push((entries[0].0.clone(), *page.id()));
+        let (keys, pointers): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+        if self.leaf {
+            *page.keys_mut() = keys;
+            *page.values_mut() = pointers;
+            page.set_next_leaf(next_leaf);
+        } else {
+            *page.keys_mut() = keys.into_iter().skip(1).collect();
+            *page.children_mut() = pointers;
+        }
+        self.pager.write_page(&page)?;
+
+        if self.pager.dirty_pages.lock().unwrap().len() >= BULK_LOAD_BATCH_PAGES {
+            self.pager.commit()?;
+        }
+
+        Ok(())
+    }
+}
+
+// A transaction buffers all changed pages in the NodePager. The commit writes them as a single WAL record,
+// so either all or none of the changes survive a crash. Dropping a transaction without commit rolls it back.
+pub struct Transaction<'a, K = u32> {
+    store: &'a mut BTreeStore<K>,
+    meta_data: StoreMetaData, // before the transaction, restored on rollback
+    finished: bool,
+}
+
+impl<K: StoreKey> Transaction<'_, K> {
+    // Sees the changes of this transaction
+    pub fn find(&self, key: impl borrow::Borrow<K>) -> Result<Option<u32>, BTreeStoreError> {
+        self.store.find(key)
+    }
+
+    // Sees the changes of this transaction
+    pub fn find_all(&self, key: impl borrow::Borrow<K>) -> Result<impl Iterator<Item = Result<u32, BTreeStoreError>> + '_, BTreeStoreError> {
+        self.store.find_all(key)
+    }
+
+    // Sees the changes of this transaction
+    pub fn range(&self, range: impl RangeBounds<K>) -> Result<Range<'_, K>, BTreeStoreError> {
+        self.store.range(range)
+    }
+
+    // Inserts or overwrites the value of the key. Returns the previous value.
+    pub fn insert(&mut self, key: impl borrow::Borrow<K>, value: u32) -> Result<Option<u32>, BTreeStoreError> {
+        self.insert_with(borrow::Borrow::borrow(&key), value, true)
+    }
+
+    // Keeps the value of an existing key. Returns true, if the key has been inserted.
+    pub fn insert_if_absent(&mut self, key: impl borrow::Borrow<K>, value: u32) -> Result<bool, BTreeStoreError> {
+        Ok(self.insert_with(borrow::Borrow::borrow(&key), value, false)?.is_none())
+    }
+
+    // Fails, if the key already exists
+    pub fn try_insert(&mut self, key: impl borrow::Borrow<K>, value: u32) -> Result<(), BTreeStoreError> {
+        match self.insert_with(borrow::Borrow::borrow(&key), value, false)? {
+            Some(_) => Err(BTreeStoreError::DuplicateKey),
+            None => Ok(()),
+        }
+    }
+
+    fn insert_with(&mut self, key: &K, value: u32, overwrite: bool) -> Result<Option<u32>, BTreeStoreError> {
+        let store = &mut *self.store;
+        let key = store.entry_key(key, value);
+        let key_size = match store.index_mode() {
+            IndexMode::Unique => key.len(),
+            IndexMode::NonUnique => key.len() - VALUE_SUFFIX_SIZE,
+        };
+        if key_size > store.max_key_size() {
+            return Err(BTreeStoreError::KeyTooLarge { key_size, max_key_size: store.max_key_size() });
+        }
+
+        let mut root = match store.read_root()? {
+            // an existing key must not change any page, not even by the split of the root below
+            Some(root) if !overwrite => match store.pager.find(*root.id(), &key)? {
+                Some(previous) => return Ok(Some(previous)),
+                None => root,
+            },
+            Some(root) => root,
+            None => {
+                let root = store.pager.allocate_new_page()?;
+                store.meta_data.write().unwrap().set_root(*root.id());
+                root
+            }
+        };
+        if root.is_full() {
+            // the old root keeps the left half
+            let (rnode, root_key) = root.split(&store.pager)?;
+            let mut new_root = store.pager.allocate_new_page()?;
+            new_root.keys_mut().push(root_key);
+            new_root.children_mut().push(*root.id());
+            new_root.children_mut().push(*rnode.id());
+
+            store.meta_data.write().unwrap().set_root(*new_root.id());
+            root = new_root;
             *root.changed().borrow_mut() = true;
         }
         
-        root.insert(&self.pager, key, value);
-        self.pager.write_page(&root)
-                .map_err(|_| BTreeStoreError { msg: "Cannot write new root (op: insert)".to_owned() })?;
+        let previous = root.insert(&store.pager, &key, value, overwrite)?;
+        store.pager.write_page(&root)?;
 
-        self.save_metadata()?;
-        Ok(())
+        Ok(previous)
+    }
+
+    // Deletes the key with all its values. Returns the (smallest) deleted value.
+    pub fn delete(&mut self, key: impl borrow::Borrow<K>) -> Result<Option<u32>, BTreeStoreError> {
+        let key = borrow::Borrow::borrow(&key);
+        match self.store.index_mode() {
+            IndexMode::Unique => self.delete_entry(&key::encode(key)),
+            IndexMode::NonUnique => {
+                let values = self.store.find_all(key)?.collect::<Result<Vec<_>, _>>()?;
+                for value in &values {
+                    let entry_key = self.store.entry_key(key, *value);
+                    self.delete_entry(&entry_key)?;
+                }
+                Ok(values.first().copied())
+            },
+        }
+    }
+
+    // Deletes a single (key, value) pair. Returns false, if the key does not have this value.
+    pub fn delete_one(&mut self, key: impl borrow::Borrow<K>, value: u32) -> Result<bool, BTreeStoreError> {
+        let key = borrow::Borrow::borrow(&key);
+        match self.store.index_mode() {
+            IndexMode::Unique => {
+                if self.store.find(key)? != Some(value) {
+                    return Ok(false);
+                }
+                Ok(self.delete_entry(&key::encode(key))?.is_some())
+            },
+            IndexMode::NonUnique => {
+                let entry_key = self.store.entry_key(key, value);
+                Ok(self.delete_entry(&entry_key)?.is_some())
+            },
+        }
     }
 
-    pub fn delete(&mut self, key: u32) -> Result<Option<u32>, BTreeStoreError> {
-        let mut root = self.root()?;
-        let res = root.delete(&self.pager, key);
+    // Deletes the entry with the encoded key (see BTreeStore::entry_key)
+    fn delete_entry(&mut self, key: &[u8]) -> Result<Option<u32>, BTreeStoreError> {
+        let store = &mut *self.store;
+        let Some(mut root) = store.read_root()? else {
+            return Ok(None);
+        };
+        let res = root.delete(&store.pager, key)?;
 
         if root.keys().is_empty() && !root.is_leaf() {
             // Special case where keys are empty and children has length 1 (after merging)
             debug_assert_eq!(root.children().len(), 1, "Internal root node must have exactly 1 child when it is out of keys");
             let new_root = root.children_mut().remove(0);
-            root = self.pager.read_page(new_root)?;
-            self.meta_data.borrow_mut().root = Some(new_root);
+            store.pager.delete_page(*root.id())?;
+            root = store.pager.read_page(new_root)?;
+            store.meta_data.write().unwrap().set_root(new_root);
         }
 
-        self.pager.write_page(&root)?;
-        self.save_metadata()?;
+        store.pager.write_page(&root)?;
 
         Ok(res)
     }
 
-    fn save_metadata(&self) -> Result<(), BTreeStoreError> {
-        let changed = self.meta_data.borrow().changed;
+    // Commits all changes atomically. If the changes cannot be made durable, the transaction is rolled back.
+    // Afterwards the transaction is committed: an error of pinning the new root is returned, but does not undo the changes.
+    // A failed checkpoint of SyncMode::Always does not fail the commit, it is retried later (see BTreeStore::checkpoint_pending).
+    pub fn commit(mut self) -> Result<(), BTreeStoreError> {
+        self.store.pager.commit()?;
+        self.finished = true;
+
+        self.store.pager.checkpoint_after_commit();
+        self.store.pin_root()
+    }
+
+    pub fn rollback(mut self) {
+        self.discard();
+    }
+}
 
-        if changed {
-            let bytes = meta_data_to_bytes(&self.meta_data.borrow());
-            let mut file = self.pager.file.borrow_mut();
+impl<K> Transaction<'_, K> {
+    fn discard(&mut self) {
+        self.store.pager.rollback();
+        *self.store.meta_data.write().unwrap() = self.meta_data.clone();
+        self.finished = true;
+    }
+}
 
-            file.seek(std::io::SeekFrom::Start(0))
-                .map_err(|_| BTreeStoreError { msg: "Cannot seek in file (saving StoreMetaData)".to_owned() })?;
-            file.write_all(&bytes)
-                .map_err(|_| BTreeStoreError { msg: "Cannot save StoreMetaData".to_owned() })?;
+impl<K> Drop for Transaction<'_, K> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.discard();
         }
-        
-        Ok(())
     }
+}
 
-    pub fn root(&self) -> Result<NodePage, BTreeStoreError> {
-        let root = self.meta_data.borrow().root;
+pub struct Range<'a, K = u32> {
+    pager: &'a NodePager,
+    entries: VecDeque<Result<(K, u32), BTreeStoreError>>, // the entries of the current leaf, which have not been returned yet
+    next_leaf: Option<u32>, // None, if the range ends in the current leaf
+    end: Bound<Vec<u8>>, // encoded key
+    value_suffix: usize, // bytes behind the key, which do not belong to it (see IndexMode::NonUnique)
+}
 
-        match root {
-            Some(root_id) => Ok(self.pager.read_page(root_id)?),
-            None => {
-                let new_root = self.pager.allocate_new_page()?;
-                self.meta_data.borrow_mut().root = Some(*new_root.id());
-                self.save_metadata()?;
-                Ok(new_root)
-            },
+impl<K: StoreKey> Range<'_, K> {
+    // Decodes the entries of the leaf from start up to the end of the range, the keys are read in place
+    fn read_entries(&mut self, leaf: &PageView, start: Bound<&[u8]>) -> Result<(), CorruptionError> {
+        let first_slot = match start {
+            Bound::Included(start) => leaf.search(start)?.unwrap_or_else(|slot| slot),
+            Bound::Excluded(start) => leaf.search(start)?.map_or_else(|slot| slot, |slot| slot + 1),
+            Bound::Unbounded => 0,
+        };
+        for slot in first_slot..leaf.cell_count {
+            let (key, value) = leaf.cell(slot)?;
+            let in_range = match &self.end {
+                Bound::Included(end) => key <= end.as_slice(),
+                Bound::Excluded(end) => key < end.as_slice(),
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                return Ok(());
+            }
+
+            self.entries.push_back(
+                key.len().checked_sub(self.value_suffix)
+                    .and_then(|key_len| key::decode(&key[..key_len]))
+                    .map(|key| (key, value))
+                    .ok_or_else(|| CorruptionError::UndecodableKey { page_id: leaf.page_id, key: key.to_vec() }.into())
+            );
         }
+        // go on with the right sibling
+        self.next_leaf = leaf.next_leaf();
 
+        Ok(())
+    }
+}
+
+impl<K: StoreKey> Iterator for Range<'_, K> {
+    type Item = Result<(K, u32), BTreeStoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.pop_front() {
+                return Some(entry);
+            }
+            let leaf = self.next_leaf.take()?;
+            let pager = self.pager;
+            if let Err(e) = pager.with_page(leaf, |leaf| Ok(self.read_entries(&leaf, Bound::Unbounded)?)) {
+                // the entries of the leaf before the error are returned first
+                self.entries.push_back(Err(e.into()));
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{collections::{BTreeMap, BTreeSet}, fs, ops::Bound, path::Path};
+
     use tempfile::NamedTempFile;
 
-    use crate::page_based_bplustree::{btree_store::BTreeStore, node::NodePage};
+    use crate::page_based_bplustree::{btree_store::{BTreeStore, BTreeStoreError, CHECKSUM_SIZE, CorruptionError, DEFAULT_CACHE_PAGES, FORMAT_VERSION, IndexMode, MAX_NUMBER_OF_PAGES, META_DATA_HEADER_SIZE, PAGE_HEADER_SIZE, POS_CHECKSUM, PageView, PagerMode, SyncMode, page_checksum, page_offset}, key::encode, node::NodePage, wal::{Wal, crash}};
+
+    fn encode_all(keys: &[u32]) -> Vec<Vec<u8>> {
+        keys.iter().map(encode).collect()
+    }
 
     #[test]
     fn delete_everything_except_one_key() {
@@ -561,12 +2041,12 @@ mod tests {
     #[test]
     fn get_root() {
         let temp = NamedTempFile::new().unwrap();
-        let btree= BTreeStore::new(temp.path(), 10).unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 10).unwrap();
         let root_res = btree.root();
         assert!(root_res.is_ok());
         let root = root_res.unwrap();
         assert_eq!(*root.id(), 0);
-        assert_eq!(*root.deleted(), false);
+        assert!(!*root.deleted());
         assert!(root.keys().is_empty());
         assert!(root.children().is_empty());
         assert!(root.values().is_empty());
@@ -579,19 +2059,21 @@ mod tests {
         let btree= BTreeStore::new(temp.path(), 10).unwrap();
         // allocate page1
         let mut page1 = btree.pager.allocate_new_page().unwrap();
-        page1.keys_mut().push(1);
-        page1.keys_mut().push(5);
+        page1.keys_mut().push(encode(&1u32));
+        page1.keys_mut().push(encode(&5u32));
+        page1.values_mut().extend([1, 5]);
         btree.pager.write_page(&page1).unwrap();
 
         // allocate page2
         let mut page2 = btree.pager.allocate_new_page().unwrap();
-        page2.keys_mut().push(7);
-        page2.keys_mut().push(10);
+        page2.keys_mut().push(encode(&7u32));
+        page2.keys_mut().push(encode(&10u32));
+        page2.values_mut().extend([7, 10]);
         btree.pager.write_page(&page2).unwrap();
 
         // delete page2
         btree.pager.delete_page(*page2.id()).unwrap();
-        btree.save_metadata().unwrap();
+        btree.pager.commit().unwrap();
         
         // Act
         // should now allocate the delete page page2 (id=1)
@@ -601,57 +2083,1210 @@ mod tests {
 
         // Assert
         assert_eq!(*allocated1.id(), 1);
-        assert_eq!(*allocated1.deleted(), false);
+        assert!(!*allocated1.deleted());
         assert_eq!(*allocated1.next_deleted_page(), None);
         assert!(allocated1.keys().is_empty());
         assert!(allocated1.values().is_empty());
 
 
         assert_eq!(*allocated2.id(), 2);
-        assert_eq!(*allocated2.deleted(), false);
+        assert!(!*allocated2.deleted());
         assert_eq!(*allocated2.next_deleted_page(), None);
     }
 
     #[test]
-    fn write_and_read_pages() {
-        let page1 = NodePage::new_from_store(
-            0, false, 
-            None, vec![1, 5, 6],
-            vec![3, 9, 10, 16], Vec::new(),
-            4
-        );
+    fn insert_overwrites_existing_keys() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+            for key in 0..50 {
+                assert_eq!(btree.insert(key, key).unwrap(), None);
+            }
+            for key in (0..50).step_by(3) {
+                assert_eq!(btree.insert(key, key + 100).unwrap(), Some(key));
+            }
+        }
 
-        *page1.changed().borrow_mut() = true;
+        let btree= BTreeStore::new(temp.path(), 4).unwrap();
+        let all = btree.range(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        let expected = (0..50).map(|key| (key, if key % 3 == 0 { key + 100 } else { key })).collect::<Vec<_>>();
+        assert_eq!(all, expected);
+    }
 
+    #[test]
+    fn insert_if_absent_and_try_insert_keep_existing_values() {
         let temp = NamedTempFile::new().unwrap();
-        let btree= BTreeStore::new(temp.path(), 10).unwrap();
-        btree.pager.write_page(&page1).unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+        for key in 0..50 {
+            btree.insert(key, key).unwrap();
+        }
 
-        let page1_loaded = btree.pager.read_page(0).unwrap();
+        assert!(!btree.insert_if_absent(7, 700).unwrap());
+        assert!(btree.insert_if_absent(70, 700).unwrap());
+        assert!(matches!(btree.try_insert(8, 800), Err(BTreeStoreError::DuplicateKey)));
+        assert!(btree.try_insert(80, 800).is_ok());
 
-        assert_eq!(*page1_loaded.id(), 0);
-        assert_eq!(*page1_loaded.deleted(), false);
-        assert_eq!(*page1_loaded.next_deleted_page(), None);
-        assert_eq!(*page1_loaded.keys(), vec![1, 5, 6]);
-        assert_eq!(*page1_loaded.children(), vec![3, 9, 10, 16]);
-        assert!(page1_loaded.values().is_empty());
+        assert_eq!(btree.find(7).unwrap(), Some(7));
+        assert_eq!(btree.find(70).unwrap(), Some(700));
+        assert_eq!(btree.find(8).unwrap(), Some(8));
+        assert_eq!(btree.find(80).unwrap(), Some(800));
+        assert_eq!(btree.range(..).unwrap().count(), 52);
+    }
 
-        // page 2:
-        let page2 = NodePage::new_from_store(
-            1, false, 
-            None, vec![7, 8],
-            Vec::new(), vec![1, 2],
-            4
-        );
-        *page2.changed().borrow_mut() = true;
+    #[test]
+    fn existing_keys_are_not_written() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+        let wal_size = || fs::metadata(Wal::path(temp.path())).map_or(0, |metadata| metadata.len());
+        for key in 0..50 {
+            btree.insert(key, key).unwrap();
+
+            // whether the root is full or not, neither the pages nor the WAL change
+            let size = wal_size();
+            for existing in 0..=key {
+                assert!(matches!(btree.try_insert(existing, 0), Err(BTreeStoreError::DuplicateKey)));
+                assert!(!btree.insert_if_absent(existing, 0).unwrap());
+            }
+            assert_eq!(wal_size(), size);
+        }
+        assert!(btree.check().is_ok());
+    }
+
+    #[test]
+    fn u32_max_is_a_regular_key_and_value() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        let keys = [u32::MAX, 0, u32::MAX - 1, 1, 7, u32::MAX / 2];
+        for key in (0..20).chain(keys) {
+            btree.insert(key, u32::MAX - key).unwrap();
+        }
+        btree.insert(3, u32::MAX).unwrap();
+        drop(btree);
+
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.find(u32::MAX).unwrap(), Some(0));
+        assert_eq!(btree.find(0).unwrap(), Some(u32::MAX));
+        assert_eq!(btree.find(3).unwrap(), Some(u32::MAX));
+        assert_eq!(btree.range(u32::MAX - 1..).unwrap().map(|entry| entry.unwrap()).collect::<Vec<_>>(), vec![(u32::MAX - 1, 1), (u32::MAX, 0)]);
+        assert_eq!(btree.range(..).unwrap().count(), 23);
+        assert!(btree.check().is_ok());
+
+        // the value is part of the key in a non-unique index
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::<u32>::open_multimap(temp.path(), 8).unwrap();
+        for value in [u32::MAX, 0, 5] {
+            btree.insert(u32::MAX, value).unwrap();
+            btree.insert(1, value).unwrap();
+        }
+        assert_eq!(btree.find_all(u32::MAX).unwrap().map(Result::unwrap).collect::<Vec<_>>(), vec![0, 5, u32::MAX]);
+        assert_eq!(btree.range((Bound::Excluded(1), Bound::Unbounded)).unwrap().count(), 3);
+        assert_eq!(btree.range(..=1).unwrap().count(), 3);
+        assert!(btree.delete_one(1, u32::MAX).unwrap());
+        assert_eq!(btree.find_all(1).unwrap().map(Result::unwrap).collect::<Vec<_>>(), vec![0, 5]);
+    }
+
+    #[test]
+    fn multimap_stores_duplicate_keys() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let mut btree= BTreeStore::<String>::open_multimap(temp.path(), 8).unwrap();
+            for (i, name) in ["bob", "alice", "bob", "carol", "bob", "alice"].iter().enumerate() {
+                assert_eq!(btree.insert(name.to_string(), 10 - i as u32).unwrap(), None);
+            }
+            // the same pair is only stored once
+            assert_eq!(btree.insert("bob".to_string(), 10).unwrap(), Some(10));
+        }
+
+        let mut btree= BTreeStore::<String>::open_multimap(temp.path(), 8).unwrap();
+        let values = |btree: &BTreeStore<String>, name: &str| btree.find_all(name.to_string()).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(values(&btree, "bob"), vec![6, 8, 10]);
+        assert_eq!(values(&btree, "alice"), vec![5, 9]);
+        assert_eq!(values(&btree, "dave"), Vec::<u32>::new());
+        assert_eq!(btree.find("bob".to_string()).unwrap(), Some(6));
+
+        let entries = btree.range("alice".to_string()..="bob".to_string()).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        let expected = [("alice", 5), ("alice", 9), ("bob", 6), ("bob", 8), ("bob", 10)];
+        assert_eq!(entries, expected.map(|(name, value)| (name.to_string(), value)));
+        let after_alice = btree.range((Bound::Excluded("alice".to_string()), Bound::Excluded("carol".to_string()))).unwrap().count();
+        assert_eq!(after_alice, 3);
+
+        assert!(btree.delete_one("bob".to_string(), 8).unwrap());
+        assert!(!btree.delete_one("bob".to_string(), 8).unwrap());
+        assert_eq!(values(&btree, "bob"), vec![6, 10]);
+
+        assert_eq!(btree.delete("alice".to_string()).unwrap(), Some(5));
+        assert_eq!(values(&btree, "alice"), Vec::<u32>::new());
+        assert_eq!(btree.range(..).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn multimap_matches_btreemap_of_sets() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut expected: BTreeMap<u32, BTreeSet<u32>> = BTreeMap::new();
+        {
+            let mut btree= BTreeStore::<u32>::open_multimap(temp.path(), 8).unwrap();
+            let mut seed: u32 = 11;
+            for _ in 0..1500 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let key = (seed >> 16) % 40;
+                let value = (seed >> 8) % 8;
+                if seed.is_multiple_of(3) {
+                    let removed = expected.get_mut(&key).is_some_and(|values| values.remove(&value));
+                    assert_eq!(btree.delete_one(key, value).unwrap(), removed);
+                } else {
+                    let existed = !expected.entry(key).or_default().insert(value);
+                    assert_eq!(btree.insert(key, value).unwrap().is_some(), existed);
+                }
+            }
+        }
+
+        let btree= BTreeStore::<u32>::open_multimap(temp.path(), 8).unwrap();
+        for (key, values) in &expected {
+            assert!(btree.find_all(key).unwrap().map(Result::unwrap).eq(values.iter().copied()));
+        }
+        let all = btree.range(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        let expected_all = expected.iter().flat_map(|(key, values)| values.iter().map(|value| (*key, *value))).collect::<Vec<_>>();
+        assert_eq!(all, expected_all);
+    }
+
+    #[test]
+    fn index_mode_is_recorded_in_the_header() {
+        let temp = NamedTempFile::new().unwrap();
+        BTreeStore::<u32>::open_multimap(temp.path(), 8).unwrap().insert(1, 1).unwrap();
+
+        assert!(BTreeStore::<u32>::open(temp.path(), 8).is_err());
+        let btree= BTreeStore::<u32>::open_multimap(temp.path(), 8).unwrap();
+        assert_eq!(btree.index_mode(), IndexMode::NonUnique);
+        // the pages are sized for keys with the appended value, which takes 4 bytes of the 24 bytes of the page
+        assert_eq!(btree.max_key_size(), 20);
+    }
+
+    #[test]
+    fn multimaps_of_the_smallest_degree() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::<u32>::open_multimap(temp.path(), 4).unwrap();
+        assert!(btree.max_key_size() >= 4);
+        for key in 0..50 {
+            for value in 0..3 {
+                btree.insert(key, value).unwrap();
+            }
+        }
+        assert!(btree.find_all(7).unwrap().map(Result::unwrap).eq(0..3));
+        assert_eq!(btree.range(..).unwrap().count(), 150);
+        assert!(btree.check().is_ok());
+    }
+
+    // Checks that all leaves are on the same level and that all nodes except the root are filled to the minimum.
+    // Returns the number of nodes.
+    fn assert_balanced<K>(btree: &BTreeStore<K>) -> usize {
+        let root = btree.meta_data.read().unwrap().root.unwrap();
+        let mut level = vec![root];
+        let mut nodes = 0;
+        loop {
+            let pages = level.iter().map(|page_id| btree.pager.read_page(*page_id).unwrap()).collect::<Vec<_>>();
+            nodes += pages.len();
+            for page in pages.iter().filter(|page| *page.id() != root) {
+                assert!(!page.is_less_than_minimal(), "Node {} is underfull", page.id());
+            }
+            if pages[0].is_leaf() {
+                assert!(pages.iter().all(|page| page.is_leaf()), "All leaves must be on the same level");
+                return nodes;
+            }
+            level = pages.iter().flat_map(|page| page.children().clone()).collect();
+        }
+    }
+
+    #[test]
+    fn bulk_load_builds_a_balanced_tree() {
+        let temp = NamedTempFile::new().unwrap();
+        let bulk_loaded_nodes = {
+            let btree= BTreeStore::bulk_load(temp.path(), 8, (0..5000).map(|key| (key * 2, key))).unwrap();
+            assert!(btree.range(..).unwrap().map(Result::unwrap).eq((0..5000).map(|key| (key * 2, key))));
+            assert_eq!(btree.find(4000).unwrap(), Some(2000));
+            assert_eq!(btree.find(4001).unwrap(), None);
+            assert_balanced(&btree)
+        };
+
+        // inserting one key at a time leaves the pages half full
+        let inserted = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::new(inserted.path(), 8).unwrap();
+        for key in 0..5000 {
+            btree.insert(key * 2, key).unwrap();
+        }
+        assert!(bulk_loaded_nodes < assert_balanced(&btree));
+
+        // the loaded tree supports all operations after reopening
+        let mut btree= BTreeStore::new(temp.path(), 8).unwrap();
+        let mut expected = (0..5000).map(|key| (key * 2, key)).collect::<BTreeMap<_, _>>();
+        for key in (0..10000).step_by(7) {
+            if key % 2 == 0 {
+                assert_eq!(btree.delete(key).unwrap(), expected.remove(&key));
+            } else {
+                assert_eq!(btree.insert(key, key).unwrap(), expected.insert(key, key));
+            }
+        }
+        assert_balanced(&btree);
+        assert!(btree.range(..).unwrap().map(Result::unwrap).eq(expected.into_iter()));
+    }
+
+    #[test]
+    fn bulk_load_with_fill_factor() {
+        let full = NamedTempFile::new().unwrap();
+        let full = BTreeStore::bulk_load_with_fill_factor(full.path(), 8, 1.0, (0..1000).map(|key| (key, key))).unwrap();
+        let half = NamedTempFile::new().unwrap();
+        let half = BTreeStore::bulk_load_with_fill_factor(half.path(), 8, 0.5, (0..1000).map(|key| (key, key))).unwrap();
+        assert!(assert_balanced(&full) < assert_balanced(&half));
+        assert_eq!(half.range(..).unwrap().count(), 1000);
+
+        let temp = NamedTempFile::new().unwrap();
+        assert!(BTreeStore::bulk_load_with_fill_factor(temp.path(), 8, 0.3, (0..10).map(|key| (key, key))).is_err());
+
+        let btree= BTreeStore::bulk_load(temp.path(), 8, std::iter::empty::<(u32, u32)>()).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn bulk_load_rejects_unsorted_input() {
+        let temp = NamedTempFile::new().unwrap();
+        assert!(BTreeStore::<u32>::bulk_load(temp.path(), 8, [(1, 1), (3, 3), (2, 2)]).is_err());
+        assert!(BTreeStore::<u32>::bulk_load(temp.path(), 8, [(1, 1), (1, 2)]).is_err());
+        // the error occurs after batches of pages have been committed
+        assert!(BTreeStore::bulk_load(temp.path(), 8, (1..5000).map(|key| (key, key)).chain([(0, 0)])).is_err());
+
+        let mut btree= BTreeStore::new(temp.path(), 8).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 0);
+        assert_eq!(btree.meta_data.read().unwrap().number_of_pages, 0);
+        btree.insert(1, 1).unwrap();
+        assert_eq!(btree.find(1).unwrap(), Some(1));
+        drop(btree);
+
+        // a store with entries cannot be loaded
+        assert!(BTreeStore::bulk_load(temp.path(), 8, [(2, 2)]).is_err());
+    }
+
+    // Pretends, that the store has number_of_pages pages. The missing pages are not written.
+    fn set_number_of_pages(path: &Path, number_of_pages: u32) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[20..24].copy_from_slice(&number_of_pages.to_be_bytes());
+        let checksum = crc32fast::hash(&bytes[..META_DATA_HEADER_SIZE - CHECKSUM_SIZE]);
+        bytes[META_DATA_HEADER_SIZE - CHECKSUM_SIZE..META_DATA_HEADER_SIZE].copy_from_slice(&checksum.to_be_bytes());
+        fs::write(path, &bytes).unwrap();
+    }
+
+    #[test]
+    fn page_offsets_are_64_bit() {
+        assert_eq!(page_offset(73, 0), META_DATA_HEADER_SIZE as u64);
+        assert_eq!(page_offset(73, 2), META_DATA_HEADER_SIZE as u64 + 146);
+        assert_eq!(page_offset(73, MAX_NUMBER_OF_PAGES), META_DATA_HEADER_SIZE as u64 + 73 * (u32::MAX as u64 - 1));
+        assert_eq!(page_offset(u16::MAX as u32, MAX_NUMBER_OF_PAGES), META_DATA_HEADER_SIZE as u64 + u16::MAX as u64 * (u32::MAX as u64 - 1));
+    }
+
+    #[test]
+    fn pages_beyond_4_gib() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        for key in 0..10 {
+            btree.insert(key, key).unwrap();
+        }
+        let page_size = btree.page_size();
+        drop(btree);
+
+        // the next pages are written just behind 4 GiB of the (sparse) file
+        set_number_of_pages(temp.path(), ((1u64 << 32) / page_size as u64 + 1) as u32);
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        for key in 10..50 {
+            btree.insert(key, key).unwrap();
+        }
+        drop(btree);
+
+        assert!(fs::metadata(temp.path()).unwrap().len() > 1 << 32);
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.range(..).unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>(), (0..50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn page_ids_run_out() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        for key in 0..10 {
+            btree.insert(key, key).unwrap();
+        }
+        drop(btree);
+
+        // no new page can be allocated, so nothing is written at the end of the address space
+        set_number_of_pages(temp.path(), MAX_NUMBER_OF_PAGES);
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        let mut key = 10;
+        let err = loop {
+            match btree.insert(key, key) {
+                Ok(_) => key += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, BTreeStoreError::TooManyPages { number_of_pages: MAX_NUMBER_OF_PAGES }), "{}", err);
+        // the failed insert has been rolled back
+        assert_eq!(btree.find(key).unwrap(), None);
+        assert_eq!(btree.find(key - 1).unwrap(), Some(key - 1));
+        drop(btree);
+
+        assert!(fs::metadata(temp.path()).unwrap().len() < 1 << 20);
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.range(..).unwrap().map(|entry| entry.unwrap().0).collect::<Vec<_>>(), (0..key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn deleted_pages_are_reused_after_reopen() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let btree= BTreeStore::new(temp.path(), 10).unwrap();
+            for _ in 0..6 {
+                let page = btree.pager.allocate_new_page().unwrap();
+                btree.pager.write_page(&page).unwrap();
+            }
+            for page_id in [1, 4, 2] {
+                btree.pager.delete_page(page_id).unwrap();
+            }
+            assert!(btree.pager.delete_page(4).is_err());
+            btree.pager.commit().unwrap();
+        }
+
+        let btree= BTreeStore::new(temp.path(), 10).unwrap();
+        let page = btree.pager.read_page(2).unwrap();
+        assert!(*page.deleted());
+        assert_eq!(*page.next_deleted_page(), Some(4));
+
+        // the free list is a stack: the last deleted page is reused first
+        let reused = (0..3).map(|_| *btree.pager.allocate_new_page().unwrap().id()).collect::<Vec<_>>();
+        assert_eq!(reused, vec![2, 4, 1]);
+        assert_eq!(*btree.pager.allocate_new_page().unwrap().id(), 6);
+    }
+
+    #[test]
+    fn pages_of_a_shrinking_tree_are_reused() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+            for key in 0..100 {
+                btree.insert(key, key).unwrap();
+            }
+            for key in 0..100 {
+                btree.delete(key).unwrap();
+            }
+        }
+
+        let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+        let number_of_pages = btree.meta_data.read().unwrap().number_of_pages;
+        for key in 0..100 {
+            btree.insert(key, key).unwrap();
+        }
+        // the tree needs as many pages as before, all of them are taken from the free list
+        assert_eq!(btree.meta_data.read().unwrap().number_of_pages, number_of_pages);
+        assert_eq!(btree.meta_data.read().unwrap().first_deleted_page, None);
+        for key in 0..100 {
+            assert_eq!(btree.find(key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn write_and_read_pages() {
+        let page1 = NodePage::new_from_store(
+            0, false, 
+            None, encode_all(&[1, 5, 6]),
+            vec![3, 9, 10, 16], Vec::new(),
+            120, None
+        );
+
+        *page1.changed().borrow_mut() = true;
+
+        let temp = NamedTempFile::new().unwrap();
+        let btree= BTreeStore::new(temp.path(), 10).unwrap();
+        btree.pager.write_page(&page1).unwrap();
+
+        let page1_loaded = btree.pager.read_page(0).unwrap();
+
+        assert_eq!(*page1_loaded.id(), 0);
+        assert!(!*page1_loaded.deleted());
+        assert_eq!(*page1_loaded.next_deleted_page(), None);
+        assert_eq!(*page1_loaded.keys(), encode_all(&[1, 5, 6]));
+        assert_eq!(*page1_loaded.children(), vec![3, 9, 10, 16]);
+        assert!(page1_loaded.values().is_empty());
+
+        // page 2:
+        let page2 = NodePage::new_from_store(
+            1, false, 
+            None, encode_all(&[7, 8]),
+            Vec::new(), vec![1, 2],
+            120, Some(0)
+        );
+        *page2.changed().borrow_mut() = true;
         btree.pager.write_page(&page2).unwrap();
         let page2_loaded = btree.pager.read_page(1).unwrap();
 
         assert_eq!(*page2_loaded.id(), 1);
-        assert_eq!(*page2_loaded.deleted(), false);
+        assert!(!*page2_loaded.deleted());
         assert_eq!(*page2_loaded.next_deleted_page(), None);
-        assert_eq!(*page2_loaded.keys(), vec![7, 8]);
+        assert_eq!(*page2_loaded.keys(), encode_all(&[7, 8]));
         assert_eq!(*page2_loaded.values(), vec![1, 2]);
+        assert_eq!(*page2_loaded.next_leaf(), Some(0));
+    }
+
+    #[test]
+    fn range_over_leaf_chain() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+        // insert in a scrambled order to split leaves in the middle of the chain
+        for i in 0..100 {
+            let key = (i * 37) % 100;
+            btree.insert(key, key + 1000).unwrap();
+        }
+
+        let all = btree.range(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(all, (0..100).map(|k| (k, k + 1000)).collect::<Vec<_>>());
+
+        let keys = btree.range(10..20).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, (10..20).collect::<Vec<_>>());
+
+        let keys = btree.range(90..=150).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, (90..100).collect::<Vec<_>>());
+
+        let keys = btree.range((Bound::Excluded(49), Bound::Included(52))).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, vec![50, 51, 52]);
+
+        assert_eq!(btree.range(200..).unwrap().count(), 0);
+        assert_eq!(btree.range(5..5).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn range_after_delete_and_reopen() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+            for key in 0..60 {
+                btree.insert(key, key).unwrap();
+            }
+            // merges must unlink the removed leaves from the chain
+            for key in (0..60).filter(|k| k % 3 != 0) {
+                assert_eq!(btree.delete(key).unwrap(), Some(key));
+            }
+        }
+
+        // leaf chain is persisted with the pages
+        let btree= BTreeStore::new(temp.path(), 4).unwrap();
+        let keys = btree.range(..).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, (0..60).filter(|k| k % 3 == 0).collect::<Vec<_>>());
+
+        let keys = btree.range(..=10).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, vec![0, 3, 6, 9]);
+    }
+
+    #[test]
+    fn range_matches_btreemap_after_mixed_operations() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 5).unwrap();
+        let mut expected = BTreeMap::new();
+
+        let mut seed: u32 = 7;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let key = (seed >> 16) % 300;
+            if seed.is_multiple_of(3) {
+                assert_eq!(btree.delete(key).unwrap(), expected.remove(&key));
+            } else if let std::collections::btree_map::Entry::Vacant(e) = expected.entry(key) {
+                e.insert(seed);
+                btree.insert(key, seed).unwrap();
+            }
+        }
+
+        let all = btree.range(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(all, expected.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+
+        let part = btree.range(100..200).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(part, expected.range(100..200).map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn range_on_empty_store() {
+        let temp = NamedTempFile::new().unwrap();
+        let btree= BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn write_and_read_variable_length_keys() {
+        let keys = vec![b"a".to_vec(), b"abc\0def".to_vec(), Vec::new(), vec![0xFF; 20]];
+        let page = NodePage::new_from_store(
+            0, false,
+            None, keys.clone(),
+            Vec::new(), vec![1, 2, 3, u32::MAX - 1],
+            120, None
+        );
+        *page.changed().borrow_mut() = true;
+
+        let temp = NamedTempFile::new().unwrap();
+        let btree= BTreeStore::new(temp.path(), 10).unwrap();
+        btree.pager.write_page(&page).unwrap();
+
+        let loaded = btree.pager.read_page(0).unwrap();
+        assert_eq!(*loaded.keys(), keys);
+        assert_eq!(*loaded.values(), vec![1, 2, 3, u32::MAX - 1]);
+        assert!(loaded.children().is_empty());
+    }
+
+    #[test]
+    fn page_views_search_the_slots() {
+        let key = |key: u32| encode(&key);
+        let keys = encode_all(&[10, 20, 30, 40]);
+        let leaf = NodePage::new_from_store(0, false, None, keys.clone(), Vec::new(), vec![1, 2, 3, 4], 120, Some(7));
+        let internal = NodePage::new_from_store(1, false, None, keys, vec![100, 101, 102, 103, 104], Vec::new(), 120, None);
+        let temp = NamedTempFile::new().unwrap();
+        let btree = BTreeStore::new(temp.path(), 10).unwrap();
+        for page in [&leaf, &internal] {
+            *page.changed().borrow_mut() = true;
+            btree.pager.write_page(page).unwrap();
+        }
+        let pages = btree.pager.dirty_pages.lock().unwrap();
+
+        let leaf = PageView::new(&pages[&0]).unwrap();
+        assert!(leaf.is_leaf());
+        assert_eq!(leaf.next_leaf(), Some(7));
+        assert_eq!(leaf.search(&key(30)).unwrap(), Ok(2));
+        assert_eq!(leaf.search(&key(5)).unwrap(), Err(0));
+        assert_eq!(leaf.search(&key(35)).unwrap(), Err(3));
+        assert_eq!(leaf.search(&key(50)).unwrap(), Err(4));
+        assert_eq!(leaf.cell(3).unwrap(), (key(40).as_slice(), 4));
+
+        // keys equal to a separator belong to the right child
+        let internal = PageView::new(&pages[&1]).unwrap();
+        assert!(!internal.is_leaf());
+        assert_eq!(internal.child(None).unwrap(), 100);
+        assert_eq!(internal.child(Some(&key(5))).unwrap(), 100);
+        assert_eq!(internal.child(Some(&key(10))).unwrap(), 101);
+        assert_eq!(internal.child(Some(&key(35))).unwrap(), 103);
+        assert_eq!(internal.child(Some(&key(99))).unwrap(), 104);
+
+        // a cell offset behind the end of the page
+        let mut broken = pages[&0].clone();
+        broken[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 2].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(PageView::new(&broken).unwrap().search(&key(10)), Err(CorruptionError::MalformedPage { page_id: 0 }));
+    }
+
+    #[test]
+    fn string_keys() {
+        let temp = NamedTempFile::new().unwrap();
+        let names = ["mia", "noah", "emma", "liam", "olivia", "sophia", "jackson", "aiden", "lucas", "ava", "", "zoe"];
+        {
+            let mut btree= BTreeStore::<String>::open(temp.path(), 8).unwrap();
+            for (i, name) in names.iter().enumerate() {
+                btree.insert(name.to_string(), i as u32).unwrap();
+            }
+            assert_eq!(btree.find("emma".to_string()).unwrap(), Some(2));
+            assert_eq!(btree.delete("liam".to_string()).unwrap(), Some(3));
+        }
+
+        let btree= BTreeStore::<String>::open(temp.path(), 8).unwrap();
+        let mut expected = names.iter().enumerate()
+            .filter(|(_, name)| **name != "liam")
+            .map(|(i, name)| (name.to_string(), i as u32))
+            .collect::<Vec<_>>();
+        expected.sort();
+        let all = btree.range(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(all, expected);
+
+        let keys = btree.range("jackson".to_string().."noah".to_string()).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["jackson", "lucas", "mia"]);
+        assert_eq!(btree.find("liam".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn composite_keys() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::<(String, i64)>::open(temp.path(), 10).unwrap();
+        for day in -20..20i64 {
+            btree.insert(("temperature".to_string(), day), (day + 100) as u32).unwrap();
+            btree.insert(("humidity".to_string(), day), (day + 200) as u32).unwrap();
+        }
+
+        assert_eq!(btree.find(("humidity".to_string(), -3)).unwrap(), Some(197));
+
+        // all humidity values between day -2 and day 2
+        let values = btree.range(("humidity".to_string(), -2)..=("humidity".to_string(), 2)).unwrap()
+            .map(|e| e.unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![198, 199, 200, 201, 202]);
+    }
+
+    #[test]
+    fn wide_keys_fit_into_the_smallest_degree() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::<u64>::open(temp.path(), 4).unwrap();
+        assert!(btree.max_key_size() >= 8);
+        for key in 0..100u64 {
+            btree.insert(key << 40, key as u32).unwrap();
+        }
+        assert_eq!(btree.find(7u64 << 40).unwrap(), Some(7));
+        assert_eq!(btree.range(..).unwrap().count(), 100);
+        assert!(btree.check().is_ok());
+
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::<(u64, u64)>::open(temp.path(), 5).unwrap();
+        for key in 0..100u64 {
+            btree.insert((key, u64::MAX - key), key as u32).unwrap();
+        }
+        assert_eq!(btree.range(..).unwrap().count(), 100);
+        assert!(btree.check().is_ok());
+    }
+
+    #[test]
+    fn long_keys_split_earlier_than_short_keys() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::<Vec<u8>>::open(temp.path(), 20).unwrap();
+        // 10 short keys fit into the root leaf
+        for i in 0..10u8 {
+            btree.insert(vec![i], i as u32).unwrap();
+        }
+        assert!(btree.root().unwrap().is_leaf());
+
+        // a few long keys fill the page
+        for i in 0..4u8 {
+            btree.insert(vec![100 + i; 40], i as u32).unwrap();
+        }
+        let root = btree.root().unwrap();
+        assert!(!root.is_leaf());
+        assert!(root.keys().len() < 4);
+
+        let keys = btree.range(..).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys.len(), 14);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn reject_keys_exceeding_the_page() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::<Vec<u8>>::open(temp.path(), 4).unwrap();
+        assert_eq!(btree.max_key_size(), 4);
+        // encoded byte strings need two additional bytes for the terminator
+        assert!(btree.insert(vec![1, 2], 1).is_ok());
+        assert!(matches!(btree.insert(vec![1, 2, 3], 1), Err(BTreeStoreError::KeyTooLarge { key_size: 5, max_key_size: 4 })));
+    }
+
+    #[test]
+    fn errors_keep_their_cause() {
+        let temp = NamedTempFile::new().unwrap();
+        let missing = temp.path().with_extension("missing");
+        match BTreeStore::new(&missing, 4) {
+            Err(err @ BTreeStoreError::Io { .. }) => {
+                let source = std::error::Error::source(&err).unwrap().downcast_ref::<std::io::Error>().unwrap();
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            },
+            other => panic!("Expected an I/O error, got {:?}", other.err()),
+        }
+
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        btree.insert(1, 1).unwrap();
+        drop(btree);
+
+        // a cell offset behind the end of the page, the checksum is valid
+        let mut bytes = fs::read(temp.path()).unwrap();
+        let page = &mut bytes[META_DATA_HEADER_SIZE..];
+        page[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 2].copy_from_slice(&u16::MAX.to_be_bytes());
+        let checksum = page_checksum(&page[..73]);
+        page[POS_CHECKSUM..POS_CHECKSUM + 4].copy_from_slice(&checksum.to_be_bytes());
+        fs::write(temp.path(), &bytes).unwrap();
+
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert!(matches!(btree.find(1), Err(BTreeStoreError::Corrupt(CorruptionError::MalformedPage { page_id: 0 }))));
+        assert!(matches!(btree.range(..).map(|range| range.count()), Err(BTreeStoreError::Corrupt(_))));
+    }
+
+    #[test]
+    fn mixed_key_sizes_match_btreemap() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::<Vec<u8>>::open(temp.path(), 16).unwrap();
+        let mut expected = BTreeMap::new();
+
+        let mut seed: u32 = 11;
+        for _ in 0..3000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            // zero bytes are escaped, so the encoded key has up to 32 bytes
+            let length = ((seed >> 8) % 16) as usize;
+            let key = vec![((seed >> 16) % 7) as u8; length];
+            if seed.is_multiple_of(3) {
+                assert_eq!(btree.delete(&key).unwrap(), expected.remove(&key));
+            } else if let std::collections::btree_map::Entry::Vacant(e) = expected.entry(key.clone()) {
+                e.insert(seed);
+                btree.insert(key, seed).unwrap();
+            }
+        }
+
+        let all = btree.range(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(all, expected.into_iter().collect::<Vec<_>>());
+    }
+
+    // Operations of the crash tests: (key, Some(value)) inserts, (key, None) deletes
+    fn crash_test_operations() -> Vec<(u32, Option<u32>)> {
+        let mut seed: u32 = 3;
+        (0..40).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let key = (seed >> 16) % 60;
+            (key, (!seed.is_multiple_of(3)).then_some(seed))
+        }).collect()
+    }
+
+    #[test]
+    fn recover_from_crash_at_arbitrary_write() {
+        recover_from_crash_at_arbitrary_write_with(PagerMode::Wal, DEFAULT_CACHE_PAGES);
+    }
+
+    #[test]
+    fn recover_from_crash_while_writing_back_evicted_pages() {
+        recover_from_crash_at_arbitrary_write_with(PagerMode::Wal, 2);
+    }
+
+    #[test]
+    fn recover_from_crash_with_shadow_paging() {
+        recover_from_crash_at_arbitrary_write_with(PagerMode::Shadow, 2);
+    }
+
+    fn recover_from_crash_at_arbitrary_write_with(pager_mode: PagerMode, cache_pages: usize) {
+        // committed state before the crash
+        let base = NamedTempFile::new().unwrap();
+        let mut base_state = BTreeMap::new();
+        {
+            let mut btree= BTreeStore::open_with_pager_mode(base.path(), 4, IndexMode::Unique, pager_mode, DEFAULT_CACHE_PAGES).unwrap();
+            for key in 0..30 {
+                btree.insert(key * 2, key).unwrap();
+                base_state.insert(key * 2, key);
+            }
+        }
+
+        let mut budget = 0;
+        loop {
+            let temp = NamedTempFile::new().unwrap();
+            fs::copy(base.path(), temp.path()).unwrap();
+
+            let mut expected = base_state.clone();
+            let mut before_crash = expected.clone();
+            let mut crashed = false;
+            {
+                let mut btree= BTreeStore::<u32>::open_with_cache(temp.path(), 4, cache_pages).unwrap();
+                crash::set_write_budget(Some(budget));
+                for (key, value) in crash_test_operations() {
+                    before_crash = expected.clone();
+                    let result = match value {
+                        Some(value) => {
+                            expected.insert(key, value);
+                            btree.insert(key, value).map(|_| ())
+                        },
+                        None => {
+                            expected.remove(&key);
+                            btree.delete(key).map(|_| ())
+                        }
+                    };
+                    if result.is_err() {
+                        crashed = true;
+                        break;
+                    }
+                }
+                // the checkpoint might crash as well
+                crashed |= btree.checkpoint().is_err();
+            }
+            crash::set_write_budget(None);
+
+            // reopening replays the WAL or uses the last complete header, the interrupted operation is either complete or missing
+            let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+            let all = btree.range(..).unwrap().collect::<Result<BTreeMap<_, _>, _>>().unwrap();
+            assert!(all == expected || all == before_crash, "Inconsistent store after crash at byte {}", budget);
+            for (key, value) in &all {
+                assert_eq!(btree.find(*key).unwrap(), Some(*value));
+            }
+
+            // the recovered store is still usable
+            btree.insert(1000, 1).unwrap();
+            assert_eq!(btree.find(1000).unwrap(), Some(1));
+
+            if !crashed {
+                break;
+            }
+            // an odd step hits different positions within the pages and records
+            budget += 61;
+        }
+    }
+
+    #[test]
+    fn shadow_paging_keeps_the_previous_commit() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::open_shadow_paged(temp.path(), 4).unwrap();
+        for key in 0..50 {
+            btree.insert(key, key).unwrap();
+        }
+        // the slots of replaced pages are reused, the file does not grow
+        for value in 0..10 {
+            btree.insert(7, value).unwrap();
+        }
+        let size = fs::metadata(temp.path()).unwrap().len();
+        for value in 10..200 {
+            btree.insert(7, value).unwrap();
+        }
+        assert_eq!(fs::metadata(temp.path()).unwrap().len(), size);
+        assert!(!Wal::path(temp.path()).exists());
+        drop(btree);
+
+        assert!(matches!(BTreeStore::<u32>::open_with_pager_mode(temp.path(), 4, IndexMode::Unique, PagerMode::Wal, 8), Err(BTreeStoreError::Incompatible { .. })));
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(*btree.meta_data().pager_mode(), PagerMode::Shadow);
+        assert_eq!(btree.find(7).unwrap(), Some(199));
+        assert!(btree.check().is_ok());
+        drop(btree);
+
+        // a torn write of the newer header leaves the previous commit
+        let mut bytes = fs::read(temp.path()).unwrap();
+        let sequence = |offset: usize| u64::from_be_bytes(bytes[offset + 34..offset + 42].try_into().unwrap());
+        let newer = if sequence(0) > sequence(META_DATA_HEADER_SIZE) { 0 } else { META_DATA_HEADER_SIZE };
+        bytes[newer + 28] ^= 0x01;
+        fs::write(temp.path(), &bytes).unwrap();
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.find(7).unwrap(), Some(198));
+        assert_eq!(btree.range(..).unwrap().count(), 50);
+        btree.insert(100, 100).unwrap();
+        assert!(btree.check().is_ok());
+    }
+
+    #[test]
+    fn replay_committed_operations_missing_in_store_file() {
+        let temp = NamedTempFile::new().unwrap();
+        let wal_path = Wal::path(temp.path());
+        {
+            let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+            for key in 0..20 {
+                btree.insert(key, key + 100).unwrap();
+            }
+            btree.checkpoint().unwrap();
+            assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+            let checkpointed = fs::read(temp.path()).unwrap();
+
+            for key in 20..40 {
+                btree.insert(key, key + 100).unwrap();
+            }
+            for key in 0..10 {
+                btree.delete(key).unwrap();
+            }
+
+            // lose all writes to the store file since the checkpoint, only the WAL survives the crash
+            let wal = fs::read(&wal_path).unwrap();
+            drop(btree);
+            fs::write(temp.path(), checkpointed).unwrap();
+            fs::write(&wal_path, wal).unwrap();
+        }
+
+        let btree= BTreeStore::new(temp.path(), 4).unwrap();
+        let keys = btree.range(..).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, (10..40).collect::<Vec<_>>());
+        assert_eq!(btree.find(25).unwrap(), Some(125));
+    }
+
+    #[test]
+    fn read_only_stores_apply_the_wal_in_memory() {
+        let temp = NamedTempFile::new().unwrap();
+        let wal_path = Wal::path(temp.path());
+        {
+            let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+            for key in 0..20 {
+                btree.insert(key, key + 100).unwrap();
+            }
+            btree.checkpoint().unwrap();
+            let checkpointed = fs::read(temp.path()).unwrap();
+            for key in 20..40 {
+                btree.insert(key, key + 100).unwrap();
+            }
+            btree.delete(5).unwrap();
+
+            // only the WAL has the last operations
+            let wal = fs::read(&wal_path).unwrap();
+            drop(btree);
+            fs::write(temp.path(), checkpointed).unwrap();
+            fs::write(&wal_path, wal).unwrap();
+        }
+        let store_file = fs::read(temp.path()).unwrap();
+        let wal = fs::read(&wal_path).unwrap();
+
+        let mut btree = BTreeStore::<u32>::open_read_only(temp.path()).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 39);
+        assert_eq!(btree.find(5).unwrap(), None);
+        assert_eq!(btree.find(25).unwrap(), Some(125));
+        assert!(btree.check().is_ok());
+        assert!(matches!(btree.insert(50, 50), Err(BTreeStoreError::ReadOnly)));
+        assert!(matches!(btree.delete(1), Err(BTreeStoreError::ReadOnly)));
+        assert_eq!(btree.find(1).unwrap(), Some(101));
+        drop(btree);
+
+        // neither the store file nor the WAL have been written
+        assert_eq!(fs::read(temp.path()).unwrap(), store_file);
+        assert_eq!(fs::read(&wal_path).unwrap(), wal);
+        assert_eq!(BTreeStore::new(temp.path(), 4).unwrap().range(..).unwrap().count(), 39);
+
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::open_shadow_paged(temp.path(), 4).unwrap();
+        for key in 0..20 {
+            btree.insert(key, key).unwrap();
+        }
+        drop(btree);
+        let mut btree = BTreeStore::<u32>::open_read_only(temp.path()).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 20);
+        assert!(matches!(btree.insert(50, 50), Err(BTreeStoreError::ReadOnly)));
+        assert!(btree.check().is_ok());
+        assert!(BTreeStore::<u64>::open_read_only(temp.path()).is_err());
+    }
+
+    #[test]
+    fn transaction_reads_its_own_writes_and_commits() {
+        let temp = NamedTempFile::new().unwrap();
+        {
+            let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+            let mut transaction = btree.begin();
+            for key in 0..50 {
+                transaction.insert(key, key + 1).unwrap();
+            }
+            assert_eq!(transaction.delete(7).unwrap(), Some(8));
+            assert_eq!(transaction.find(42).unwrap(), Some(43));
+            assert_eq!(transaction.find(7).unwrap(), None);
+            assert_eq!(transaction.range(5..10).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>(), vec![5, 6, 8, 9]);
+            transaction.commit().unwrap();
+
+            assert_eq!(btree.find(42).unwrap(), Some(43));
+        }
+
+        let btree= BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 49);
+        assert_eq!(btree.find(49).unwrap(), Some(50));
+    }
+
+    #[test]
+    fn rollback_discards_pages_and_meta_data() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+        for key in 0..20 {
+            btree.insert(key, key).unwrap();
+        }
+        let number_of_pages = btree.meta_data.read().unwrap().number_of_pages;
+        let root = btree.meta_data.read().unwrap().root;
+
+        let mut transaction = btree.begin();
+        // splits and merges change the root and allocate and delete pages
+        for key in 20..60 {
+            transaction.insert(key, key).unwrap();
+        }
+        for key in 0..15 {
+            transaction.delete(key).unwrap();
+        }
+        assert_eq!(transaction.find(55).unwrap(), Some(55));
+        transaction.rollback();
+
+        assert_eq!(btree.meta_data.read().unwrap().number_of_pages, number_of_pages);
+        assert_eq!(btree.meta_data.read().unwrap().root, root);
+        assert_eq!(btree.range(..).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>(), (0..20).collect::<Vec<_>>());
+
+        // dropping a transaction rolls it back as well
+        {
+            let mut transaction = btree.begin();
+            transaction.insert(100, 100).unwrap();
+        }
+        assert_eq!(btree.find(100).unwrap(), None);
+
+        // the store is still usable after the rollback
+        btree.insert(100, 1).unwrap();
+        drop(btree);
+        let btree= BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.range(..).unwrap().map(|e| e.unwrap().0).collect::<Vec<_>>(), (0..20).chain([100]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn crash_during_commit_keeps_transaction_atomic() {
+        let mut budget = 0;
+        loop {
+            let temp = NamedTempFile::new().unwrap();
+            let mut crashed = false;
+            {
+                let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+                btree.insert(0, 0).unwrap();
+
+                let mut transaction = btree.begin();
+                for key in 1..30 {
+                    transaction.insert(key, key).unwrap();
+                }
+                crash::set_write_budget(Some(budget));
+                crashed |= transaction.commit().is_err();
+                crashed |= btree.checkpoint().is_err();
+            }
+            crash::set_write_budget(None);
+
+            let btree= BTreeStore::new(temp.path(), 4).unwrap();
+            let count = btree.range(..).unwrap().count();
+            assert!(count == 1 || count == 30, "Transaction is only partially visible after crash at byte {}: {} keys", budget, count);
+
+            if !crashed {
+                break;
+            }
+            budget += 113;
+        }
+    }
+
+    #[test]
+    fn sync_modes_decide_when_the_store_file_is_written() {
+        let temp = NamedTempFile::new().unwrap();
+        let wal_path = Wal::path(temp.path());
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.sync_mode(), SyncMode::OnCommit);
+        for key in 0..20 {
+            btree.insert(key, key).unwrap();
+        }
+        // the commits are only in the WAL
+        assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+        let header = fs::read(temp.path()).unwrap()[..META_DATA_HEADER_SIZE].to_vec();
+
+        // flush writes the store file, but keeps the WAL
+        btree.flush().unwrap();
+        assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+        let copy = NamedTempFile::new().unwrap();
+        fs::copy(temp.path(), copy.path()).unwrap();
+        assert_ne!(fs::read(copy.path()).unwrap()[..META_DATA_HEADER_SIZE], header);
+        assert_eq!(BTreeStore::new(copy.path(), 4).unwrap().range(..).unwrap().count(), 20);
+
+        btree.sync().unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+
+        // every commit reaches the store file
+        btree.set_sync_mode(SyncMode::Always);
+        btree.insert(20, 20).unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+        fs::copy(temp.path(), copy.path()).unwrap();
+        assert_eq!(BTreeStore::new(copy.path(), 4).unwrap().find(20).unwrap(), Some(20));
+
+        btree.set_sync_mode(SyncMode::Never);
+        for key in 21..40 {
+            btree.insert(key, key).unwrap();
+        }
+        drop(btree);
+        // drop writes the pending pages and meta data
+        assert!(!wal_path.exists());
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.range(..).unwrap().count(), 40);
+        assert!(btree.check().is_ok());
+    }
+
+    #[test]
+    fn failed_checkpoints_after_the_commit_are_retried() {
+        // the size of the WAL record of the first insert
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        btree.insert(1, 1).unwrap();
+        let record_size = fs::metadata(Wal::path(temp.path())).unwrap().len() as usize;
+        drop(btree);
+
+        let temp = NamedTempFile::new().unwrap();
+        let wal_size = || fs::metadata(Wal::path(temp.path())).map_or(0, |metadata| metadata.len());
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        btree.set_sync_mode(SyncMode::Always);
+        // the record reaches the WAL, the checkpoint fails, but the commit does not
+        crash::set_write_budget(Some(record_size));
+        btree.insert(1, 1).unwrap();
+        crash::set_write_budget(None);
+        assert!(btree.checkpoint_pending());
+        assert_eq!(wal_size(), record_size as u64);
+        assert_eq!(btree.find(1).unwrap(), Some(1));
+
+        // the next commit retries the checkpoint
+        btree.insert(2, 2).unwrap();
+        assert!(!btree.checkpoint_pending());
+        assert_eq!(wal_size(), 0);
+
+        // the retry on drop fails as well, the commit survives in the WAL
+        crash::set_write_budget(Some(record_size));
+        btree.insert(3, 3).unwrap();
+        assert!(btree.checkpoint_pending());
+        drop(btree);
+        crash::set_write_budget(None);
+        assert!(wal_size() > 0);
+
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert_eq!(btree.range(..).unwrap().map(|entry| entry.unwrap()).collect::<Vec<_>>(), vec![(1, 1), (2, 2), (3, 3)]);
+        assert!(btree.check().is_ok());
+    }
+
+    #[test]
+    fn root_stays_cached() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+        for key in 0..200 {
+            btree.insert(key, key).unwrap();
+        }
+        btree.checkpoint().unwrap();
+        drop(btree);
+
+        // a single page cache only holds the pinned root
+        let btree= BTreeStore::<u32>::open_with_cache(temp.path(), 4, 1).unwrap();
+        let root = btree.meta_data.read().unwrap().root.unwrap();
+        let before = btree.cache_stats();
+        for key in 0..200 {
+            assert_eq!(btree.find(key).unwrap(), Some(key));
+        }
+        let after = btree.cache_stats();
+        // every find hits the root, all other pages are read from the file
+        assert_eq!(after.hits() - before.hits(), 200);
+        assert!(after.misses() > before.misses());
+        assert!(btree.pager.buffer_pool.lock().unwrap().contains(root));
+    }
+
+    #[test]
+    fn repeated_reads_hit_the_cache() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 8).unwrap();
+        for key in 0..100 {
+            btree.insert(key, key).unwrap();
+        }
+
+        let before = btree.cache_stats();
+        for _ in 0..10 {
+            assert_eq!(btree.range(..).unwrap().count(), 100);
+        }
+        let after = btree.cache_stats();
+        // the whole tree fits into the cache
+        assert_eq!(after.misses(), before.misses());
+        assert!(after.hits() > before.hits());
+    }
+
+    #[test]
+    fn small_cache_writes_back_dirty_pages() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut expected = BTreeMap::new();
+        {
+            let mut btree= BTreeStore::<u32>::open_with_cache(temp.path(), 4, 3).unwrap();
+            let mut seed: u32 = 5;
+            for _ in 0..1000 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let key = (seed >> 16) % 200;
+                if seed.is_multiple_of(4) {
+                    assert_eq!(btree.delete(key).unwrap(), expected.remove(&key));
+                } else if let std::collections::btree_map::Entry::Vacant(e) = expected.entry(key) {
+                    e.insert(seed);
+                    btree.insert(key, seed).unwrap();
+                }
+            }
+            assert!(*btree.cache_stats().write_backs() > 0);
+            assert!(btree.pager.buffer_pool.lock().unwrap().stats().evictions() > &0);
+
+            let all = btree.range(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(all, expected.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+        }
+
+        let btree= BTreeStore::new(temp.path(), 4).unwrap();
+        let all = btree.range(..).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(all, expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cache_must_hold_a_page() {
+        let temp = NamedTempFile::new().unwrap();
+        assert!(matches!(BTreeStore::<u32>::open_with_cache(temp.path(), 4, 0), Err(BTreeStoreError::InvalidArgument { .. })));
     }
 
     #[test]
@@ -663,25 +3298,126 @@ mod tests {
         assert!(btree.is_err());
         let btree = BTreeStore::new(temp.path(), 4);
         assert!(btree.is_ok());
-        assert_eq!(btree.unwrap().page_size(), 49) // 9 + 4*4 + 3*4 + 3*4 = 45
+        assert_eq!(btree.unwrap().page_size(), 73) // 25 + 4 * (2 + 2 + 4 + 4) = 73
     }
 
     #[test]
     fn create_new_btree_store() {
         let temp = NamedTempFile::new().unwrap();
         let btree= BTreeStore::new(temp.path(), 10).unwrap();
-        let meta_data = btree.meta_data.borrow();
+        let meta_data = btree.meta_data.read().unwrap();
         assert_eq!(meta_data.first_deleted_page, None);
         assert_eq!(meta_data.max_degree, 10);
         assert_eq!(meta_data.number_of_pages, 0);
         
         // Open existing BTree with some random degree
         let btree= BTreeStore::new(temp.path(), 100).unwrap();
-        let meta_data = btree.meta_data.borrow();
+        let meta_data = btree.meta_data.read().unwrap();
         assert_eq!(meta_data.first_deleted_page, None);
         assert_eq!(meta_data.max_degree, 10); // Use the degree from meta data section
         assert_eq!(meta_data.number_of_pages, 0);
-        assert_eq!(btree.page_size(), 121) // 9 + 10*4 + 9*4 + 9*4 = 121
+        assert_eq!(btree.page_size(), 145) // 25 + 10 * (2 + 2 + 4 + 4) = 145
+    }
+
+    #[test]
+    fn foreign_files_are_rejected() {
+        let temp = NamedTempFile::new().unwrap();
+        fs::write(temp.path(), b"just some text, which is longer than the header").unwrap();
+        let err = BTreeStore::new(temp.path(), 10).err().unwrap();
+        assert!(err.to_string().contains("magic bytes are missing"), "{}", err);
+        // the file is not overwritten
+        assert_eq!(fs::read(temp.path()).unwrap(), b"just some text, which is longer than the header");
+
+        fs::write(temp.path(), b"BPT").unwrap();
+        assert!(BTreeStore::new(temp.path(), 10).is_err());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn incompatible_stores_are_rejected() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::new(temp.path(), 10).unwrap();
+        btree.insert(1, 1).unwrap();
+        drop(btree);
+        let bytes = fs::read(temp.path()).unwrap();
+        assert_eq!(&bytes[0..4], b"BPTS");
+
+        let err = BTreeStore::<u64>::open(temp.path(), 10).err().unwrap();
+        assert!(err.to_string().contains("keys of 4 bytes, not of 8 bytes"), "{}", err);
+        let err = BTreeStore::<String>::open(temp.path(), 10).err().unwrap();
+        assert!(err.to_string().contains("keys of 4 bytes, not of variable length"), "{}", err);
+        // keys of the same width, but of another type
+        let err = BTreeStore::<i32>::open(temp.path(), 10).err().unwrap();
+        assert!(err.to_string().contains("key type 0x00000003, not 0x00000005 (i32)"), "{}", err);
+        assert!(BTreeStore::<(u16, u16)>::open(temp.path(), 10).is_err());
+
+        let mut newer_version = bytes.clone();
+        newer_version[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        fs::write(temp.path(), &newer_version).unwrap();
+        let err = BTreeStore::new(temp.path(), 10).err().unwrap();
+        assert!(err.to_string().contains(&format!("format version {}", FORMAT_VERSION + 1)), "{}", err);
+
+        let mut other_page_header = bytes.clone();
+        other_page_header[8..10].copy_from_slice(&21u16.to_be_bytes());
+        fs::write(temp.path(), &other_page_header).unwrap();
+        let err = BTreeStore::new(temp.path(), 10).err().unwrap();
+        assert!(err.to_string().contains("page headers of 21 bytes"), "{}", err);
+
+        fs::write(temp.path(), &bytes[..20]).unwrap();
+        let err = BTreeStore::new(temp.path(), 10).err().unwrap();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+
+        fs::write(temp.path(), &bytes).unwrap();
+        assert_eq!(BTreeStore::new(temp.path(), 10).unwrap().find(1).unwrap(), Some(1));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_reads_the_same_pages() {
+        use crate::page_based_bplustree::check::Problem;
+
+        for pager_mode in [PagerMode::Wal, PagerMode::Shadow] {
+            let temp = NamedTempFile::new().unwrap();
+            // a small cache, so that committed pages are evicted and read back through the mapping
+            let mut btree = BTreeStore::<u32>::open_with_pager_mode(temp.path(), 4, IndexMode::Unique, pager_mode, 8).unwrap();
+            btree.set_mmap(true);
+            assert!(btree.mmap());
+            let mut expected = BTreeMap::new();
+            let mut seed: u32 = 7;
+            // the store file grows behind the mapping
+            for _ in 0..3000 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let key = (seed >> 16) % 500;
+                if seed.is_multiple_of(4) {
+                    assert_eq!(btree.delete(key).unwrap(), expected.remove(&key), "{:?}", pager_mode);
+                } else {
+                    assert_eq!(btree.insert(key, seed).unwrap(), expected.insert(key, seed), "{:?}", pager_mode);
+                }
+            }
+            let entries = |btree: &BTreeStore| btree.range(..).unwrap().map(Result::unwrap).collect::<Vec<_>>();
+            assert_eq!(entries(&btree), expected.clone().into_iter().collect::<Vec<_>>());
+            assert!(btree.check().is_ok());
+            btree.set_mmap(false);
+            assert_eq!(entries(&btree), expected.into_iter().collect::<Vec<_>>());
+        }
+
+        // the checksums of mapped pages are verified as well
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        for key in 0..200 {
+            btree.insert(key, key).unwrap();
+        }
+        let mut leaf = btree.read_page(btree.meta_data().root.unwrap()).unwrap();
+        while !leaf.is_leaf() {
+            leaf = btree.read_page(leaf.children()[0]).unwrap();
+        }
+        let page_size = btree.page_size() as usize;
+        drop(btree);
+        let mut bytes = fs::read(temp.path()).unwrap();
+        bytes[META_DATA_HEADER_SIZE + page_size * (*leaf.id() as usize + 1) - 1] ^= 0x10;
+        fs::write(temp.path(), &bytes).unwrap();
+
+        let mut btree = BTreeStore::new(temp.path(), 4).unwrap();
+        btree.set_mmap(true);
+        assert_eq!(*btree.check().problems(), vec![Problem::ChecksumMismatch { page_id: *leaf.id() }]);
+    }
+}
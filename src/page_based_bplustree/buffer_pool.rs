@@ -0,0 +1,245 @@
+use std::{collections::HashMap, io};
+
+use derive_getters::Getters;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Getters)]
+pub struct BufferPoolStats {
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    write_backs: u64, // dirty pages written to the file on eviction
+}
+
+struct Frame {
+    page_id: u32,
+    data: Vec<u8>,
+    pin_count: u32,
+    dirty: bool, // committed, but not written to the file yet
+    referenced: bool, // second chance of the CLOCK algorithm
+}
+
+// Page cache with a fixed number of frames, replaced by the CLOCK algorithm.
+// Pinned frames are never replaced. Dirty frames are written back, before they are replaced.
+pub struct BufferPool {
+    capacity: usize,
+    frames: Vec<Frame>,
+    page_table: HashMap<u32, usize>, // page_id => index of the frame
+    hand: usize,
+    stats: BufferPoolStats,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        BufferPool {
+            capacity,
+            frames: Vec::with_capacity(capacity),
+            page_table: HashMap::new(),
+            hand: 0,
+            stats: BufferPoolStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.stats
+    }
+
+    pub fn contains(&self, page_id: u32) -> bool {
+        self.page_table.contains_key(&page_id)
+    }
+
+    // Returns the cached page and counts the hit or miss
+    pub fn get(&mut self, page_id: u32) -> Option<&[u8]> {
+        match self.page_table.get(&page_id) {
+            Some(&index) => {
+                self.stats.hits += 1;
+                let frame = &mut self.frames[index];
+                frame.referenced = true;
+                Some(&frame.data)
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Caches the page, a cached page is replaced. write_back is called for a dirty page, which is evicted.
+    // If no frame can be replaced (all pinned or the write back failed), the pool grows beyond its capacity
+    // and shrinks again with the next evictions.
+    pub fn insert(&mut self, page_id: u32, data: Vec<u8>, dirty: bool, mut write_back: impl FnMut(u32, &[u8]) -> io::Result<()>) {
+        if let Some(&index) = self.page_table.get(&page_id) {
+            let frame = &mut self.frames[index];
+            frame.data = data;
+            frame.dirty |= dirty;
+            frame.referenced = true;
+            return;
+        }
+
+        while self.frames.len() >= self.capacity {
+            match self.find_victim(&mut write_back) {
+                Some(index) => self.remove_frame(index),
+                None => break,
+            }
+        }
+
+        self.frames.push(Frame { page_id, data, pin_count: 0, dirty, referenced: true });
+        self.page_table.insert(page_id, self.frames.len() - 1);
+    }
+
+    fn remove_frame(&mut self, index: usize) {
+        let frame = self.frames.swap_remove(index);
+        self.page_table.remove(&frame.page_id);
+        if index < self.frames.len() {
+            // the last frame has been moved
+            self.page_table.insert(self.frames[index].page_id, index);
+        }
+        if self.hand >= self.frames.len() {
+            self.hand = 0;
+        }
+    }
+
+    fn find_victim(&mut self, write_back: &mut impl FnMut(u32, &[u8]) -> io::Result<()>) -> Option<usize> {
+        // two rounds: the first one might only clear the referenced flags
+        for _ in 0..2 * self.frames.len() {
+            let index = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+
+            let frame = &mut self.frames[index];
+            if frame.pin_count > 0 {
+                continue;
+            }
+            if frame.referenced {
+                frame.referenced = false;
+                continue;
+            }
+            if frame.dirty {
+                // a page which cannot be written back stays in the pool
+                if write_back(frame.page_id, &frame.data).is_err() {
+                    continue;
+                }
+                self.stats.write_backs += 1;
+            }
+
+            self.stats.evictions += 1;
+            return Some(index);
+        }
+
+        None
+    }
+
+    // A pinned page is not evicted, until it is unpinned as often as it has been pinned.
+    // Returns false, if the page is not cached.
+    pub fn pin(&mut self, page_id: u32) -> bool {
+        match self.page_table.get(&page_id) {
+            Some(&index) => {
+                self.frames[index].pin_count += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn unpin(&mut self, page_id: u32) {
+        if let Some(&index) = self.page_table.get(&page_id) {
+            let frame = &mut self.frames[index];
+            frame.pin_count = frame.pin_count.saturating_sub(1);
+        }
+    }
+
+    // Writes all dirty pages back
+    pub fn flush(&mut self, mut write_back: impl FnMut(u32, &[u8]) -> io::Result<()>) -> io::Result<()> {
+        for frame in self.frames.iter_mut().filter(|frame| frame.dirty) {
+            write_back(frame.page_id, &frame.data)?;
+            frame.dirty = false;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::BufferPool;
+
+    fn page(fill: u8) -> Vec<u8> {
+        vec![fill; 4]
+    }
+
+    #[test]
+    fn evict_with_second_chance() {
+        let mut pool = BufferPool::new(3);
+        for page_id in 0..3 {
+            pool.insert(page_id, page(page_id as u8), false, |_, _| unreachable!());
+        }
+        // all referenced: the first round clears the flags, page 0 is evicted
+        pool.insert(3, page(3), false, |_, _| unreachable!());
+        assert!(!pool.contains(0));
+
+        // page 1 gets a second chance, page 2 is evicted
+        assert_eq!(pool.get(1), Some(page(1).as_slice()));
+        pool.insert(4, page(4), false, |_, _| unreachable!());
+        assert!(pool.contains(1));
+        assert!(!pool.contains(2));
+
+        assert_eq!(pool.get(2), None);
+        assert_eq!(*pool.stats().hits(), 1);
+        assert_eq!(*pool.stats().misses(), 1);
+        assert_eq!(*pool.stats().evictions(), 2);
+    }
+
+    #[test]
+    fn pinned_pages_are_not_evicted() {
+        let mut pool = BufferPool::new(2);
+        pool.insert(0, page(0), false, |_, _| unreachable!());
+        pool.insert(1, page(1), false, |_, _| unreachable!());
+        assert!(pool.pin(0));
+        assert!(!pool.pin(7));
+
+        pool.insert(2, page(2), false, |_, _| unreachable!());
+        assert!(pool.contains(0));
+        assert!(!pool.contains(1));
+
+        // everything is pinned: the pool grows
+        assert!(pool.pin(2));
+        pool.insert(3, page(3), false, |_, _| unreachable!());
+        assert!(pool.contains(0) && pool.contains(2) && pool.contains(3));
+
+        pool.unpin(0);
+        pool.unpin(2);
+        pool.insert(4, page(4), false, |_, _| unreachable!());
+        pool.insert(5, page(5), false, |_, _| unreachable!());
+        assert!(pool.contains(4) && pool.contains(5));
+        // back to its capacity
+        assert_eq!(pool.frames.len(), 2);
+    }
+
+    #[test]
+    fn dirty_pages_are_written_back() {
+        let mut pool = BufferPool::new(2);
+        pool.insert(0, page(0), true, |_, _| unreachable!());
+        pool.insert(1, page(1), false, |_, _| unreachable!());
+
+        // a failing write back keeps the page
+        pool.insert(2, page(2), false, |_, _| Err(io::Error::other("disk full")));
+        assert!(pool.contains(0));
+        assert!(!pool.contains(1));
+
+        let mut written = Vec::new();
+        pool.insert(3, page(3), true, |page_id, data| {
+            written.push((page_id, data.to_vec()));
+            Ok(())
+        });
+        assert_eq!(written, vec![(0, page(0))]);
+        assert_eq!(*pool.stats().write_backs(), 1);
+
+        let mut flushed = Vec::new();
+        pool.flush(|page_id, _| {
+            flushed.push(page_id);
+            Ok(())
+        }).unwrap();
+        assert_eq!(flushed, vec![3]);
+        pool.flush(|_, _| unreachable!()).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::BTreeSet;
+
+use derive_getters::Getters;
+
+use crate::page_based_bplustree::{btree_store::{CorruptionError, NodePager, NodePagerError}, node::NodePage};
+
+// Inconsistencies found by BTreeStore::check, page_id is the page, which contains the inconsistency
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    // the page cannot be read from the store file
+    Unreadable { page_id: u32, msg: String },
+    // the checksum of the page does not match its content
+    ChecksumMismatch { page_id: u32 },
+    // the page stores another id than the one it has been read from
+    PageIdMismatch { page_id: u32, stored_id: u32 },
+    // a pointer refers to a page behind the last page of the store
+    PageOutOfRange { page_id: u32, number_of_pages: u32 },
+    // the page is referenced by more than one parent (or the free list and the tree)
+    ReferencedTwice { page_id: u32 },
+    // a deleted page is part of the tree
+    DeletedPageInTree { page_id: u32 },
+    // a page of the free list is not marked as deleted
+    FreePageNotDeleted { page_id: u32 },
+    KeysNotSorted { page_id: u32 },
+    // a key violates the separator keys of the parent
+    KeyOutOfBounds { page_id: u32, key_index: usize },
+    // all leaves must have the same distance to the root
+    LeafDepth { page_id: u32, depth: usize, expected: usize },
+    ChildrenCount { page_id: u32, keys: usize, children: usize },
+    ValuesCount { page_id: u32, keys: usize, values: usize },
+    // the cells need more space than the page offers
+    Overfull { page_id: u32, used_bytes: usize, capacity: usize },
+    // a node except the root is more than one cell below the minimum occupancy (reported as a warning)
+    Underfull { page_id: u32, used_bytes: usize, min_bytes: usize },
+    // next_leaf does not point to the right sibling
+    BrokenLeafChain { page_id: u32, next_leaf: Option<u32>, expected: Option<u32> },
+}
+
+#[derive(Debug, Default, Getters)]
+pub struct CheckReport {
+    number_of_pages: u32,
+    depth: usize, // number of levels, 0 for an empty tree
+    entries: usize,
+    tree_pages: usize, // pages reachable from the root
+    free_pages: Vec<u32>, // in the order of the free list
+    leaked_pages: Vec<u32>, // neither in the tree nor on the free list
+    problems: Vec<Problem>,
+    warnings: Vec<Problem>,
+}
+
+impl CheckReport {
+    // Underfull nodes are tolerated, they can be left behind by deleting long keys, which could not be rebalanced
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty() && self.leaked_pages.is_empty()
+    }
+}
+
+struct Checker<'a> {
+    pager: &'a NodePager,
+    root: u32,
+    visited: BTreeSet<u32>,
+    leaves: Vec<(u32, Option<u32>)>, // (page_id, next_leaf) in key order
+    report: CheckReport,
+}
+
+impl Checker<'_> {
+    // Reads the page, if it is within the store and has not been visited yet
+    fn visit(&mut self, page_id: u32) -> Option<NodePage> {
+        if page_id >= self.report.number_of_pages {
+            self.report.problems.push(Problem::PageOutOfRange { page_id, number_of_pages: self.report.number_of_pages });
+            return None;
+        }
+        if !self.visited.insert(page_id) {
+            self.report.problems.push(Problem::ReferencedTwice { page_id });
+            return None;
+        }
+
+        match self.pager.read_page(page_id) {
+            Ok(page) => Some(page),
+            Err(NodePagerError::Corrupt(CorruptionError::PageChecksum { .. })) => {
+                self.report.problems.push(Problem::ChecksumMismatch { page_id });
+                None
+            },
+            Err(NodePagerError::Corrupt(CorruptionError::PageIdMismatch { stored_page_id, .. })) => {
+                self.report.problems.push(Problem::PageIdMismatch { page_id, stored_id: stored_page_id });
+                None
+            },
+            Err(e) => {
+                self.report.problems.push(Problem::Unreadable { page_id, msg: e.to_string() });
+                None
+            }
+        }
+    }
+
+    // Checks the subtree, all keys must be within [min_key, max_key)
+    fn check_node(&mut self, page_id: u32, depth: usize, min_key: Option<&[u8]>, max_key: Option<&[u8]>) {
+        let Some(page) = self.visit(page_id) else {
+            return;
+        };
+        let problems = &mut self.report.problems;
+        if *page.deleted() {
+            problems.push(Problem::DeletedPageInTree { page_id });
+            return;
+        }
+        self.report.tree_pages += 1;
+
+        if !page.keys().windows(2).all(|pair| pair[0] < pair[1]) {
+            problems.push(Problem::KeysNotSorted { page_id });
+        }
+        for (key_index, key) in page.keys().iter().enumerate() {
+            if min_key.is_some_and(|min_key| key.as_slice() < min_key) || max_key.is_some_and(|max_key| key.as_slice() >= max_key) {
+                problems.push(Problem::KeyOutOfBounds { page_id, key_index });
+            }
+        }
+        if page.used_bytes() > *page.capacity() {
+            problems.push(Problem::Overfull { page_id, used_bytes: page.used_bytes(), capacity: *page.capacity() });
+        }
+        // nodes are refilled before a delete descends into them, so the delete itself can leave them a cell below the minimum
+        if page_id != self.root && page.used_bytes() + page.max_cell_size() < page.min_bytes() {
+            self.report.warnings.push(Problem::Underfull { page_id, used_bytes: page.used_bytes(), min_bytes: page.min_bytes() });
+        }
+
+        if page.is_leaf() {
+            if page.values().len() != page.keys().len() {
+                problems.push(Problem::ValuesCount { page_id, keys: page.keys().len(), values: page.values().len() });
+            }
+            if self.report.depth == 0 {
+                self.report.depth = depth;
+            } else if depth != self.report.depth {
+                problems.push(Problem::LeafDepth { page_id, depth, expected: self.report.depth });
+            }
+            self.report.entries += page.keys().len();
+            self.leaves.push((page_id, *page.next_leaf()));
+            return;
+        }
+
+        if page.children().len() != page.keys().len() + 1 || page.keys().is_empty() {
+            problems.push(Problem::ChildrenCount { page_id, keys: page.keys().len(), children: page.children().len() });
+            return;
+        }
+        for (i, child) in page.children().iter().enumerate() {
+            let child_min = if i == 0 { min_key } else { Some(page.keys()[i - 1].as_slice()) };
+            let child_max = page.keys().get(i).map(Vec::as_slice).or(max_key);
+            self.check_node(*child, depth + 1, child_min, child_max);
+        }
+    }
+
+    fn check_leaf_chain(&mut self) {
+        let next_leaves = self.leaves.iter().skip(1).map(|(page_id, _)| Some(*page_id)).chain([None]);
+        for ((page_id, next_leaf), expected) in self.leaves.iter().zip(next_leaves) {
+            if *next_leaf != expected {
+                self.report.problems.push(Problem::BrokenLeafChain { page_id: *page_id, next_leaf: *next_leaf, expected });
+            }
+        }
+    }
+
+    fn check_free_list(&mut self, first_deleted_page: Option<u32>) {
+        let mut next = first_deleted_page;
+        while let Some(page_id) = next {
+            let Some(page) = self.visit(page_id) else {
+                // a cycle or a broken page ends the list
+                return;
+            };
+            if !*page.deleted() {
+                self.report.problems.push(Problem::FreePageNotDeleted { page_id });
+                return;
+            }
+            self.report.free_pages.push(page_id);
+            next = *page.next_deleted_page();
+        }
+    }
+}
+
+// Checks the tree below root and the free list. Every page of the store must be in exactly one of them.
+pub(crate) fn check_store(pager: &NodePager, root: Option<u32>, first_deleted_page: Option<u32>, number_of_pages: u32) -> CheckReport {
+    let mut checker = Checker {
+        pager,
+        root: root.unwrap_or(u32::MAX),
+        visited: BTreeSet::new(),
+        leaves: Vec::new(),
+        report: CheckReport { number_of_pages, ..CheckReport::default() },
+    };
+
+    if let Some(root) = root {
+        checker.check_node(root, 1, None, None);
+        checker.check_leaf_chain();
+    }
+    checker.check_free_list(first_deleted_page);
+
+    checker.report.leaked_pages = (0..number_of_pages).filter(|page_id| !checker.visited.contains(page_id)).collect();
+    checker.report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use tempfile::NamedTempFile;
+
+    use crate::page_based_bplustree::btree_store::{
+        BTreeStore, BTreeStoreError, CHECKSUM_SIZE, CorruptionError, META_DATA_HEADER_SIZE, PAGE_HEADER_SIZE, POS_CHECKSUM,
+        POS_FIRST_CHILD, POS_HEADER_FIRST_DELETED_PAGE, POS_HEADER_ROOT, POS_NEXT_LEAF, page_checksum, page_offset, page_size,
+    };
+
+    use super::Problem;
+
+    // the stores of the tests have the max degree 4
+    fn page_len() -> usize {
+        page_size(4, 4)
+    }
+
+    fn page_start(page_id: u32) -> usize {
+        page_offset(page_len() as u32, page_id) as usize
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    // The tests change the file on purpose, the checksums are updated, so that the changes are not detected as bit rot
+    fn update_page_checksum(bytes: &mut [u8], page: usize) {
+        let checksum = page_checksum(&bytes[page..page + page_len()]);
+        bytes[page + POS_CHECKSUM..page + POS_CHECKSUM + CHECKSUM_SIZE].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn update_header_checksum(bytes: &mut [u8]) {
+        let checksum = crc32fast::hash(&bytes[..META_DATA_HEADER_SIZE - CHECKSUM_SIZE]);
+        bytes[META_DATA_HEADER_SIZE - CHECKSUM_SIZE..META_DATA_HEADER_SIZE].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    // Returns the offset of the leftmost leaf in the store file
+    fn first_leaf(bytes: &[u8]) -> usize {
+        let mut page_id = read_u32(bytes, POS_HEADER_ROOT);
+        loop {
+            let offset = page_start(page_id);
+            match read_u32(bytes, offset + POS_FIRST_CHILD) {
+                u32::MAX => return offset,
+                first_child => page_id = first_child,
+            }
+        }
+    }
+
+    fn store_with_free_pages(path: &Path) {
+        let mut btree= BTreeStore::new(path, 4).unwrap();
+        for key in 0..100 {
+            btree.insert(key, key).unwrap();
+        }
+        for key in 20..60 {
+            btree.delete(key).unwrap();
+        }
+    }
+
+    #[test]
+    fn consistent_stores_pass_the_check() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::new(temp.path(), 4).unwrap();
+        let report = btree.check();
+        assert!(report.is_ok());
+        assert_eq!(*report.depth(), 0);
+
+        let mut seed: u32 = 3;
+        for _ in 0..2000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let key = (seed >> 16) % 300;
+            if seed.is_multiple_of(3) {
+                btree.delete(key).unwrap();
+            } else {
+                btree.insert(key, seed).unwrap();
+            }
+        }
+        let report = btree.check();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.warnings().is_empty());
+        assert_eq!(*report.entries(), btree.range(..).unwrap().count());
+        assert_eq!(*report.tree_pages() + report.free_pages().len(), *report.number_of_pages() as usize);
+
+        let temp = NamedTempFile::new().unwrap();
+        let btree= BTreeStore::bulk_load(temp.path(), 8, (0..3000).map(|key| (key, key))).unwrap();
+        let report = btree.check();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(*report.entries(), 3000);
+        assert_eq!(*report.depth(), 4);
+
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree= BTreeStore::<Vec<u8>>::open(temp.path(), 16).unwrap();
+        for i in 0..500u32 {
+            let key = vec![(i % 7) as u8 + 1; (i as usize * 13) % 30];
+            if i % 4 == 0 {
+                btree.delete(&key).unwrap();
+            } else {
+                btree.insert(key, i).unwrap();
+            }
+        }
+        assert!(btree.check().is_ok());
+    }
+
+    #[test]
+    fn detect_leaked_pages() {
+        let temp = NamedTempFile::new().unwrap();
+        store_with_free_pages(temp.path());
+        let free_pages = BTreeStore::new(temp.path(), 4).unwrap().check().free_pages().clone();
+        assert!(!free_pages.is_empty());
+
+        // forget the free list
+        let mut bytes = fs::read(temp.path()).unwrap();
+        bytes[POS_HEADER_FIRST_DELETED_PAGE..POS_HEADER_FIRST_DELETED_PAGE + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        update_header_checksum(&mut bytes);
+        fs::write(temp.path(), &bytes).unwrap();
+
+        let report = BTreeStore::new(temp.path(), 4).unwrap().check();
+        assert!(!report.is_ok());
+        assert!(report.problems().is_empty());
+        let mut leaked_pages = free_pages;
+        leaked_pages.sort();
+        assert_eq!(*report.leaked_pages(), leaked_pages);
+    }
+
+    #[test]
+    fn detect_broken_pages() {
+        let temp = NamedTempFile::new().unwrap();
+        store_with_free_pages(temp.path());
+        let mut bytes = fs::read(temp.path()).unwrap();
+        let leaf = first_leaf(&bytes);
+        let leaf_id = read_u32(&bytes, leaf);
+        // cut the leaf chain and swap the first two slots
+        bytes[leaf + POS_NEXT_LEAF..leaf + POS_NEXT_LEAF + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let slots = leaf + PAGE_HEADER_SIZE;
+        bytes.copy_within(slots..slots + 2, slots + 4);
+        bytes.copy_within(slots + 2..slots + 4, slots);
+        bytes.copy_within(slots + 4..slots + 6, slots + 2);
+        // the free list points into the tree
+        bytes[POS_HEADER_FIRST_DELETED_PAGE..POS_HEADER_FIRST_DELETED_PAGE + 4].copy_from_slice(&leaf_id.to_be_bytes());
+        update_page_checksum(&mut bytes, leaf);
+        update_header_checksum(&mut bytes);
+        fs::write(temp.path(), &bytes).unwrap();
+
+        let report = BTreeStore::new(temp.path(), 4).unwrap().check();
+        assert!(report.problems().contains(&Problem::KeysNotSorted { page_id: leaf_id }));
+        assert!(report.problems().iter().any(|problem| matches!(problem, Problem::BrokenLeafChain { page_id, next_leaf: None, .. } if *page_id == leaf_id)));
+        assert!(report.problems().contains(&Problem::ReferencedTwice { page_id: leaf_id }));
+        // the former free pages are leaked now
+        assert!(!report.leaked_pages().is_empty());
+    }
+
+    #[test]
+    fn detect_bit_rot() {
+        let temp = NamedTempFile::new().unwrap();
+        store_with_free_pages(temp.path());
+        let mut bytes = fs::read(temp.path()).unwrap();
+        let leaf = first_leaf(&bytes);
+        let leaf_id = read_u32(&bytes, leaf);
+        // the last byte of the page belongs to the value of a cell
+        bytes[leaf + page_len() - 1] ^= 0x10;
+        fs::write(temp.path(), &bytes).unwrap();
+
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        let report = btree.check();
+        assert_eq!(*report.problems(), vec![Problem::ChecksumMismatch { page_id: leaf_id }]);
+        match btree.read_page(leaf_id) {
+            Err(BTreeStoreError::Corrupt(corruption)) => assert_eq!(corruption.page_id(), Some(leaf_id)),
+            other => panic!("Expected a corrupt page, got {:?}", other.map(|page| *page.id())),
+        }
+        drop(btree);
+
+        // a page, which has been written to the wrong place, is detected as well
+        bytes[leaf..leaf + 4].copy_from_slice(&(leaf_id + 1).to_be_bytes());
+        update_page_checksum(&mut bytes, leaf);
+        fs::write(temp.path(), &bytes).unwrap();
+        let report = BTreeStore::new(temp.path(), 4).unwrap().check();
+        assert!(report.problems().contains(&Problem::PageIdMismatch { page_id: leaf_id, stored_id: leaf_id + 1 }));
+
+        // the header is checked on open
+        bytes[POS_HEADER_ROOT] ^= 0x01;
+        fs::write(temp.path(), &bytes).unwrap();
+        assert!(matches!(BTreeStore::new(temp.path(), 4), Err(BTreeStoreError::Corrupt(CorruptionError::HeaderChecksum))));
+    }
+}
@@ -0,0 +1,260 @@
+// Keys are stored as byte strings in the pages and compared bytewise.
+// An encoding must be order preserving (a < b <=> encode(a) < encode(b)) and self delimiting,
+// so that encoded keys can be concatenated to composite keys (tuples).
+pub trait StoreKey: Sized {
+    // Size of every encoded key, None for keys of variable length. It is recorded in the header of a store.
+    const WIDTH: Option<usize> = None;
+    // Tag of the key type, which is recorded in the header of a store, so that a store is only opened with its key type.
+    // Every type needs its own tag, also if it has the same width or encoding as another type.
+    const TYPE_ID: u32;
+
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    // Decodes a key from the start of bytes and advances bytes behind the decoded key.
+    fn decode_key(bytes: &mut &[u8]) -> Option<Self>;
+}
+
+pub fn encode<K: StoreKey>(key: &K) -> Vec<u8> {
+    let mut out = Vec::new();
+    key.encode_key(&mut out);
+    out
+}
+
+// Decodes a key, fails if not all bytes belong to the key.
+pub fn decode<K: StoreKey>(mut bytes: &[u8]) -> Option<K> {
+    let key = K::decode_key(&mut bytes)?;
+    bytes.is_empty().then_some(key)
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+    let (head, tail) = bytes.split_first_chunk::<N>()?;
+    *bytes = tail;
+    Some(*head)
+}
+
+// Unsigned integers are stored big-endian, so that the byte order equals the numeric order.
+macro_rules! unsigned_key {
+    ($($t:ty => $id:expr),*) => {
+        $(
+            impl StoreKey for $t {
+                const WIDTH: Option<usize> = Some(size_of::<$t>());
+                const TYPE_ID: u32 = $id;
+
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_key(bytes: &mut &[u8]) -> Option<Self> {
+                    take(bytes).map(<$t>::from_be_bytes)
+                }
+            }
+        )*
+    };
+}
+
+// Signed integers flip the sign bit, so that negative numbers are ordered before positive ones.
+macro_rules! signed_key {
+    ($($t:ty => $u:ty, $id:expr),*) => {
+        $(
+            impl StoreKey for $t {
+                const WIDTH: Option<usize> = <$u>::WIDTH;
+                const TYPE_ID: u32 = $id;
+
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_key(out);
+                }
+
+                fn decode_key(bytes: &mut &[u8]) -> Option<Self> {
+                    <$u>::decode_key(bytes).map(|u| (u ^ (1 << (<$u>::BITS - 1))) as $t)
+                }
+            }
+        )*
+    };
+}
+
+unsigned_key!(u8 => 1, u16 => 2, u32 => 3, u64 => 4);
+signed_key!(i32 => u32, 5, i64 => u64, 6);
+
+// Byte strings escape 0x00 as 0x00 0xFF and are terminated by 0x00 0x01.
+// A shorter string is ordered before all strings it is a prefix of, because the terminator is less than
+// an escaped 0x00 and every other byte.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_NULL: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+fn encode_bytes(value: &[u8], out: &mut Vec<u8>) {
+    for b in value {
+        out.push(*b);
+        if *b == ESCAPE {
+            out.push(ESCAPED_NULL);
+        }
+    }
+    out.push(ESCAPE);
+    out.push(TERMINATOR);
+}
+
+fn decode_bytes(bytes: &mut &[u8]) -> Option<Vec<u8>> {
+    let mut value = Vec::new();
+    let mut i = 0;
+    loop {
+        match *bytes.get(i)? {
+            ESCAPE => {
+                match *bytes.get(i + 1)? {
+                    ESCAPED_NULL => value.push(ESCAPE),
+                    TERMINATOR => {
+                        *bytes = &bytes[i + 2..];
+                        return Some(value);
+                    },
+                    _ => return None,
+                }
+                i += 2;
+            },
+            b => {
+                value.push(b);
+                i += 1;
+            }
+        }
+    }
+}
+
+impl StoreKey for Vec<u8> {
+    const TYPE_ID: u32 = 7;
+
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_bytes(self, out);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Option<Self> {
+        decode_bytes(bytes)
+    }
+}
+
+impl StoreKey for String {
+    const TYPE_ID: u32 = 8;
+
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_bytes(self.as_bytes(), out);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(decode_bytes(bytes)?).ok()
+    }
+}
+
+// Sums up the widths of the parts of a composite key
+const fn sum_widths(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        _ => None,
+    }
+}
+
+// Tag of a composite key, which depends on the order of its parts (FNV-1a over the tags of the parts)
+const fn combine_type_ids(ids: &[u32]) -> u32 {
+    let mut hash: u32 = 0x811C_9DC5;
+    let mut i = 0;
+    while i < ids.len() {
+        hash = (hash ^ ids[i]).wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+impl<A: StoreKey, B: StoreKey> StoreKey for (A, B) {
+    const WIDTH: Option<usize> = sum_widths(A::WIDTH, B::WIDTH);
+    const TYPE_ID: u32 = combine_type_ids(&[A::TYPE_ID, B::TYPE_ID]);
+
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.0.encode_key(out);
+        self.1.encode_key(out);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Option<Self> {
+        Some((A::decode_key(bytes)?, B::decode_key(bytes)?))
+    }
+}
+
+impl<A: StoreKey, B: StoreKey, C: StoreKey> StoreKey for (A, B, C) {
+    const WIDTH: Option<usize> = sum_widths(sum_widths(A::WIDTH, B::WIDTH), C::WIDTH);
+    const TYPE_ID: u32 = combine_type_ids(&[A::TYPE_ID, B::TYPE_ID, C::TYPE_ID]);
+
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.0.encode_key(out);
+        self.1.encode_key(out);
+        self.2.encode_key(out);
+    }
+
+    fn decode_key(bytes: &mut &[u8]) -> Option<Self> {
+        Some((A::decode_key(bytes)?, B::decode_key(bytes)?, C::decode_key(bytes)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StoreKey, decode, encode};
+
+    fn assert_order_preserved<K: StoreKey + Ord + std::fmt::Debug>(mut keys: Vec<K>) {
+        keys.sort();
+        let encoded = keys.iter().map(encode).collect::<Vec<_>>();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]), "Encoding must preserve the order of {:?}", keys);
+
+        for (key, bytes) in keys.iter().zip(encoded.iter()) {
+            assert_eq!(decode::<K>(bytes).as_ref(), Some(key));
+        }
+    }
+
+    #[test]
+    fn integers_keep_their_order() {
+        assert_order_preserved(vec![0u32, 1, 255, 256, 70_000, u32::MAX]);
+        assert_order_preserved(vec![i64::MIN, -1_000_000, -1, 0, 1, 42, i64::MAX]);
+        assert_order_preserved(vec![i32::MIN, -2, 0, 7, i32::MAX]);
+    }
+
+    #[test]
+    fn strings_keep_their_order() {
+        assert_order_preserved(
+            vec!["", "a", "a\0", "a\0b", "ab", "abc", "b", "\u{ff}"].into_iter().map(String::from).collect()
+        );
+        assert_order_preserved(vec![vec![], vec![0u8], vec![0, 0], vec![0, 1], vec![1], vec![0xFF, 0]]);
+    }
+
+    #[test]
+    fn composite_keys_keep_their_order() {
+        assert_order_preserved(vec![
+            ("a".to_string(), 2u32),
+            ("a".to_string(), 10),
+            ("ab".to_string(), 0),
+            ("b".to_string(), 1),
+        ]);
+        assert_order_preserved(vec![(-5i64, "x".to_string(), 1u8), (-5, "x".to_string(), 2), (3, String::new(), 0)]);
+    }
+
+    #[test]
+    fn decode_rejects_trailing_and_truncated_bytes() {
+        let mut bytes = encode(&7u32);
+        bytes.push(0);
+        assert_eq!(decode::<u32>(&bytes), None);
+        assert_eq!(decode::<u32>(&[0, 1]), None);
+        assert_eq!(decode::<String>(b"abc"), None);
+    }
+
+    #[test]
+    fn widths_of_fixed_size_keys() {
+        assert_eq!(u32::WIDTH, Some(encode(&7u32).len()));
+        assert_eq!(i64::WIDTH, Some(8));
+        assert_eq!(<(u32, u16)>::WIDTH, Some(6));
+        assert_eq!(<(u32, String)>::WIDTH, None);
+        assert_eq!(Vec::<u8>::WIDTH, None);
+    }
+
+    #[test]
+    fn key_types_have_distinct_tags() {
+        let ids = [
+            u8::TYPE_ID, u16::TYPE_ID, u32::TYPE_ID, u64::TYPE_ID, i32::TYPE_ID, i64::TYPE_ID, Vec::<u8>::TYPE_ID, String::TYPE_ID,
+            <(u32, u32)>::TYPE_ID, <(u32, i32)>::TYPE_ID, <(i32, u32)>::TYPE_ID, <(u32, u32, u32)>::TYPE_ID, <((u32, u32), u32)>::TYPE_ID,
+        ];
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[i + 1..].contains(id), "{} is used twice", id);
+        }
+    }
+}
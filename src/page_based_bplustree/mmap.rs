@@ -0,0 +1,94 @@
+use std::{fs::File, io};
+
+use memmap2::{Mmap, MmapOptions};
+
+// Read-only memory map of the store file. The file grows with the number of pages, the mapping follows it,
+// when a page behind the end of the mapped file is read. The pages are written through the file, on Linux and Windows
+// the mapping sees these writes immediately (both use the page cache of the operating system).
+// On Unix the mapping grows geometrically: it is at least doubled and may reach behind the end of the file, so that
+// a growing file is not remapped with every new page. The bytes behind the end of the file are never read (they would
+// raise SIGBUS), reads are checked against the size of the file. Windows can't map a read-only file behind its end.
+// Mapping a file is unsafe, because the mapped bytes change, if the file is written. NodePager only writes the file,
+// while it holds the lock of the file, and reads the mapping under the same lock. The store file must not be
+// truncated or written by other processes, while it is open.
+#[derive(Default)]
+pub struct MappedFile {
+    map: Option<Mmap>,
+    file_len: u64, // size of the file, when it has been checked the last time
+}
+
+impl MappedFile {
+    fn len(&self) -> usize {
+        self.map.as_ref().map_or(0, |map| map.len())
+    }
+
+    // The bytes at offset, the mapping is grown, if they are behind the end of the mapped file.
+    // The file must be locked, as long as the bytes are used (see above).
+    pub fn read(&mut self, file: &File, offset: u64, len: usize) -> io::Result<&[u8]> {
+        let end = offset + len as u64;
+        if end > self.file_len {
+            let file_len = file.metadata()?.len();
+            if end > file_len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} bytes at offset {} are behind the end of the file", len, offset)));
+            }
+            if file_len > self.len() as u64 {
+                #[cfg(unix)]
+                let map_len = file_len.max(2 * self.len() as u64);
+                #[cfg(not(unix))]
+                let map_len = file_len;
+                // SAFETY: see above
+                self.map = Some(unsafe { MmapOptions::new().len(map_len as usize).map(file)? });
+            }
+            self.file_len = file_len;
+        }
+
+        let map = self.map.as_deref().unwrap_or_default();
+        Ok(&map[offset as usize..end as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::MappedFile;
+
+    #[test]
+    fn mapping_grows_with_the_file() {
+        let mut temp = NamedTempFile::new().unwrap();
+        temp.write_all(&[1; 100]).unwrap();
+        let mut mapped = MappedFile::default();
+        assert_eq!(mapped.len(), 0);
+        assert_eq!(mapped.read(temp.as_file(), 90, 10).unwrap(), &[1; 10]);
+        assert_eq!(mapped.len(), 100);
+
+        // the mapping is grown, when bytes behind its end are read
+        temp.write_all(&[2; 100]).unwrap();
+        assert_eq!(mapped.read(temp.as_file(), 95, 10).unwrap(), &[1, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+        assert_eq!(mapped.len(), 200);
+        assert!(mapped.read(temp.as_file(), 150, 51).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mapping_is_doubled() {
+        let mut temp = NamedTempFile::new().unwrap();
+        temp.write_all(&[1; 100]).unwrap();
+        let mut mapped = MappedFile::default();
+        mapped.read(temp.as_file(), 0, 10).unwrap();
+        temp.write_all(&[2; 10]).unwrap();
+        assert_eq!(mapped.read(temp.as_file(), 100, 10).unwrap(), &[2; 10]);
+        assert_eq!(mapped.len(), 200);
+
+        // the file grows into the mapping, which is not remapped
+        temp.write_all(&[3; 90]).unwrap();
+        assert_eq!(mapped.read(temp.as_file(), 190, 10).unwrap(), &[3; 10]);
+        assert_eq!(mapped.len(), 200);
+        assert!(mapped.read(temp.as_file(), 195, 10).is_err());
+        temp.write_all(&[4; 1]).unwrap();
+        assert_eq!(mapped.read(temp.as_file(), 200, 1).unwrap(), &[4]);
+        assert_eq!(mapped.len(), 400);
+    }
+}
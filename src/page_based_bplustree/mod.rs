@@ -1,7 +1,14 @@
-use std::u32;
-
 pub mod btree_store;
+pub mod buffer_pool;
+pub mod check;
+pub mod key;
+#[cfg(feature = "mmap")]
+pub mod mmap;
 pub mod node;
+pub mod shadow;
+pub mod shared_store;
+pub mod snapshot;
+pub mod wal;
 
 pub fn read_u32_with_null(raw_value: u32) -> Option<u32> {
     if raw_value == u32::MAX {
@@ -1,11 +1,11 @@
-use std::{cell::RefCell, mem, u32};
+use std::cell::RefCell;
 
 use derive_getters::Getters;
 
-use crate::page_based_bplustree::btree_store::NodePager;
+use crate::page_based_bplustree::btree_store::{NodePager, NodePagerError, cell_size};
 
 enum FindKeyResponse {
-    GreaterThanTheLast(usize),
+    GreaterThanTheLast,
     Equal(usize),
     LessThan(usize)
 }
@@ -15,16 +15,16 @@ pub struct NodePage {
     id: u32, // u32::MAX is a new page
     deleted: bool,
     next_deleted_page: Option<u32>,
-    keys: Vec<u32>,
+    keys: Vec<Vec<u8>>, // encoded keys, compared bytewise
     children: Vec<u32>, // stores page number (page_id)
     values: Vec<u32>, // each item points to a page of rows
-    max_degree: usize,
+    capacity: usize, // bytes available for slots and cells
     changed: RefCell<bool>, // flag is not stored, indicates, if the node has been changed
-    // next_leaf: Option<u32> TODO: linked list between leaves
+    next_leaf: Option<u32>, // linked list between leaves (right sibling), None for internal nodes and the last leaf
 }
 
 impl NodePage {
-    pub fn keys_mut(&mut self) -> &mut Vec<u32> {
+    pub fn keys_mut(&mut self) -> &mut Vec<Vec<u8>> {
         *self.changed.borrow_mut() = true;
         &mut self.keys
     }
@@ -39,6 +39,11 @@ impl NodePage {
         &mut self.values
     }
 
+    pub fn set_next_leaf(&mut self, next_leaf: Option<u32>) {
+        *self.changed.borrow_mut() = true;
+        self.next_leaf = next_leaf;
+    }
+
     pub fn delete_page(&mut self, next_deleted: Option<u32>) {
         self.deleted = true;
         *self.changed.borrow_mut() = true;
@@ -46,6 +51,7 @@ impl NodePage {
         self.children = Vec::new();
         self.values = Vec::new();
         self.next_deleted_page = next_deleted;
+        self.next_leaf = None;
     }
 
     pub fn reallocate(&mut self) {
@@ -55,8 +61,9 @@ impl NodePage {
         self.children = Vec::new();
         self.values = Vec::new();
         self.next_deleted_page = None;
+        self.next_leaf = None;
     }
-    pub fn new(max_degree: usize, id: u32) -> Self {
+    pub fn new(capacity: usize, id: u32) -> Self {
         if id == u32::MAX {
             panic!("Cannot write page with id 0xFFFFFFFF");
         }
@@ -67,19 +74,22 @@ impl NodePage {
             values: Vec::new(),
             keys: Vec::new(),
             children: Vec::new(),
-            max_degree,
+            capacity,
             changed: RefCell::new(true),
+            next_leaf: None,
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_store(
         id: u32,
         deleted: bool,
         next_deleted_page: Option<u32>,
-        keys: Vec<u32>,
+        keys: Vec<Vec<u8>>,
         children: Vec<u32>,
         values: Vec<u32>,
-        max_degree: usize
+        capacity: usize,
+        next_leaf: Option<u32>,
     ) -> Self {
         Self {
             id,
@@ -88,8 +98,9 @@ impl NodePage {
             keys,
             children,
             values,
-            max_degree,
+            capacity,
             changed: RefCell::new(false),
+            next_leaf,
         }
 
     }
@@ -106,44 +117,27 @@ impl NodePage {
     //     }
     // }
 
-    pub fn min_keys(&self) -> usize {
-        (self.max_keys() as f32 / 2.0).ceil() as usize
+    // Bytes used by the slots and cells of this node
+    pub fn used_bytes(&self) -> usize {
+        self.keys.iter().map(|k| cell_size(k)).sum()
     }
 
-    pub fn max_keys(&self) -> usize {
-        self.max_degree - 1
+    // After a split each half uses at most half of the page plus one cell,
+    // so the next cell always fits into the node it is inserted into
+    pub fn max_cell_size(&self) -> usize {
+        self.capacity / 4
+    }
+
+    // Two minimal nodes plus one cell of the sibling fit into a single page,
+    // so a node below the minimum can always be merged with a leaf sibling which cannot lend
+    pub fn min_bytes(&self) -> usize {
+        (self.capacity - self.max_cell_size()) / 2
     }
 
     pub fn is_leaf(&self) -> bool {
         self.children.is_empty()
     }
 
-    // #[cfg(test)]
-    // fn validate(&self, min_key: Option<u32>, max_key: Option<u32>) {
-    //     self.check_node_invariants();
-    //     if let Some(min_key) = min_key {
-    //         assert!(self.keys.iter().all(|k| *k >= min_key), "All Keys must be greater or equal than min_key. min_key: {}, keys:{:?}", min_key, self.keys);
-    //     }
-
-    //     if let Some(max_key) = max_key {
-    //         assert!(self.keys.iter().all(|k| *k < max_key), "All Keys must be less than max_key. max_key: {}, keys:{:?}", max_key, self.keys);
-    //     }
-
-    //     for i in 0..self.children.len() {
-    //         let child_min = match i {
-    //             0 => min_key,
-    //             _ => Some(self.keys[i - 1]),
-    //         };
-
-    //         let child_max = match i {
-    //             i if i < self.keys.len() => Some(self.keys[i]),
-    //             _ => max_key,
-    //         };
-
-    //         self.children[i].validate(child_min, child_max);
-    //     }
-    // }
-
     #[cfg(test)]
     fn check_node_invariants(&self) {
         assert!(!self.keys.is_empty(), "Keys must never be empty: {:?}", self);
@@ -159,125 +153,132 @@ impl NodePage {
             assert!(!self.children.is_empty(), "Children must not be empty if not leaf: {:?}", self);
         }
 
-        assert!(self.max_degree > self.keys.len(), "Max degree must be greater than key len. Keys: {:?}", self.keys);
+        assert!(self.capacity >= self.used_bytes(), "Cells must fit into the page. Keys: {:?}", self.keys);
 
         assert!(self.keys.windows(2).all(|pair| pair[0] < pair[1]), "Keys must be sorted. Keys in this node: {:?}", self.keys);
     }
 
-    // returns new left node, new right node and the key (K) for the parent
-    pub fn split(&mut self, pager: &NodePager) -> (NodePage, NodePage, u32) {
-        // check invariants before split
-        let middle_value_index = self.keys.len() / 2;
+    // Splits the node: self keeps the left half (and its page id), so the leaf chain pointing
+    // to this page stays valid. Returns the new right node and the key (K) for the parent
+    pub fn split(&mut self, pager: &NodePager) -> Result<(NodePage, Vec<u8>), NodePagerError> {
+        // split at the cell where the left half reaches half of the used bytes
+        let half = self.used_bytes() / 2;
+        let mut middle_value_index = 0;
+        let mut left_bytes = 0;
+        while middle_value_index < self.keys.len() && left_bytes + cell_size(&self.keys[middle_value_index]) <= half {
+            left_bytes += cell_size(&self.keys[middle_value_index]);
+            middle_value_index += 1;
+        }
+        // both halves must keep at least one key, an internal node also needs a key to promote
+        let max_index = if self.is_leaf() { self.keys.len() - 1 } else { self.keys.len() - 2 };
+        let middle_value_index = middle_value_index.clamp(1, max_index);
 
         let mut right_keys = self.keys.split_off(middle_value_index);
         let mut right_children = Vec::new();
         let mut right_values = Vec::new();
 
-        let left_keys;
-        let mut left_children = Vec::new();
-        let mut left_values = Vec::new();
-
         let promoted_key;
         
         if !self.is_leaf() {
             right_children = self.children.split_off(middle_value_index + 1);
-            left_children = mem::take(&mut self.children);
 
             promoted_key = right_keys.remove(0); // Key promotes and gets removed
         } else {
             right_values = self.values.split_off(middle_value_index);
-            left_values = mem::take(&mut self.values);
 
-            promoted_key = right_keys[0]; // Key stays in right node and promotes
+            promoted_key = right_keys[0].clone(); // Key stays in right node and promotes
         }
-        left_keys = mem::take(&mut self.keys);
-
-        let mut left_node = pager.allocate_new_page().unwrap();
-        left_node.values = left_values;
-        left_node.keys = left_keys;
-        left_node.children = left_children;
-        left_node.max_degree = *self.max_degree();
-        
-        pager.write_page(&left_node).unwrap();
 
-        let mut right_node = pager.allocate_new_page().unwrap();
+        let mut right_node = pager.allocate_new_page()?;
         right_node.values = right_values;
         right_node.keys = right_keys;
         right_node.children = right_children;
-        right_node.max_degree = *self.max_degree();
+        right_node.capacity = self.capacity;
 
-        pager.write_page(&right_node).unwrap();
+        if self.is_leaf() {
+            // right node takes over the old successor, left node (self) points to the right node
+            right_node.next_leaf = self.next_leaf;
+            self.next_leaf = Some(*right_node.id());
+        }
+
+        pager.write_page(&right_node)?;
 
         *self.changed.borrow_mut() = true;
-        (left_node, right_node, promoted_key)
+        pager.write_page(self)?;
+
+        Ok((right_node, promoted_key))
     }
 
-    fn find_key_index(&self, key: u32) -> FindKeyResponse {
-        for (i, &k) in self.keys.iter().enumerate() {
-            if key < k {
+    fn find_key_index(&self, key: &[u8]) -> FindKeyResponse {
+        for (i, k) in self.keys.iter().enumerate() {
+            if key < k.as_slice() {
                 return FindKeyResponse::LessThan(i);
-            } else if key == k {
+            } else if key == k.as_slice() {
                 return FindKeyResponse::Equal(i);
             }
         }
         
-        FindKeyResponse::GreaterThanTheLast(self.keys.len().saturating_sub(1))
+        FindKeyResponse::GreaterThanTheLast
     }
 
-    fn insert_key_value(&mut self, key: u32, value: u32) {
-        match self.find_key_index(key) {
+    // Returns the previous value of an existing key, which is only replaced if overwrite is set
+    fn insert_key_value(&mut self, key: &[u8], value: u32, overwrite: bool) -> Option<u32> {
+        let previous = match self.find_key_index(key) {
             FindKeyResponse::LessThan(i) => {
-                self.keys.insert(i, key);
+                self.keys.insert(i, key.to_vec());
                 self.values.insert(i, value);
                 *self.changed.borrow_mut() = true;
+                None
             },
-            FindKeyResponse::GreaterThanTheLast(_) => {
-                self.keys.push(key);
+            FindKeyResponse::GreaterThanTheLast => {
+                self.keys.push(key.to_vec());
                 self.values.push(value);
                 *self.changed.borrow_mut() = true;
+                None
+            },
+            FindKeyResponse::Equal(i) => {
+                let previous = self.values[i];
+                if overwrite && previous != value {
+                    self.values[i] = value;
+                    *self.changed.borrow_mut() = true;
+                }
+                Some(previous)
             },
-            FindKeyResponse::Equal(_) => {},
-        }      
+        };
  
         #[cfg(test)]
         self.check_node_invariants();
+
+        previous
     }
     
-    pub fn insert(&mut self, pager: &NodePager, key: u32, value: u32) {
+    // Returns the previous value of the key. An existing value is only replaced if overwrite is set.
+    pub fn insert(&mut self, pager: &NodePager, key: &[u8], value: u32, overwrite: bool) -> Result<Option<u32>, NodePagerError> {
         // if is leaf, then insert key and value
         if self.is_leaf() {
-            self.insert_key_value(key, value); 
+            Ok(self.insert_key_value(key, value, overwrite))
         } else {
             // if not leaf:
 
             // 1. find correct Node
             let mut node_index= self.keys.iter().enumerate()
-                .find(|(_, k)| key < **k)
+                .find(|(_, k)| key < k.as_slice())
                 .map(|(i, _)| i)
                 .unwrap_or(self.children.len() - 1);
 
             // 2. if Node is full, split
-            let mut child = pager.read_page(self.children[node_index]).unwrap();
+            let mut child = pager.read_page(self.children[node_index])?;
             let mut split = false;
             if child.is_full() {
                     split = true;
-                    let (lnode, rnode, new_key) = child.split(pager);
-                    if self.keys.len() == node_index {
-                        // append at the end
-                        self.keys.push(new_key);
-                        self.children[node_index] = *lnode.id();
-                        self.children.push(*rnode.id());
-
-                        if key > new_key {
-                            node_index += 1;
-                        }
-                    } else {
-                        self.keys.insert(node_index, new_key);
-                        self.children.insert(node_index, *rnode.id());
-                        self.children.insert(node_index, *lnode.id());
-                        if key < new_key {
-                            node_index -= 1;
-                        }
+                    // child keeps its page id as left node, the right node is placed behind it
+                    let (rnode, new_key) = child.split(pager)?;
+                    let insert_right = key >= new_key.as_slice();
+                    self.keys.insert(node_index, new_key);
+                    self.children.insert(node_index + 1, *rnode.id());
+
+                    if insert_right {
+                        node_index += 1;
                     }
                     *self.changed.borrow_mut() = true;
             }
@@ -285,79 +286,99 @@ impl NodePage {
             // 3. insert into next node
             if split {
                 // node_index has changed, that's why the child is loaded again
-                child = pager.read_page(self.children[node_index]).unwrap();
+                child = pager.read_page(self.children[node_index])?;
             }
 
-            child.insert(pager, key, value);
-            pager.write_page(&child).unwrap();
+            let previous = child.insert(pager, key, value, overwrite)?;
+            pager.write_page(&child)?;
+            Ok(previous)
         }
     }
 
+    // A node is full, if a cell of the maximum size does not fit anymore
     pub fn is_full(&self) -> bool {
-        self.keys.len() >= self.max_keys()
+        self.capacity - self.used_bytes() < self.max_cell_size()
     }
 
-    pub fn can_lend_keys(&self) -> bool {
-        self.keys.len() > self.min_keys()
+    // Checks if the node stays at the minimum after lending the key at key_index
+    pub fn can_lend_key(&self, key_index: usize) -> bool {
+        self.keys.len() > 1 && self.used_bytes() - cell_size(&self.keys[key_index]) >= self.min_bytes()
     }
 
     pub fn is_less_than_minimal(&self) -> bool {
-        self.keys.len() < self.min_keys()
+        self.used_bytes() < self.min_bytes()
     }
 
-    pub fn find(&self, pager: &NodePager, key: u32) -> Option<u32> {
-        match self.find_key_index(key) {
-            // is leaf
-            FindKeyResponse::GreaterThanTheLast(_) if self.is_leaf() => None,
-            FindKeyResponse::LessThan(_) if self.is_leaf() => None,
-            FindKeyResponse::Equal(i) if self.is_leaf() => Some(self.values[i]),
-            // internal node
-            FindKeyResponse::GreaterThanTheLast(i) 
-                | FindKeyResponse::Equal(i) => {
-                    let child = pager.read_page(self.children[i + 1]).unwrap();
-                    child.find(pager, key)
-            },
-            FindKeyResponse::LessThan(i) => {
-                let child = pager.read_page(self.children[i]).unwrap();
-                child.find(pager, key)
-            }
+    // Checks if an additional key of `additional` bytes fits into the page
+    pub fn fits(&self, additional: usize) -> bool {
+        self.used_bytes() + additional <= self.capacity
+    }
+
+    // Checks if the key at key_index can be replaced by key without overflowing the page
+    fn can_replace_key(&self, key_index: usize, key: &[u8]) -> bool {
+        self.used_bytes() - cell_size(&self.keys[key_index]) + cell_size(key) <= self.capacity
+    }
+
+    // Leaves are refilled lazily (below the minimum), internal nodes already when they are down to a single key.
+    // An internal node loses a key whenever two of its children are merged, this way it never runs out of keys.
+    pub fn needs_refill(&self) -> bool {
+        if self.is_leaf() {
+            self.is_less_than_minimal()
+        } else {
+            self.keys.len() <= 1 || self.is_less_than_minimal()
         }
     }
 
     // Delete a key from this subtree. Returns the removed value if present.
-    pub fn delete(&mut self, pager: &NodePager, key: u32) -> Option<u32> {
+    pub fn delete(&mut self, pager: &NodePager, key: &[u8]) -> Result<Option<u32>, NodePagerError> {
         if self.is_leaf() {
             // TODO: use binary search
-            if let Some(pos) = self.keys.iter().position(|k| *k == key) {
+            if let Some(pos) = self.keys.iter().position(|k| k.as_slice() == key) {
                 self.keys.remove(pos);
                 let v = self.values.remove(pos);
                 *self.changed.borrow_mut() = true;
-                return Some(v);
+                return Ok(Some(v));
             }
-            return None;
+            return Ok(None);
         }
 
         let node_index = self.keys.iter().enumerate()
-            .find(|(_, k)| key < **k)
+            .find(|(_, k)| key < k.as_slice())
             .map(|(i, _)| i)
             .unwrap_or(self.children.len() - 1);
 
-        let mut target_node = pager.read_page(self.children[node_index]).unwrap();
+        let mut target_node = pager.read_page(self.children[node_index])?;
         // Refactoring: MERGE
         // self.merge(node_index)
-        if target_node.is_less_than_minimal() {
-            
+        if target_node.needs_refill() {
+            // Keys have different sizes, so a rotation is only possible if the rotated keys
+            // fit into the parent and the target node
             let left_neighbor_can_lend = if node_index > 0  {
-                let left = pager.read_page(self.children[node_index - 1]).unwrap();
-                let can_lend = left.can_lend_keys();
+                let left = pager.read_page(self.children[node_index - 1])?;
+                let last = left.keys.len().saturating_sub(1);
+                let can_lend = left.can_lend_key(last)
+                    && self.can_replace_key(node_index - 1, &left.keys[last])
+                    && if target_node.is_leaf() {
+                        target_node.fits(cell_size(&left.keys[last]))
+                    } else {
+                        target_node.fits(cell_size(&self.keys[node_index - 1]))
+                    };
                 Some((left, can_lend))
             } else {
                 None
             };
 
             let right_neighbor_can_lend = if node_index + 1 < self.children.len() {
-                let right = pager.read_page(self.children[node_index + 1]).unwrap();
-                let can_lend = right.can_lend_keys();
+                let right = pager.read_page(self.children[node_index + 1])?;
+                let can_lend = right.can_lend_key(0)
+                    && if target_node.is_leaf() {
+                        // the second key of the right node becomes the new separator
+                        self.can_replace_key(node_index, &right.keys[1])
+                            && target_node.fits(cell_size(&right.keys[0]))
+                    } else {
+                        self.can_replace_key(node_index, &right.keys[0])
+                            && target_node.fits(cell_size(&self.keys[node_index]))
+                    };
                 Some((right, can_lend))
             } else {
                 None
@@ -370,21 +391,20 @@ impl NodePage {
                     let v = left_node.values.pop().unwrap();
                     target_node.keys.insert(0, k);
                     target_node.values.insert(0, v);
-                    self.keys[node_index - 1] = target_node.keys[0];
+                    self.keys[node_index - 1] = target_node.keys[0].clone();
                 } else {
                     let left_key = left_node.keys.pop().unwrap();
                     let left_child = left_node.children.pop().unwrap();
-                    let parent_key = self.keys[node_index - 1];
+                    let parent_key = std::mem::replace(&mut self.keys[node_index - 1], left_key);
                     target_node.keys.insert(0, parent_key);
                     target_node.children.insert(0, left_child);
-                    self.keys[node_index - 1] = left_key;
                 }
                 *target_node.changed.borrow_mut() = true;
                 *left_node.changed.borrow_mut() = true;
                 *self.changed.borrow_mut() = true;
 
-                pager.write_page(&target_node).unwrap();
-                pager.write_page(&left_node).unwrap();
+                pager.write_page(&target_node)?;
+                pager.write_page(&left_node)?;
             } else if let Some((mut right_node, true)) = right_neighbor_can_lend {
                 // There is a right_node and the right node can lend
                 if target_node.is_leaf() {
@@ -392,25 +412,32 @@ impl NodePage {
                     let v = right_node.values.remove(0);
                     target_node.keys.push(k);
                     target_node.values.push(v);
-                    self.keys[node_index] = right_node.keys[0];
+                    self.keys[node_index] = right_node.keys[0].clone();
                 } else {
                     let right_key = right_node.keys.remove(0);
                     let right_child = right_node.children.remove(0);
-                    let parent_key = self.keys[node_index];
+                    let parent_key = std::mem::replace(&mut self.keys[node_index], right_key);
                     target_node.keys.push(parent_key);
                     target_node.children.push(right_child);
-                    self.keys[node_index] = right_key;
                 }
 
                 *target_node.changed.borrow_mut() = true;
                 *right_node.changed.borrow_mut() = true;
                 *self.changed.borrow_mut() = true;
 
-                pager.write_page(&target_node).unwrap();
-                pager.write_page(&right_node).unwrap();
+                pager.write_page(&target_node)?;
+                pager.write_page(&right_node)?;
             } else {
-                // must merge with a sibling:
-                if let Some((mut left_node, _)) = left_neighbor_can_lend {
+                // must merge with a sibling, if the keys of both nodes fit into a single page.
+                // Otherwise the target node stays below the minimum.
+                let left_node = left_neighbor_can_lend
+                    .map(|(left, _)| left)
+                    .filter(|left| left.fits(target_node.merge_size(&self.keys[node_index - 1])));
+                let right_node = right_neighbor_can_lend
+                    .map(|(right, _)| right)
+                    .filter(|right| target_node.fits(right.merge_size(&self.keys[node_index])));
+
+                if let Some(mut left_node) = left_node {
                     // Target node will be deleted and all keys, children, values will be moved to the left node
                     // the left node will then be the new target node
                     let left_index = node_index - 1;
@@ -420,47 +447,59 @@ impl NodePage {
                     // remove left key from parent
                     let separator = self.keys.remove(left_index);
                     if left_node.is_leaf() {
-                        left_node.keys.extend(std::mem::take(&mut target_node.keys).into_iter());
-                        left_node.values.extend(std::mem::take(&mut target_node.values).into_iter());
+                        left_node.keys.extend(std::mem::take(&mut target_node.keys));
+                        left_node.values.extend(std::mem::take(&mut target_node.values));
+                        // target node is removed from the leaf chain
+                        left_node.next_leaf = target_node.next_leaf;
                     } else {
                         left_node.keys.push(separator);
-                        left_node.keys.extend(std::mem::take(&mut target_node.keys).into_iter());
-                        left_node.children.extend(std::mem::take(&mut target_node.children).into_iter());
+                        left_node.keys.extend(std::mem::take(&mut target_node.keys));
+                        left_node.children.extend(std::mem::take(&mut target_node.children));
                     }
                     *left_node.changed.borrow_mut() = true;
                     *self.changed.borrow_mut() = true;
-                    pager.write_page(&left_node).unwrap();
-                    pager.delete_page(*target_node.id()).unwrap();
+                    pager.write_page(&left_node)?;
+                    pager.delete_page(*target_node.id())?;
 
                     // delete must be executed in the left node
                     target_node = left_node;
-                } else if let Some((mut right_node, _)) = right_neighbor_can_lend {
+                } else if let Some(mut right_node) = right_node {
                     // merge target node with the right node and delete the right node completely
                     self.children.remove(node_index + 1);
 
                     let separator = self.keys.remove(node_index);
                     if target_node.is_leaf() {
-                        target_node.keys.extend(std::mem::take(&mut right_node.keys).into_iter());
-                        target_node.values.extend(std::mem::take(&mut right_node.values).into_iter());
+                        target_node.keys.extend(std::mem::take(&mut right_node.keys));
+                        target_node.values.extend(std::mem::take(&mut right_node.values));
+                        // right node is removed from the leaf chain
+                        target_node.next_leaf = right_node.next_leaf;
                     } else {
                         // set parents separator in target_node to match the references to the children
                         target_node.keys.push(separator);
-                        target_node.keys.extend(std::mem::take(&mut right_node.keys).into_iter());
-                        target_node.children.extend(std::mem::take(&mut right_node.children).into_iter());
+                        target_node.keys.extend(std::mem::take(&mut right_node.keys));
+                        target_node.children.extend(std::mem::take(&mut right_node.children));
                     }
 
                     *target_node.changed.borrow_mut() = true;
                     *self.changed.borrow_mut() = true;
-                    pager.write_page(&target_node).unwrap();
-                    pager.delete_page(*right_node.id()).unwrap();
+                    pager.write_page(&target_node)?;
+                    pager.delete_page(*right_node.id())?;
                 }
-                // No need for else-statement, because there should never be another state. Either left or right node must exist.
             }
         }
 
-        let res = target_node.delete(pager, key);
-        pager.write_page(&target_node).unwrap();
+        let res = target_node.delete(pager, key)?;
+        pager.write_page(&target_node)?;
 
-        res
+        Ok(res)
+    }
+
+    // Bytes this node adds to a sibling when merged into it. Internal nodes pull down the separator.
+    fn merge_size(&self, separator: &[u8]) -> usize {
+        if self.is_leaf() {
+            self.used_bytes()
+        } else {
+            self.used_bytes() + cell_size(separator)
+        }
     }
 }
@@ -0,0 +1,404 @@
+use std::{collections::{BTreeMap, BTreeSet}, fs::{self, File}, io::{self, Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::{Arc, Mutex, Weak}};
+
+use crate::page_based_bplustree::{btree_store::{CorruptionError, NodePagerError, page_offset, write_page_image}, wal::write_at};
+
+// Shadow paging:
+// Pages are never overwritten in place. The page ids of the tree are logical, the page table maps them to slots
+// (physical pages) of the store file. A commit writes the changed pages and a new page table to free slots, syncs the file
+// and afterwards writes the header, which points to the new page table. Until the header has been written, the last commit
+// stays intact: a crash in the middle of a commit only leaves unused slots behind. The slots of the replaced pages and of
+// the old page table become free, after the header has been written.
+// There are two copies of the header, which are written alternately (by the sequence number of the commit):
+// at the start of the file and in slot 0, which is reserved for it. A torn header is detected by its checksum,
+// the other copy still describes the previous commit.
+// The page table is a tree of slots, the header points to its root. The leaves hold the slots of the pages
+// (entries_per_slot pages per leaf, in the order of the page ids), the slots above hold the slots of the level below.
+// The shape of the tree follows from the number of pages. A commit copies only the changed leaves and the slots
+// on their paths to the root, the other slots of the table are shared with the previous commit.
+// Slot of the page table:
+// 4 bytes: CRC32 of the rest of the slot
+// 2 bytes: number of entries in this slot
+// 4 bytes per entry: slot of the page (u32::MAX for pages, which have not been committed yet) or of the page table slot below
+// Snapshots pin the page table of a commit: the slots, which are replaced by later commits, are not released,
+// until all snapshots of the earlier commits have been dropped. Only the page table of the store knows the pinned slots,
+// so a store file with snapshots must not be opened for writing again, until they have been dropped (see SNAPSHOT_TABLES).
+
+const POS_CHECKSUM: usize = 0;
+const POS_COUNT: usize = 4;
+const POS_ENTRIES: usize = 6;
+
+const NULL: u32 = u32::MAX;
+
+fn entries_per_slot(page_size: u32) -> usize {
+    (page_size as usize - POS_ENTRIES) / 4
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn table_slot_image(page_size: u32, entries: &[u32]) -> Vec<u8> {
+    let mut data = vec![0; page_size as usize];
+    data[POS_COUNT..POS_COUNT + 2].copy_from_slice(&(entries.len() as u16).to_be_bytes());
+    for (i, slot) in entries.iter().enumerate() {
+        data[POS_ENTRIES + i * 4..POS_ENTRIES + i * 4 + 4].copy_from_slice(&slot.to_be_bytes());
+    }
+    let checksum = crc32fast::hash(&data[POS_COUNT..]);
+    data[POS_CHECKSUM..POS_CHECKSUM + 4].copy_from_slice(&checksum.to_be_bytes());
+    data
+}
+
+// Reads a slot of the page table, which must have count entries
+fn read_table_slot(file: &mut File, page_size: u32, slot: u32, count: usize) -> Result<Vec<u32>, NodePagerError> {
+    let mut data = vec![0; page_size as usize];
+    file.seek(SeekFrom::Start(page_offset(page_size, slot)))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(|source| NodePagerError::Io { context: format!("Cannot read the page table in slot {}", slot), source })?;
+
+    let stored_count = u16::from_be_bytes([data[POS_COUNT], data[POS_COUNT + 1]]) as usize;
+    if read_u32(&data, POS_CHECKSUM) != crc32fast::hash(&data[POS_COUNT..]) || stored_count != count {
+        return Err(CorruptionError::BrokenPageTable.into());
+    }
+    Ok((0..count).map(|i| read_u32(&data, POS_ENTRIES + i * 4)).collect())
+}
+
+// Number of entries of every level of the page table for number_of_pages pages, from the leaves to the root
+fn level_sizes(page_size: u32, number_of_pages: usize) -> Vec<usize> {
+    let mut sizes = vec![number_of_pages];
+    while *sizes.last().unwrap() > entries_per_slot(page_size) {
+        sizes.push(sizes.last().unwrap().div_ceil(entries_per_slot(page_size)));
+    }
+    sizes
+}
+
+#[derive(Debug, Clone)]
+pub struct PageTable {
+    slots: Vec<u32>, // slot of every page
+    table_slots: Vec<Vec<u32>>, // slots of the page table itself, per level from the leaves to the root
+    free_slots: BTreeSet<u32>,
+    number_of_slots: u32, // in the file, including slot 0
+    sequence: u64, // of the last commit
+    snapshots: BTreeMap<u64, usize>, // number of live snapshots per sequence
+    retained: Vec<(u64, Vec<u32>)>, // slots replaced by the commit with the sequence, which are still used by snapshots
+}
+
+impl PageTable {
+    fn new() -> Self {
+        PageTable {
+            slots: Vec::new(),
+            table_slots: Vec::new(),
+            free_slots: BTreeSet::new(),
+            number_of_slots: 1,
+            sequence: 0,
+            snapshots: BTreeMap::new(),
+            retained: Vec::new(),
+        }
+    }
+
+    // Reads the page table, whose root is in root_slot (see the header). Slots, which are neither used by a page
+    // nor by the page table, are free.
+    pub fn load(file: &mut File, page_size: u32, root_slot: Option<u32>, number_of_pages: u32, sequence: u64) -> Result<Self, NodePagerError> {
+        let mut table = PageTable { sequence, ..PageTable::new() };
+        let sizes = level_sizes(page_size, number_of_pages as usize);
+        let per_slot = entries_per_slot(page_size);
+
+        // from the root down to the leaves, the entries of a level are the slots of the level below
+        let mut slots = match root_slot {
+            Some(root_slot) if number_of_pages > 0 => vec![root_slot],
+            None if number_of_pages == 0 => Vec::new(),
+            _ => return Err(CorruptionError::BrokenPageTable.into()),
+        };
+        let mut levels = Vec::new();
+        for size in sizes.iter().rev() {
+            let mut entries = Vec::with_capacity(*size);
+            for (i, slot) in slots.iter().enumerate() {
+                entries.extend(read_table_slot(file, page_size, *slot, per_slot.min(size - i * per_slot))?);
+            }
+            levels.push(std::mem::replace(&mut slots, entries));
+        }
+        levels.reverse();
+        table.slots = slots;
+        table.table_slots = levels;
+
+        let file_size = file.metadata()
+            .map_err(|source| NodePagerError::Io { context: "Cannot read the size of the store file".to_owned(), source })?
+            .len();
+        let used: BTreeSet<u32> = table.slots.iter().chain(table.table_slots.iter().flatten()).copied().filter(|slot| *slot != NULL).collect();
+        let slots_in_file = file_size.saturating_sub(page_offset(page_size, 0)).div_ceil(page_size as u64);
+        table.number_of_slots = used.last().map_or(1, |slot| slot + 1).max(slots_in_file.min(NULL as u64) as u32).max(1);
+        table.free_slots = (1..table.number_of_slots).filter(|slot| !used.contains(slot)).collect();
+
+        Ok(table)
+    }
+
+    // The slot of a committed page
+    pub fn slot(&self, page_id: u32) -> Option<u32> {
+        self.slots.get(page_id as usize).copied().filter(|slot| *slot != NULL)
+    }
+
+    pub fn root_slot(&self) -> Option<u32> {
+        self.table_slots.last().and_then(|root| root.first()).copied()
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn allocate(&mut self) -> u32 {
+        match self.free_slots.pop_first() {
+            Some(slot) => slot,
+            None => {
+                self.number_of_slots += 1;
+                self.number_of_slots - 1
+            },
+        }
+    }
+
+    // Writes the pages and the page table to free slots. Returns the page table of the next commit and the slots,
+    // which are still used by the current header. They are released, after the header of the next commit has been written.
+    pub fn write(&self, file: &mut File, page_size: u32, pages: &BTreeMap<u32, Vec<u8>>, number_of_pages: u32) -> io::Result<(PageTable, Vec<u32>)> {
+        let mut next = self.clone();
+        next.sequence += 1;
+        next.slots.resize(number_of_pages as usize, NULL);
+
+        let mut replaced = Vec::new();
+        for (page_id, page) in pages {
+            let slot = next.allocate();
+            write_page_image(file, page_size, slot, page)?;
+            let previous = std::mem::replace(&mut next.slots[*page_id as usize], slot);
+            if previous != NULL {
+                replaced.push(previous);
+            }
+        }
+
+        // copy on write: the changed slots of a level are written to free slots, which changes the entries of the level above
+        let per_slot = entries_per_slot(page_size);
+        let sizes = level_sizes(page_size, next.slots.len());
+        let mut changed: BTreeSet<usize> = pages.keys().map(|page_id| *page_id as usize).collect();
+        for (level, size) in sizes.iter().copied().enumerate() {
+            let previous_size = match level {
+                0 => self.slots.len(),
+                _ => self.table_slots.get(level - 1).map_or(0, Vec::len),
+            };
+            if next.table_slots.len() == level {
+                next.table_slots.push(Vec::new());
+            }
+            let number_of_slots = size.div_ceil(per_slot);
+            next.table_slots[level].resize(number_of_slots, NULL);
+
+            // a slot changes with its entries and with the number of entries of the level (only the last slots)
+            let mut changed_slots: BTreeSet<usize> = changed.iter().map(|entry| entry / per_slot).collect();
+            if size != previous_size {
+                changed_slots.extend(previous_size.min(size) / per_slot..number_of_slots);
+            }
+            for i in &changed_slots {
+                let slot = next.allocate();
+                let entries = match level {
+                    0 => &next.slots,
+                    _ => &next.table_slots[level - 1],
+                };
+                write_at(file, page_offset(page_size, slot), &table_slot_image(page_size, &entries[i * per_slot..size.min((i + 1) * per_slot)]))?;
+                let previous = std::mem::replace(&mut next.table_slots[level][*i], slot);
+                if previous != NULL {
+                    replaced.push(previous);
+                }
+            }
+            changed = changed_slots;
+        }
+        replaced.extend(next.table_slots.drain(sizes.len()..).flatten().filter(|slot| *slot != NULL));
+
+        Ok((next, replaced))
+    }
+
+    // The header of the commit has been written. Snapshots of earlier commits keep the slots.
+    pub fn release(&mut self, slots: Vec<u32>) {
+        self.retained.push((self.sequence, slots));
+        self.release_retained();
+    }
+
+    // The slots of the commits after the oldest snapshot are still needed
+    fn release_retained(&mut self) {
+        let oldest = self.snapshots.keys().next().copied().unwrap_or(u64::MAX);
+        let (released, retained): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retained).into_iter()
+            .partition(|(sequence, _)| *sequence <= oldest);
+        self.retained = retained;
+        self.free_slots.extend(released.into_iter().flat_map(|(_, slots)| slots));
+    }
+
+    // Both copies of the header are written alternately
+    pub fn header_offset(&self, page_size: u32) -> u64 {
+        match self.sequence % 2 {
+            0 => 0,
+            _ => page_offset(page_size, 0),
+        }
+    }
+}
+
+// The page table of a commit, its slots are not reused while it is alive
+#[derive(Debug)]
+pub struct PinnedPageTable {
+    page_table: Arc<Mutex<PageTable>>,
+    sequence: u64,
+    slots: Vec<u32>,
+}
+
+impl PinnedPageTable {
+    // Pins the last commit
+    pub fn new(page_table: Arc<Mutex<PageTable>>) -> Self {
+        let mut table = page_table.lock().unwrap();
+        let sequence = table.sequence;
+        *table.snapshots.entry(sequence).or_default() += 1;
+        let slots = table.slots.clone();
+        drop(table);
+
+        PinnedPageTable { page_table, sequence, slots }
+    }
+
+    pub fn slot(&self, page_id: u32) -> Option<u32> {
+        self.slots.get(page_id as usize).copied().filter(|slot| *slot != NULL)
+    }
+
+}
+
+// Page tables with snapshots by the path of their store file. Snapshots can outlive their store, a new page table
+// loaded from the file would reuse the pinned slots.
+static SNAPSHOT_TABLES: Mutex<Vec<(PathBuf, Weak<Mutex<PageTable>>)>> = Mutex::new(Vec::new());
+
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The page table of the store file at path pins the slots of snapshots
+pub fn register_snapshot_table(path: &Path, page_table: &Arc<Mutex<PageTable>>) {
+    let path = canonical_path(path);
+    let mut tables = SNAPSHOT_TABLES.lock().unwrap();
+    tables.retain(|(_, table)| table.strong_count() > 0);
+    if !tables.iter().any(|(table_path, table)| *table_path == path && table.ptr_eq(&Arc::downgrade(page_table))) {
+        tables.push((path, Arc::downgrade(page_table)));
+    }
+}
+
+// Whether snapshots of the store file at path are alive
+pub fn has_snapshots(path: &Path) -> bool {
+    let path = canonical_path(path);
+    SNAPSHOT_TABLES.lock().unwrap().iter()
+        .filter(|(table_path, _)| *table_path == path)
+        .filter_map(|(_, table)| table.upgrade())
+        .any(|table| !table.lock().unwrap().snapshots.is_empty())
+}
+
+impl Drop for PinnedPageTable {
+    fn drop(&mut self) {
+        let mut table = self.page_table.lock().unwrap();
+        if let Some(count) = table.snapshots.get_mut(&self.sequence) {
+            *count -= 1;
+            if *count == 0 {
+                table.snapshots.remove(&self.sequence);
+            }
+        }
+        table.release_retained();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::{Arc, Mutex}};
+
+    use tempfile::NamedTempFile;
+
+    use super::{PageTable, PinnedPageTable};
+
+    const PAGE_SIZE: u32 = 73;
+
+    #[test]
+    fn page_table_survives_reload() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut file = temp.reopen().unwrap();
+        let pages: BTreeMap<u32, Vec<u8>> = (0..40).map(|page_id| (page_id, vec![page_id as u8; PAGE_SIZE as usize])).collect();
+        let (table, replaced) = PageTable::new().write(&mut file, PAGE_SIZE, &pages, 40).unwrap();
+        assert!(replaced.is_empty());
+        // 40 entries need 3 slots of 16 entries and a root, slot 0 is reserved
+        assert_eq!(table.root_slot(), Some(44));
+        assert!((0..40).all(|page_id| table.slot(page_id) == Some(page_id + 1)));
+
+        // only the changed pages and the slots of the table, which point to them, move, the old slots are free after the release
+        let changed: BTreeMap<u32, Vec<u8>> = [(3, vec![0; PAGE_SIZE as usize]), (40, vec![0; PAGE_SIZE as usize])].into();
+        let (mut next, replaced) = table.write(&mut file, PAGE_SIZE, &changed, 41).unwrap();
+        assert_eq!(replaced, vec![4, 41, 43, 44]);
+        assert_eq!(next.sequence(), 2);
+        next.release(replaced);
+        assert_eq!(next.slot(3), Some(45));
+        assert_eq!(next.slot(40), Some(46));
+        assert_eq!(next.slot(41), None);
+        assert_eq!(next.table_slots, vec![vec![47, 42, 48], vec![49]]);
+
+        let loaded = PageTable::load(&mut file, PAGE_SIZE, next.root_slot(), 41, 2).unwrap();
+        assert_eq!(loaded.slots, next.slots);
+        assert_eq!(loaded.table_slots, next.table_slots);
+        assert_eq!(loaded.free_slots, next.free_slots);
+        assert_eq!(loaded.number_of_slots, next.number_of_slots);
+        assert!(PageTable::load(&mut file, PAGE_SIZE, next.root_slot(), 40, 2).is_err());
+        assert!(PageTable::load(&mut file, PAGE_SIZE, None, 41, 2).is_err());
+    }
+
+    #[test]
+    fn commits_write_only_the_changed_path_of_the_table() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut file = temp.reopen().unwrap();
+        let pages: BTreeMap<u32, Vec<u8>> = (0..1000).map(|page_id| (page_id, vec![0; PAGE_SIZE as usize])).collect();
+        let (mut table, _) = PageTable::new().write(&mut file, PAGE_SIZE, &pages, 1000).unwrap();
+        // 1000 entries need 63 slots, which need 4 slots, which need the root
+        assert_eq!(table.table_slots.iter().map(Vec::len).collect::<Vec<_>>(), vec![63, 4, 1]);
+
+        for page_id in [0, 500, 999] {
+            let (mut next, replaced) = table.write(&mut file, PAGE_SIZE, &[(page_id, vec![1; PAGE_SIZE as usize])].into(), 1000).unwrap();
+            // the page and one slot per level of the table
+            assert_eq!(replaced.len(), 4);
+            next.release(replaced);
+            table = next;
+        }
+        let loaded = PageTable::load(&mut file, PAGE_SIZE, table.root_slot(), 1000, 4).unwrap();
+        assert_eq!(loaded.slots, table.slots);
+        assert_eq!(loaded.free_slots, table.free_slots);
+    }
+
+    #[test]
+    fn pinned_slots_are_released_after_the_last_snapshot() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut file = temp.reopen().unwrap();
+        let page = |page_id: u32| [(page_id, vec![0; PAGE_SIZE as usize])].into();
+        let commit = |table: &Arc<Mutex<PageTable>>, file: &mut std::fs::File, pages: &BTreeMap<u32, Vec<u8>>| {
+            let mut table = table.lock().unwrap();
+            let (mut next, replaced) = table.write(file, PAGE_SIZE, pages, 2).unwrap();
+            next.release(replaced);
+            *table = next;
+        };
+
+        let table = Arc::new(Mutex::new(PageTable::new()));
+        commit(&table, &mut file, &[(0, vec![0; PAGE_SIZE as usize]), (1, vec![0; PAGE_SIZE as usize])].into());
+        let first = PinnedPageTable::new(Arc::clone(&table));
+        commit(&table, &mut file, &page(0));
+        let second = PinnedPageTable::new(Arc::clone(&table));
+        commit(&table, &mut file, &page(0));
+        // both snapshots keep their slots
+        assert!(table.lock().unwrap().free_slots.is_empty());
+        assert_eq!(first.slot(1), second.slot(1));
+        assert_ne!(first.slot(0), second.slot(0));
+
+        // the second snapshot still needs the slots of the first commit, which have been replaced by the third one
+        let slot_of_first = first.slot(0).unwrap();
+        drop(first);
+        let free_slots = table.lock().unwrap().free_slots.clone();
+        assert!(free_slots.contains(&slot_of_first));
+        assert!(!free_slots.contains(&second.slot(0).unwrap()));
+
+        drop(second);
+        commit(&table, &mut file, &page(1));
+        let table = table.lock().unwrap();
+        assert!(table.snapshots.is_empty());
+        assert!(table.retained.is_empty());
+        // slot 0 is reserved for the header, the rest is either used or free
+        let used = table.slots.len() + table.table_slots.iter().map(Vec::len).sum::<usize>();
+        assert_eq!(used + table.free_slots.len(), table.number_of_slots as usize - 1);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{borrow, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+
+use crate::page_based_bplustree::{btree_store::{BTreeStore, BTreeStoreError}, key::StoreKey};
+
+// A BTreeStore, which can be shared between threads: any number of readers or a single writer.
+// Readers share the page cache of the store, a writer has exclusive access to the store for the whole operation,
+// so readers never see a half-applied insert or delete. Several operations can be done under one lock by read() / write().
+pub struct SharedBTreeStore<K = u32> {
+    store: Arc<RwLock<BTreeStore<K>>>,
+}
+
+impl<K> Clone for SharedBTreeStore<K> {
+    fn clone(&self) -> Self {
+        SharedBTreeStore { store: Arc::clone(&self.store) }
+    }
+}
+
+impl<K: StoreKey> SharedBTreeStore<K> {
+    pub fn new(store: BTreeStore<K>) -> Self {
+        SharedBTreeStore { store: Arc::new(RwLock::new(store)) }
+    }
+
+    // Blocks, while a writer holds the store
+    pub fn read(&self) -> RwLockReadGuard<'_, BTreeStore<K>> {
+        self.store.read().unwrap()
+    }
+
+    // Blocks, while readers or another writer hold the store
+    pub fn write(&self) -> RwLockWriteGuard<'_, BTreeStore<K>> {
+        self.store.write().unwrap()
+    }
+
+    pub fn find(&self, key: impl borrow::Borrow<K>) -> Result<Option<u32>, BTreeStoreError> {
+        self.read().find(key)
+    }
+
+    pub fn insert(&self, key: impl borrow::Borrow<K>, value: u32) -> Result<Option<u32>, BTreeStoreError> {
+        self.write().insert(key, value)
+    }
+
+    pub fn delete(&self, key: impl borrow::Borrow<K>) -> Result<Option<u32>, BTreeStoreError> {
+        self.write().delete(key)
+    }
+
+    pub fn checkpoint(&self) -> Result<(), BTreeStoreError> {
+        self.read().checkpoint()
+    }
+
+    pub fn sync(&self) -> Result<(), BTreeStoreError> {
+        self.read().sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::{Arc, atomic::{AtomicBool, Ordering}}, thread};
+
+    use tempfile::NamedTempFile;
+
+    use crate::page_based_bplustree::btree_store::BTreeStore;
+
+    use super::SharedBTreeStore;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn stores_can_be_shared_between_threads() {
+        assert_send_sync::<BTreeStore>();
+        assert_send_sync::<BTreeStore<String>>();
+        assert_send_sync::<SharedBTreeStore>();
+    }
+
+    #[test]
+    fn parallel_finds_during_inserts_and_deletes() {
+        let temp = NamedTempFile::new().unwrap();
+        // a small cache, so that the readers evict pages, which have been written by the writer
+        let store = SharedBTreeStore::new(BTreeStore::open_with_cache(temp.path(), 4, 8).unwrap());
+        // every key, which is in the store, has the value key * 2
+        for key in (0..1000u32).step_by(2) {
+            store.insert(key, key * 2).unwrap();
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4u32).map(|reader| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut finds = 0;
+                let mut key = reader;
+                while !done.load(Ordering::Relaxed) || finds < 1000 {
+                    key = (key * 7 + 13) % 1000;
+                    if let Some(value) = store.find(key).unwrap() {
+                        assert_eq!(value, key * 2);
+                    }
+                    // a range sees a consistent tree: sorted keys with their values
+                    let guard = store.read();
+                    let entries: Vec<_> = guard.range(key..key + 20).unwrap().map(|entry| entry.unwrap()).collect();
+                    assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+                    assert!(entries.iter().all(|(key, value)| *value == key * 2));
+                    finds += 1;
+                }
+            })
+        }).collect();
+
+        let mut expected: BTreeMap<u32, u32> = (0..1000u32).step_by(2).map(|key| (key, key * 2)).collect();
+        let writer = {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..3000u32 {
+                    let key = (i * 31) % 1000;
+                    if i % 3 == 0 {
+                        assert_eq!(store.delete(key).unwrap(), expected.remove(&key));
+                    } else {
+                        assert_eq!(store.insert(key, key * 2).unwrap(), expected.insert(key, key * 2));
+                    }
+                }
+                expected
+            })
+        };
+
+        let expected = writer.join().unwrap();
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let guard = store.read();
+        let entries: Vec<_> = guard.range(..).unwrap().map(|entry| entry.unwrap()).collect();
+        assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+        assert!(guard.check().is_ok());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{borrow, ops::RangeBounds};
+
+use crate::page_based_bplustree::{btree_store::{BTreeStore, BTreeStoreError, Range, StoreMetaData}, check::CheckReport, key::StoreKey};
+
+// Read-only view of a shadow paged BTreeStore, pinned to the commit at the time of BTreeStore::snapshot.
+// It reads the store file with its own handle and cache, so it can outlive borrows of the store and be sent to other threads.
+// It can even outlive the store: opening the store file again fails with BTreeStoreError::SnapshotsAlive (read-only opens
+// excepted), until the snapshot has been dropped, as the new store would reuse the slots of the snapshot.
+pub struct Snapshot<K = u32> {
+    store: BTreeStore<K>,
+}
+
+impl<K: StoreKey> Snapshot<K> {
+    pub(crate) fn new(store: BTreeStore<K>) -> Self {
+        Snapshot { store }
+    }
+
+    // The sequence of the pinned commit
+    pub fn sequence(&self) -> u64 {
+        *self.store.meta_data().sequence()
+    }
+
+    // The header of the pinned commit
+    pub fn meta_data(&self) -> StoreMetaData {
+        self.store.meta_data()
+    }
+
+    pub fn find(&self, key: impl borrow::Borrow<K>) -> Result<Option<u32>, BTreeStoreError> {
+        self.store.find(key)
+    }
+
+    pub fn find_all(&self, key: impl borrow::Borrow<K>) -> Result<impl Iterator<Item = Result<u32, BTreeStoreError>> + '_, BTreeStoreError> {
+        self.store.find_all(key)
+    }
+
+    pub fn range(&self, range: impl RangeBounds<K>) -> Result<Range<'_, K>, BTreeStoreError> {
+        self.store.range(range)
+    }
+
+    pub fn check(&self) -> CheckReport {
+        self.store.check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, thread};
+
+    use tempfile::NamedTempFile;
+
+    use crate::page_based_bplustree::btree_store::{BTreeStore, BTreeStoreError, DEFAULT_CACHE_PAGES, IndexMode, PagerMode};
+
+    use super::Snapshot;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn entries(snapshot: &Snapshot) -> Vec<(u32, u32)> {
+        snapshot.range(..).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn snapshots_keep_their_commit() {
+        assert_send_sync::<Snapshot>();
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::open_shadow_paged(temp.path(), 4).unwrap();
+        let mut expected = BTreeMap::new();
+        for key in 0..200 {
+            btree.insert(key, key).unwrap();
+            expected.insert(key, key);
+        }
+
+        let snapshot = btree.snapshot().unwrap();
+        assert_eq!(snapshot.sequence(), *btree.meta_data().sequence());
+        // overwrite, delete and insert, so that most pages of the snapshot are replaced
+        for key in 0..200 {
+            match key % 3 {
+                0 => btree.insert(key, key * 10).map(|_| ()).unwrap(),
+                1 => btree.delete(key).map(|_| ()).unwrap(),
+                _ => btree.insert(key + 1000, key).map(|_| ()).unwrap(),
+            }
+        }
+
+        assert_eq!(entries(&snapshot), expected.iter().map(|(key, value)| (*key, *value)).collect::<Vec<_>>());
+        assert_eq!(snapshot.find(3).unwrap(), Some(3));
+        assert_eq!(snapshot.find(4).unwrap(), Some(4));
+        assert_eq!(snapshot.find(1002).unwrap(), None);
+        assert!(snapshot.check().is_ok());
+        assert_eq!(btree.find(3).unwrap(), Some(30));
+        assert_eq!(btree.find(4).unwrap(), None);
+        assert_eq!(btree.find(1002).unwrap(), Some(2));
+
+        // snapshots can be read by other threads, while the store goes on
+        let reader = thread::spawn(move || entries(&snapshot).len());
+        btree.insert(5000, 0).unwrap();
+        assert_eq!(reader.join().unwrap(), 200);
+        assert!(btree.check().is_ok());
+    }
+
+    #[test]
+    fn slots_are_reused_after_the_snapshot_has_been_dropped() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::open_shadow_paged(temp.path(), 4).unwrap();
+        for key in 0..50 {
+            btree.insert(key, key).unwrap();
+        }
+        let overwrite_all = |btree: &mut BTreeStore, value: u32| {
+            for key in 0..50 {
+                btree.insert(key, value).unwrap();
+            }
+        };
+        let file_size = || fs::metadata(temp.path()).unwrap().len();
+        // the slots of the replaced pages are reused
+        overwrite_all(&mut btree, 1);
+        let size_without_snapshot = file_size();
+        overwrite_all(&mut btree, 2);
+        assert_eq!(file_size(), size_without_snapshot);
+
+        // the slots of the snapshot are kept, the store file grows
+        let snapshot = btree.snapshot().unwrap();
+        overwrite_all(&mut btree, 3);
+        overwrite_all(&mut btree, 4);
+        let size_with_snapshot = file_size();
+        assert!(size_with_snapshot > size_without_snapshot);
+        assert!(snapshot.range(..).unwrap().all(|entry| entry.unwrap().1 == 2));
+
+        drop(snapshot);
+        overwrite_all(&mut btree, 5);
+        overwrite_all(&mut btree, 6);
+        assert_eq!(file_size(), size_with_snapshot);
+        assert!(btree.range(..).unwrap().all(|entry| entry.unwrap().1 == 6));
+    }
+
+    #[test]
+    fn snapshots_of_multimaps_and_empty_stores() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::<u32>::open_with_pager_mode(temp.path(), 8, IndexMode::NonUnique, PagerMode::Shadow, DEFAULT_CACHE_PAGES).unwrap();
+        let empty = btree.snapshot().unwrap();
+        for value in 0..20 {
+            btree.insert(7, value).unwrap();
+        }
+        let snapshot = btree.snapshot().unwrap();
+        btree.delete_one(7, 3).unwrap();
+
+        assert_eq!(empty.find(7).unwrap(), None);
+        assert_eq!(empty.range(..).unwrap().count(), 0);
+        assert_eq!(snapshot.find_all(7).unwrap().map(Result::unwrap).collect::<Vec<_>>(), (0..20).collect::<Vec<_>>());
+        assert_eq!(btree.find_all(7).unwrap().count(), 19);
+    }
+
+    #[test]
+    fn stores_with_snapshots_are_not_reopened() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut btree = BTreeStore::open_shadow_paged(temp.path(), 4).unwrap();
+        for key in 0..50 {
+            btree.insert(key, key).unwrap();
+        }
+        let snapshot = btree.snapshot().unwrap();
+        drop(btree);
+
+        // a new store would reuse the slots of the snapshot
+        assert!(matches!(BTreeStore::<u32>::open_existing(temp.path()), Err(BTreeStoreError::SnapshotsAlive)));
+        let read_only = BTreeStore::<u32>::open_read_only(temp.path()).unwrap();
+        assert_eq!(read_only.range(..).unwrap().count(), 50);
+        assert_eq!(snapshot.range(..).unwrap().count(), 50);
+
+        drop(snapshot);
+        let mut btree = BTreeStore::<u32>::open_existing(temp.path()).unwrap();
+        btree.insert(100, 100).unwrap();
+        assert!(btree.check().is_ok());
+    }
+
+    #[test]
+    fn wal_stores_have_no_snapshots() {
+        let temp = NamedTempFile::new().unwrap();
+        let btree = BTreeStore::new(temp.path(), 4).unwrap();
+        assert!(matches!(btree.snapshot(), Err(BTreeStoreError::Incompatible { .. })));
+    }
+}
@@ -0,0 +1,274 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{Mutex, atomic::{AtomicU64, Ordering}}};
+
+use thiserror::Error;
+
+// WAL file design:
+// The WAL is stored next to the store file (<store file>-wal) and contains one record per operation.
+// Record:
+// 4 bytes: number of pages
+// 4 bytes: page size
+// 4 bytes: size of the meta data header
+// per page:
+//   4 bytes: page_id
+//   page size bytes: page image
+// meta data header
+// 4 bytes: CRC32 of all previous bytes of the record
+// A record with an invalid checksum has been torn by a crash, it and everything behind it is ignored.
+const RECORD_HEADER_SIZE: usize = 12;
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq)]
+pub struct WalRecord {
+    pub page_size: u32,
+    pub pages: Vec<(u32, Vec<u8>)>,
+    pub meta_data: Vec<u8>,
+}
+
+impl WalRecord {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.pages.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.page_size.to_be_bytes());
+        bytes.extend_from_slice(&(self.meta_data.len() as u32).to_be_bytes());
+        for (page_id, page) in &self.pages {
+            bytes.extend_from_slice(&page_id.to_be_bytes());
+            bytes.extend_from_slice(page);
+        }
+        bytes.extend_from_slice(&self.meta_data);
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_be_bytes());
+        bytes
+    }
+
+    // Reads the record at the start of bytes. Returns the record and its length, None if the record is incomplete.
+    fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        let read_u32 = |offset: usize| bytes.get(offset..offset + 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+
+        let number_of_pages = read_u32(0)? as usize;
+        let page_size = read_u32(4)?;
+        let meta_data_size = read_u32(8)? as usize;
+
+        let pages_size = number_of_pages.checked_mul(4 + page_size as usize)?;
+        let checksum_offset = RECORD_HEADER_SIZE.checked_add(pages_size)?.checked_add(meta_data_size)?;
+        let checksum = read_u32(checksum_offset)?;
+        if crc32fast::hash(&bytes[..checksum_offset]) != checksum {
+            return None;
+        }
+
+        let mut offset = RECORD_HEADER_SIZE;
+        let mut pages = Vec::with_capacity(number_of_pages);
+        for _ in 0..number_of_pages {
+            let page_id = read_u32(offset)?;
+            offset += 4;
+            pages.push((page_id, bytes[offset..offset + page_size as usize].to_vec()));
+            offset += page_size as usize;
+        }
+        let meta_data = bytes[offset..offset + meta_data_size].to_vec();
+
+        Some((WalRecord { page_size, pages, meta_data }, checksum_offset + CHECKSUM_SIZE))
+    }
+}
+
+pub struct Wal {
+    path: PathBuf,
+    file: Mutex<File>,
+    end: AtomicU64, // end of the last complete record, a torn record behind it is overwritten. Only changed while the file is locked.
+}
+
+#[derive(Debug, Error)]
+#[error("WAL error: {msg}: {source}")]
+pub struct WalError {
+    msg: String,
+    #[source]
+    source: io::Error,
+}
+
+impl Wal {
+    pub fn path(store_path: &Path) -> PathBuf {
+        let mut path = store_path.as_os_str().to_owned();
+        path.push("-wal");
+        PathBuf::from(path)
+    }
+
+    pub fn open(path: &Path) -> Result<Self, WalError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|source| WalError { msg: format!("Cannot open WAL {}", path.display()), source })?;
+
+        let wal = Wal {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            end: AtomicU64::new(0),
+        };
+        let end = wal.records()?.1;
+        wal.end.store(end, Ordering::Relaxed);
+
+        Ok(wal)
+    }
+
+    // Appends the record. It survives a crash of the operating system only, if the WAL is synced afterwards.
+    pub fn append(&self, record: &WalRecord) -> Result<(), WalError> {
+        let bytes = record.to_bytes();
+        let mut file = self.file.lock().unwrap();
+        write_at(&mut file, self.end.load(Ordering::Relaxed), &bytes)
+            .map_err(|source| WalError { msg: "Cannot append WAL record".to_owned(), source })?;
+        self.end.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<(), WalError> {
+        self.file.lock().unwrap().sync_data()
+            .map_err(|source| WalError { msg: "Cannot sync WAL".to_owned(), source })
+    }
+
+    // Returns all complete records and the end of the last one
+    pub fn records(&self) -> Result<(Vec<WalRecord>, u64), WalError> {
+        let mut file = self.file.lock().unwrap();
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_to_end(&mut bytes))
+            .map_err(|source| WalError { msg: "Cannot read WAL".to_owned(), source })?;
+
+        Ok(Self::parse_records(&bytes))
+    }
+
+    // Returns all complete records of the WAL at path without opening it for writing, none if there is no WAL
+    pub fn read_records(path: &Path) -> Result<Vec<WalRecord>, WalError> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(Self::parse_records(&bytes).0),
+            Err(source) if source.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(source) => Err(WalError { msg: format!("Cannot read WAL {}", path.display()), source }),
+        }
+    }
+
+    fn parse_records(bytes: &[u8]) -> (Vec<WalRecord>, u64) {
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Some((record, length)) = WalRecord::from_bytes(&bytes[offset..]) {
+            records.push(record);
+            offset += length;
+        }
+
+        (records, offset as u64)
+    }
+
+    // Removes all records, must only be called after the records have been synced to the store file
+    pub fn clear(&self) -> Result<(), WalError> {
+        let file = self.file.lock().unwrap();
+        file.set_len(0)
+            .and_then(|_| file.sync_data())
+            .map_err(|source| WalError { msg: "Cannot truncate WAL".to_owned(), source })?;
+        self.end.store(0, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end.load(Ordering::Relaxed) == 0
+    }
+
+    // Removes the WAL file
+    pub fn remove(&self) -> Result<(), WalError> {
+        fs::remove_file(&self.path)
+            .map_err(|source| WalError { msg: format!("Cannot remove WAL {}", self.path.display()), source })
+    }
+}
+
+// All writes of the store go through this function, so that tests can simulate a crash in the middle of a write.
+pub fn write_at(file: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    #[cfg(test)]
+    if let Some(written) = crash::consume(data.len()) {
+        file.write_all(&data[..written])?;
+        return Err(io::Error::other("simulated crash"));
+    }
+
+    file.write_all(data)
+}
+
+#[cfg(test)]
+pub mod crash {
+    use std::cell::Cell;
+
+    thread_local! {
+        static WRITE_BUDGET: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    // After budget bytes have been written, every write fails (the first one is torn).
+    // None disables the simulation.
+    pub fn set_write_budget(budget: Option<usize>) {
+        WRITE_BUDGET.set(budget);
+    }
+
+    // Returns the number of bytes which can be written, if the write exceeds the budget
+    pub(super) fn consume(len: usize) -> Option<usize> {
+        let budget = WRITE_BUDGET.get()?;
+        if len > budget {
+            WRITE_BUDGET.set(Some(0));
+            Some(budget)
+        } else {
+            WRITE_BUDGET.set(Some(budget - len));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use tempfile::NamedTempFile;
+
+    use super::{Wal, WalRecord};
+
+    fn record(page_id: u32, fill: u8) -> WalRecord {
+        WalRecord {
+            page_size: 8,
+            pages: vec![(page_id, vec![fill; 8]), (page_id + 1, vec![fill + 1; 8])],
+            meta_data: vec![fill; 14],
+        }
+    }
+
+    #[test]
+    fn append_and_read_records() {
+        let temp = NamedTempFile::new().unwrap();
+        let wal = Wal::open(temp.path()).unwrap();
+        assert!(wal.is_empty());
+        wal.append(&record(0, 1)).unwrap();
+        wal.append(&record(5, 7)).unwrap();
+
+        // records survive reopening
+        let wal = Wal::open(temp.path()).unwrap();
+        let (records, _) = wal.records().unwrap();
+        assert_eq!(records, vec![record(0, 1), record(5, 7)]);
+
+        wal.clear().unwrap();
+        assert!(wal.is_empty());
+        assert!(wal.records().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn torn_record_is_ignored_and_overwritten() {
+        let temp = NamedTempFile::new().unwrap();
+        let wal = Wal::open(temp.path()).unwrap();
+        wal.append(&record(0, 1)).unwrap();
+        wal.append(&record(2, 3)).unwrap();
+        let (_, end) = wal.records().unwrap();
+
+        // cut the last record in the middle
+        let file = OpenOptions::new().write(true).open(temp.path()).unwrap();
+        file.set_len(end - 10).unwrap();
+
+        let wal = Wal::open(temp.path()).unwrap();
+        assert_eq!(wal.records().unwrap().0, vec![record(0, 1)]);
+
+        // the next record replaces the torn one
+        wal.append(&record(4, 5)).unwrap();
+        assert_eq!(wal.records().unwrap().0, vec![record(0, 1), record(4, 5)]);
+    }
+}
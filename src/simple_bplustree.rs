@@ -1,4 +1,6 @@
-use std::{collections::VecDeque, mem};
+use std::{collections::VecDeque, iter::FusedIterator, mem, ops::{Bound, RangeBounds}};
+
+use thiserror::Error;
 
 enum FindKeyResponse {
     GreaterThanTheLast(usize),
@@ -7,15 +9,15 @@ enum FindKeyResponse {
 }
 
 #[derive(Debug)]
-struct Node<V> {
+struct Node<K, V> {
     values: Vec<V>,
-    keys: Vec<u32>,
-    children: Vec<Node<V>>,
+    keys: Vec<K>,
+    children: Vec<Node<K, V>>,
     max_degree: usize,
     root: bool,
 }
 
-impl<V> Node<V> {
+impl<K, V> Node<K, V> {
     pub fn new(max_degree: usize) -> Self {
         Self {
             values: Vec::new(),
@@ -27,7 +29,40 @@ impl<V> Node<V> {
     }
 }
 
-impl<V: std::fmt::Debug> Node<V> {
+impl<K, V> Node<K, V> {
+    // Moves all entries of the subtree in key order into out
+    fn into_entries(self, out: &mut Vec<(K, V)>) {
+        if self.children.is_empty() {
+            out.extend(self.keys.into_iter().zip(self.values));
+        } else {
+            for child in self.children {
+                child.into_entries(out);
+            }
+        }
+    }
+}
+
+// Splits count items into as few groups of at most max items as possible, the groups differ by at most one item.
+// This way every group of a split level keeps at least half of max items.
+fn group_sizes(count: usize, max: usize) -> impl Iterator<Item = usize> {
+    let groups = count.div_ceil(max);
+    (0..groups).map(move |i| count / groups + usize::from(i < count % groups))
+}
+
+// Sorts the entries by key, the last value of a duplicate key wins
+fn sorted_without_duplicates<K: Ord, V>(mut entries: Vec<(K, V)>) -> Vec<(K, V)> {
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut sorted: Vec<(K, V)> = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        match sorted.last_mut() {
+            Some(last) if last.0 == key => last.1 = value,
+            _ => sorted.push((key, value)),
+        }
+    }
+    sorted
+}
+
+impl<K: Ord + Clone + std::fmt::Debug, V: std::fmt::Debug> Node<K, V> {
     fn depth(&self, level: u16) -> u16 {
         let first = self.children.first();
 
@@ -39,7 +74,13 @@ impl<V: std::fmt::Debug> Node<V> {
     }
 
     pub fn min_keys(&self) -> usize {
-        (self.max_keys() as f32 / 2.0).ceil() as usize
+        if self.is_leaf() {
+            (self.max_keys() as f32 / 2.0).ceil() as usize
+        } else {
+            // merging internal nodes pulls down the separator from the parent,
+            // so two minimal nodes plus the separator must fit into a single node
+            (self.max_keys() - 1) / 2
+        }
     }
 
     pub fn max_keys(&self) -> usize {
@@ -51,24 +92,24 @@ impl<V: std::fmt::Debug> Node<V> {
     }
 
     #[cfg(test)]
-    fn validate(&self, min_key: Option<u32>, max_key: Option<u32>) {
+    fn validate(&self, min_key: Option<&K>, max_key: Option<&K>) {
         self.check_node_invariants();
         if let Some(min_key) = min_key {
-            assert!(self.keys.iter().all(|k| *k >= min_key), "All Keys must be greater or equal than min_key. min_key: {}, keys:{:?}", min_key, self.keys);
+            assert!(self.keys.iter().all(|k| k >= min_key), "All Keys must be greater or equal than min_key. min_key: {:?}, keys:{:?}", min_key, self.keys);
         }
 
         if let Some(max_key) = max_key {
-            assert!(self.keys.iter().all(|k| *k < max_key), "All Keys must be less than max_key. max_key: {}, keys:{:?}", max_key, self.keys);
+            assert!(self.keys.iter().all(|k| k < max_key), "All Keys must be less than max_key. max_key: {:?}, keys:{:?}", max_key, self.keys);
         }
 
         for i in 0..self.children.len() {
             let child_min = match i {
                 0 => min_key,
-                _ => Some(self.keys[i - 1]),
+                _ => Some(&self.keys[i - 1]),
             };
 
             let child_max = match i {
-                i if i < self.keys.len() => Some(self.keys[i]),
+                i if i < self.keys.len() => Some(&self.keys[i]),
                 _ => max_key,
             };
 
@@ -97,7 +138,7 @@ impl<V: std::fmt::Debug> Node<V> {
     }
 
     // returns new left node, new right node and the key (K) for the parent
-    fn split(&mut self) -> (Node<V>, Node<V>, u32) {
+    fn split(&mut self) -> (Node<K, V>, Node<K, V>, K) {
         // check invariants before split
         let middle_value_index = self.keys.len() / 2;
 
@@ -119,7 +160,7 @@ impl<V: std::fmt::Debug> Node<V> {
             right_values = self.values.split_off(middle_value_index);
             left_values = mem::take(&mut self.values);
 
-            promoted_key = right_keys[0]; // Key stays in right node and promotes
+            promoted_key = right_keys[0].clone(); // Key stays in right node and promotes
         }
         let left_keys = mem::take(&mut self.keys);
 
@@ -142,9 +183,9 @@ impl<V: std::fmt::Debug> Node<V> {
         (left_node, right_node, promoted_key)
     }
 
-    fn find_key_index(&self, key: u32) -> FindKeyResponse {
+    fn find_key_index(&self, key: &K) -> FindKeyResponse {
         // TODO: replace with binary search
-        for (i, &k) in self.keys.iter().enumerate() {
+        for (i, k) in self.keys.iter().enumerate() {
             if key < k {
                 return FindKeyResponse::LessThan(i);
             } else if key == k {
@@ -155,27 +196,33 @@ impl<V: std::fmt::Debug> Node<V> {
         FindKeyResponse::GreaterThanTheLast(self.keys.len().saturating_sub(1))
     }
 
-    fn insert_key_value(&mut self, key: u32, value: V) {
-        match self.find_key_index(key) {
+    // Returns the previous value of an existing key. If overwrite is not set, the key and value are handed back instead.
+    fn insert_key_value(&mut self, key: K, value: V, overwrite: bool) -> Result<Option<V>, (K, V)> {
+        let previous = match self.find_key_index(&key) {
             FindKeyResponse::LessThan(i) => {
                 self.keys.insert(i, key);
                 self.values.insert(i, value);
+                None
             },
             FindKeyResponse::GreaterThanTheLast(_) => {
                 self.keys.push(key);
                 self.values.push(value);
+                None
             },
-            FindKeyResponse::Equal(_) => {},
-        }      
+            FindKeyResponse::Equal(i) if overwrite => Some(mem::replace(&mut self.values[i], value)),
+            FindKeyResponse::Equal(_) => return Err((key, value)),
+        };
  
         #[cfg(test)]
         self.check_node_invariants();
+
+        Ok(previous)
     }
     
-    pub fn insert(&mut self, key: u32, value: V) {
+    pub fn insert(&mut self, key: K, value: V, overwrite: bool) -> Result<Option<V>, (K, V)> {
         // if is leaf, then insert key and value
         if self.is_leaf() {
-            self.insert_key_value(key, value); 
+            self.insert_key_value(key, value, overwrite)
         } else {
             // if not leaf:
 
@@ -188,27 +235,19 @@ impl<V: std::fmt::Debug> Node<V> {
             // 2. if Node is full, split
             if self.children[node_index].is_full() {
                     let (lnode, rnode, new_key) = self.children[node_index].split();
-                    if self.keys.len() == node_index {
-                        // append at the end
-                        self.keys.push(new_key);
-                        self.children[node_index] = lnode;
-                        self.children.push(rnode);
-
-                        if key > new_key {
-                            node_index += 1;
-                        }
-                    } else {
-                        self.keys.insert(node_index, new_key);
-                        self.children.insert(node_index, rnode);
-                        self.children.insert(node_index, lnode);
-                        if key < new_key {
-                            node_index -= 1;
-                        }
+                    // left node replaces the split child, right node is placed behind it
+                    self.children[node_index] = lnode;
+                    self.children.insert(node_index + 1, rnode);
+                    let insert_right = key >= new_key;
+                    self.keys.insert(node_index, new_key);
+
+                    if insert_right {
+                        node_index += 1;
                     }
             }
         
             // 3. insert into next node
-            self.children[node_index].insert(key, value);
+            self.children[node_index].insert(key, value, overwrite)
         }
     }
 
@@ -224,7 +263,17 @@ impl<V: std::fmt::Debug> Node<V> {
         self.keys.len() < self.min_keys()
     }
 
-    pub fn find(&self, key: u32) -> Option<&V> {
+    // Leaves are refilled lazily (below the minimum), internal nodes already at the minimum.
+    // An internal node loses a key whenever two of its children are merged, this way it never runs out of keys.
+    pub fn needs_refill(&self) -> bool {
+        if self.is_leaf() {
+            self.is_less_than_minimal()
+        } else {
+            !self.can_lend_keys()
+        }
+    }
+
+    pub fn find(&self, key: &K) -> Option<&V> {
         match self.find_key_index(key) {
             // is leaf
             FindKeyResponse::GreaterThanTheLast(_) if self.is_leaf() => None,
@@ -238,10 +287,10 @@ impl<V: std::fmt::Debug> Node<V> {
     }
 
     // Delete a key from this subtree. Returns the removed value if present.
-    pub fn delete(&mut self, key: u32) -> Option<V> {
+    pub fn delete(&mut self, key: &K) -> Option<V> {
         if self.is_leaf() {
             // try to find key in this leaf
-            if let Some(pos) = self.keys.iter().position(|k| *k == key) {
+            if let Some(pos) = self.keys.iter().position(|k| k == key) {
                 let _k = self.keys.remove(pos);
                 let v = self.values.remove(pos);
                 return Some(v);
@@ -250,13 +299,13 @@ impl<V: std::fmt::Debug> Node<V> {
         }
 
         let mut node_index = self.keys.iter().enumerate()
-            .find(|(_, k)| key < **k)
+            .find(|(_, k)| key < *k)
             .map(|(i, _)| i)
             .unwrap_or(self.children.len() - 1);
 
         // Refactoring: 
         // self.merge(node_index)
-        if self.children[node_index].is_less_than_minimal() {
+        if self.children[node_index].needs_refill() {
             if node_index > 0 && self.children[node_index - 1].can_lend_keys() {
                 // split the children slice to get two non-overlapping mutable refs
                 let (left_slice, right_slice) = self.children.split_at_mut(node_index);
@@ -268,14 +317,13 @@ impl<V: std::fmt::Debug> Node<V> {
                     let v = left.values.pop().unwrap();
                     child.keys.insert(0, k);
                     child.values.insert(0, v);
-                    self.keys[node_index - 1] = child.keys[0];
+                    self.keys[node_index - 1] = child.keys[0].clone();
                 } else {
                     let left_key = left.keys.pop().unwrap();
                     let left_child = left.children.pop().unwrap();
-                    let parent_key = self.keys[node_index - 1];
+                    let parent_key = mem::replace(&mut self.keys[node_index - 1], left_key);
                     child.keys.insert(0, parent_key);
                     child.children.insert(0, left_child);
-                    self.keys[node_index - 1] = left_key;
                 }
             } else if node_index + 1 < self.children.len() && self.children[node_index + 1].can_lend_keys() {
                 // borrow from right sibling using split_at_mut with position node_index+1
@@ -288,14 +336,13 @@ impl<V: std::fmt::Debug> Node<V> {
                     let v = right.values.remove(0);
                     child.keys.push(k);
                     child.values.push(v);
-                    self.keys[node_index] = right.keys[0];
+                    self.keys[node_index] = right.keys[0].clone();
                 } else {
                     let right_key = right.keys.remove(0);
                     let right_child = right.children.remove(0);
-                    let parent_key = self.keys[node_index];
+                    let parent_key = mem::replace(&mut self.keys[node_index], right_key);
                     child.keys.push(parent_key);
                     child.children.push(right_child);
-                    self.keys[node_index] = right_key;
                 }
             } else {
                 // must merge with a sibling
@@ -312,8 +359,8 @@ impl<V: std::fmt::Debug> Node<V> {
                         let sep = self.keys.remove(left_index);
                         left_node.keys.push(sep);
                         // TODO: use std::mem:take here? Or everywhere drain?
-                        left_node.keys.extend(right_node.keys.drain(..));
-                        left_node.children.extend(right_node.children.drain(..));
+                        left_node.keys.append(&mut right_node.keys);
+                        left_node.children.append(&mut right_node.children);
                     }
 
                     node_index = left_index;
@@ -321,14 +368,14 @@ impl<V: std::fmt::Debug> Node<V> {
                     // merge child and right sibling
                     let mut right_node = self.children.remove(node_index + 1);
                     let new_separator = self.keys.remove(node_index);
-                    let child_node: &mut Node<V> = &mut self.children[node_index];
+                    let child_node: &mut Node<K, V> = &mut self.children[node_index];
                     if child_node.is_leaf() {
-                        child_node.keys.extend(right_node.keys.drain(..));
-                        child_node.values.extend(right_node.values.drain(..));
+                        child_node.keys.append(&mut right_node.keys);
+                        child_node.values.append(&mut right_node.values);
                     } else {
                         child_node.keys.push(new_separator);
-                        child_node.keys.extend(right_node.keys.drain(..));
-                        child_node.children.extend(right_node.children.drain(..));
+                        child_node.keys.append(&mut right_node.keys);
+                        child_node.children.append(&mut right_node.children);
                     }
                 }
             }
@@ -338,21 +385,234 @@ impl<V: std::fmt::Debug> Node<V> {
     }
 }
 
+// Position inside the tree: path from the root to a leaf and an index into that leaf
+struct Cursor<'a, K, V> {
+    path: Vec<(&'a Node<K, V>, usize)>, // internal nodes and the index of the child taken
+    leaf: &'a Node<K, V>,
+    index: usize,
+}
+
+impl<'a, K: Ord, V> Cursor<'a, K, V> {
+    // front cursors point at the next entry, back cursors behind the next entry (index - 1)
+    fn seek(root: &'a Node<K, V>, bound: Bound<&K>, back: bool) -> Self {
+        let mut path = Vec::new();
+        let mut node = root;
+
+        while !node.children.is_empty() {
+            let child_index = match bound {
+                Bound::Included(key) | Bound::Excluded(key) => node.keys.partition_point(|k| k <= key),
+                Bound::Unbounded if back => node.children.len() - 1,
+                Bound::Unbounded => 0,
+            };
+            path.push((node, child_index));
+            node = &node.children[child_index];
+        }
+
+        let index = match bound {
+            Bound::Included(key) if back => node.keys.partition_point(|k| k <= key),
+            Bound::Included(key) => node.keys.partition_point(|k| k < key),
+            Bound::Excluded(key) if back => node.keys.partition_point(|k| k < key),
+            Bound::Excluded(key) => node.keys.partition_point(|k| k <= key),
+            Bound::Unbounded if back => node.keys.len(),
+            Bound::Unbounded => 0,
+        };
+
+        Cursor { path, leaf: node, index }
+    }
+
+    fn next_leaf(&mut self) -> bool {
+        while let Some((node, child_index)) = self.path.pop() {
+            if child_index + 1 < node.children.len() {
+                self.path.push((node, child_index + 1));
+                let mut child = &node.children[child_index + 1];
+                while !child.children.is_empty() {
+                    self.path.push((child, 0));
+                    child = &child.children[0];
+                }
+                self.leaf = child;
+                self.index = 0;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn previous_leaf(&mut self) -> bool {
+        while let Some((node, child_index)) = self.path.pop() {
+            if child_index > 0 {
+                self.path.push((node, child_index - 1));
+                let mut child = &node.children[child_index - 1];
+                while !child.children.is_empty() {
+                    self.path.push((child, child.children.len() - 1));
+                    child = &child.children[child.children.len() - 1];
+                }
+                self.leaf = child;
+                self.index = child.keys.len();
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // moves a front cursor to the next existing entry, returns false if there is none
+    fn settle_front(&mut self) -> bool {
+        while self.index >= self.leaf.keys.len() {
+            if !self.next_leaf() {
+                return false;
+            }
+        }
+        true
+    }
+
+    // moves a back cursor behind the previous existing entry, returns false if there is none
+    fn settle_back(&mut self) -> bool {
+        while self.index == 0 {
+            if !self.previous_leaf() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Double ended iterator over the leaves in key order, created by BTree::iter and BTree::range
+pub struct Iter<'a, K, V> {
+    front: Option<Cursor<'a, K, V>>,
+    back: Option<Cursor<'a, K, V>>,
+}
+
+impl<'a, K: Ord, V> Iter<'a, K, V> {
+    fn new(root: &'a Node<K, V>, start: Bound<&K>, end: Bound<&K>) -> Self {
+        let mut front = Cursor::seek(root, start, false);
+        let mut back = Cursor::seek(root, end, true);
+
+        Iter {
+            front: front.settle_front().then_some(front),
+            back: back.settle_back().then_some(back),
+        }
+    }
+
+    // front and back have met, if the next entry of the front lies behind the next entry of the back
+    fn is_exhausted(&self) -> bool {
+        match (&self.front, &self.back) {
+            (Some(front), Some(back)) => front.leaf.keys[front.index] > back.leaf.keys[back.index - 1],
+            _ => true,
+        }
+    }
+}
+
+impl<'a, K: Ord, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_exhausted() {
+            self.front = None;
+            self.back = None;
+            return None;
+        }
+
+        let front = self.front.as_mut()?;
+        let (leaf, index) = (front.leaf, front.index);
+        front.index += 1;
+        if !front.settle_front() {
+            self.front = None;
+        }
+
+        Some((&leaf.keys[index], &leaf.values[index]))
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Iter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.is_exhausted() {
+            self.front = None;
+            self.back = None;
+            return None;
+        }
+
+        let back = self.back.as_mut()?;
+        let (leaf, index) = (back.leaf, back.index - 1);
+        back.index -= 1;
+        if !back.settle_back() {
+            self.back = None;
+        }
+
+        Some((&leaf.keys[index], &leaf.values[index]))
+    }
+}
+
+impl<K: Ord, V> FusedIterator for Iter<'_, K, V> {}
+
+// Returned by try_insert, holds the key and the value, which have not been inserted
+#[derive(Debug, Error, PartialEq)]
+#[error("Key {key:?} already exists")]
+pub struct DuplicateKeyError<K, V> {
+    pub key: K,
+    pub value: V,
+}
+
 // Preemptive B+ Tree
 #[derive(Debug)]
-pub struct BTree<V> {
-    root: Node<V>,
+pub struct BTree<K, V> {
+    root: Node<K, V>,
     max_degree: usize, // number of children (max keys are: max_degree - 1, min keys are: )
+    len: usize,
 }
 
-impl<V: Default + std::fmt::Debug> BTree<V> {
+impl<K: Ord + Clone + std::fmt::Debug, V: Default + std::fmt::Debug> BTree<K, V> {
     pub fn new(max_degree: usize) -> Self {
         BTree { 
             root: Node::new(max_degree), 
             max_degree,
+            len: 0,
         }
     }
 
+    // Builds the tree from entries in ascending key order (without duplicates) in linear time:
+    // the leaves are filled from left to right, then the internal levels are built bottom-up.
+    // Panics, if the keys are not sorted.
+    pub fn from_sorted_iter(max_degree: usize, iter: impl IntoIterator<Item = (K, V)>) -> Self {
+        let sorted_entries = iter.into_iter().collect::<Vec<_>>();
+        if let Some(pair) = sorted_entries.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+            panic!("Keys must be sorted in ascending order without duplicates: {:?} is followed by {:?}", pair[0].0, pair[1].0);
+        }
+        let count = sorted_entries.len();
+        if count < max_degree {
+            let mut root = Node::new(max_degree);
+            (root.keys, root.values) = sorted_entries.into_iter().unzip();
+            return BTree { root, max_degree, len: count };
+        }
+
+        // every node of a level is paired with the smallest key of its subtree, which becomes the separator in the parent
+        let mut entries = sorted_entries.into_iter();
+        let mut level = group_sizes(count, max_degree - 1).map(|size| {
+            let (keys, values): (Vec<K>, Vec<V>) = entries.by_ref().take(size).unzip();
+            let leaf = Node { values, keys, children: Vec::new(), max_degree, root: false };
+            (leaf.keys[0].clone(), leaf)
+        }).collect::<Vec<_>>();
+
+        while level.len() > 1 {
+            let count = level.len();
+            let mut nodes = level.into_iter();
+            level = group_sizes(count, max_degree).map(|size| {
+                let mut children = nodes.by_ref().take(size);
+                let (min_key, first_child) = children.next().unwrap();
+                let mut node = Node { values: Vec::new(), keys: Vec::new(), children: vec![first_child], max_degree, root: false };
+                for (key, child) in children {
+                    node.keys.push(key);
+                    node.children.push(child);
+                }
+                (min_key, node)
+            }).collect();
+        }
+
+        let (_, mut root) = level.pop().unwrap();
+        root.root = true;
+        BTree { root, max_degree, len: count }
+    }
+
 
     pub fn print_tree(&self) {
         let height = self.root.depth(0);
@@ -375,7 +635,7 @@ impl<V: Default + std::fmt::Debug> BTree<V> {
                 }
 
                 print!("[");
-                let keys = node.keys.iter().map(|k| k.to_string()).collect::<Vec<String>>().join(",");
+                let keys = node.keys.iter().map(|k| format!("{:?}", k)).collect::<Vec<String>>().join(",");
                 print!("{}", keys);
                 print!("]");
 
@@ -396,11 +656,31 @@ impl<V: Default + std::fmt::Debug> BTree<V> {
         self.root.validate(None, None);
     }
 
-    pub fn find(&self, key: u32) -> Option<&V> {
+    pub fn find(&self, key: &K) -> Option<&V> {
         self.root.find(key)
     }
 
-    pub fn insert(&mut self, key: u32, value: V) {
+    // Inserts or overwrites the value of the key. Returns the previous value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.insert_with(key, value, true) {
+            Ok(previous) => previous,
+            Err(_) => unreachable!("An existing key is overwritten"),
+        }
+    }
+
+    // Keeps the value of an existing key. Returns true, if the key has been inserted.
+    pub fn insert_if_absent(&mut self, key: K, value: V) -> bool {
+        self.insert_with(key, value, false).is_ok()
+    }
+
+    // Fails, if the key already exists. The error hands the key and the value back.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<(), DuplicateKeyError<K, V>> {
+        self.insert_with(key, value, false)
+            .map(|_| ())
+            .map_err(|(key, value)| DuplicateKeyError { key, value })
+    }
+
+    fn insert_with(&mut self, key: K, value: V, overwrite: bool) -> Result<Option<V>, (K, V)> {
         if self.root.is_full() {
             let (lnode, rnode, root_key) = self.root.split();
             let new_root = Node {
@@ -413,12 +693,18 @@ impl<V: Default + std::fmt::Debug> BTree<V> {
 
             self.root = new_root;
         }
-        self.root.insert(key, value);
-        // check invariants
+        let res = self.root.insert(key, value, overwrite);
+        if let Ok(None) = res {
+            self.len += 1;
+        }
+        res
     }
 
-    pub fn delete(&mut self, key: u32) -> Option<V> {
+    pub fn delete(&mut self, key: &K) -> Option<V> {
         let res = self.root.delete(key);
+        if res.is_some() {
+            self.len -= 1;
+        }
 
         // if root became internal node with no keys, collapse height
         if self.root.keys.is_empty() && !self.root.is_leaf() {
@@ -434,14 +720,107 @@ impl<V: Default + std::fmt::Debug> BTree<V> {
     }
 }
 
+impl<K: Ord, V> BTree<K, V> {
+    // Number of entries
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(&self.root, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    pub fn range(&self, range: impl RangeBounds<K>) -> Iter<'_, K, V> {
+        Iter::new(&self.root, range.start_bound(), range.end_bound())
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.iter().next()
+    }
+
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.iter().next_back()
+    }
+}
+
+// Trees collected from an iterator use this max degree
+pub const DEFAULT_MAX_DEGREE: usize = 16;
+
+// The entries are sorted first, the last value of a duplicate key wins (like BTreeMap)
+impl<K: Ord + Clone + std::fmt::Debug, V: Default + std::fmt::Debug> FromIterator<(K, V)> for BTree<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        BTree::from_sorted_iter(DEFAULT_MAX_DEGREE, sorted_without_duplicates(iter.into_iter().collect()))
+    }
+}
+
+// A new value replaces the value of an existing key. Few entries are inserted one by one, a large batch
+// (whose inserts would cost more than n log n) is merged with the existing entries and the tree is rebuilt bottom-up.
+impl<K: Ord + Clone + std::fmt::Debug, V: Default + std::fmt::Debug> Extend<(K, V)> for BTree<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let new_entries = iter.into_iter().collect::<Vec<_>>();
+        let insert_cost = new_entries.len() * self.len.checked_ilog2().unwrap_or(0) as usize;
+        if insert_cost <= self.len && !self.is_empty() {
+            for (key, value) in new_entries {
+                self.insert(key, value);
+            }
+            return;
+        }
+
+        let new_entries = sorted_without_duplicates(new_entries);
+        if new_entries.is_empty() {
+            return;
+        }
+
+        let mut old_entries = Vec::new();
+        mem::replace(&mut self.root, Node::new(self.max_degree)).into_entries(&mut old_entries);
+
+        let mut merged = Vec::with_capacity(old_entries.len() + new_entries.len());
+        let mut old_entries = old_entries.into_iter().peekable();
+        for (key, value) in new_entries {
+            while let Some(old) = old_entries.next_if(|(old_key, _)| *old_key <= key) {
+                if old.0 < key {
+                    merged.push(old);
+                }
+            }
+            merged.push((key, value));
+        }
+        merged.extend(old_entries);
+
+        *self = BTree::from_sorted_iter(self.max_degree, merged);
+    }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a BTree<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::BTree;
+    use std::{collections::{BTreeMap, btree_map::Entry}, ops::Bound};
+
+    use super::{BTree, DEFAULT_MAX_DEGREE, DuplicateKeyError};
 
 
     #[test]
     fn init_and_add_values() {
-        let mut btree =BTree::<i32>::new(4);
+        let mut btree =BTree::<u32, i32>::new(4);
         btree.insert(10, 10);
         btree.insert(5, 5);
         btree.insert(80, 80);
@@ -452,7 +831,7 @@ mod tests {
 
     #[test]
     fn split_root() {
-        let mut btree =BTree::<i32>::new(4);
+        let mut btree =BTree::<u32, i32>::new(4);
         btree.insert(1, 1);
         btree.insert(50, 50);
         btree.insert(100, 100);
@@ -469,7 +848,7 @@ mod tests {
 
     #[test]
     fn find_and_delete() {
-        let mut btree =BTree::<i32>::new(4);
+        let mut btree =BTree::<u32, i32>::new(4);
         btree.insert(1, 1);
         btree.insert(50, 50);
         btree.insert(100, 100);
@@ -482,25 +861,279 @@ mod tests {
         btree.insert(60, 60);
         btree.insert(65, 65);
 
-        let val = btree.find(55);
+        let val = btree.find(&55);
         assert!(val.is_some());
         assert_eq!(*val.unwrap(), 55);
 
-        btree.delete(55);
+        btree.delete(&55);
 
-        let val = btree.find(55);
+        let val = btree.find(&55);
         assert!(val.is_none());
 
-        let val = btree.find(200);
+        let val = btree.find(&200);
         assert!(val.is_some());
         assert_eq!(*val.unwrap(), 200);
 
-        let val = btree.find(4);
+        let val = btree.find(&4);
         assert!(val.is_none());
 
-        let val = btree.find(1);
+        let val = btree.find(&1);
         assert!(val.is_some());
         assert_eq!(*val.unwrap(), 1);
     }
-}
 
+    #[test]
+    fn find_after_scrambled_inserts_and_deletes() {
+        // splits in the middle of a node and merges of internal nodes, which are at their minimum
+        for max_degree in 4..8 {
+            let mut btree = BTree::<u32, u32>::new(max_degree);
+            for i in 0..300 {
+                let key = (i * 37) % 300;
+                btree.insert(key, key + 1);
+            }
+            btree.validate();
+            for key in (0..300).filter(|key| key % 3 != 0) {
+                assert_eq!(btree.delete(&key), Some(key + 1), "max_degree {}", max_degree);
+            }
+            btree.validate();
+            for key in 0..300 {
+                assert_eq!(btree.find(&key).copied(), (key % 3 == 0).then_some(key + 1), "max_degree {}", max_degree);
+            }
+        }
+    }
+
+    fn btree_with_keys(keys: impl Iterator<Item = u32>) -> BTree<u32, u32> {
+        let mut btree = BTree::<u32, u32>::new(4);
+        for key in keys {
+            btree.insert(key, key * 10);
+        }
+        btree
+    }
+
+    #[test]
+    fn iterate_in_key_order() {
+        // scrambled insert order splits nodes in the middle of the tree
+        let btree = btree_with_keys((0..100).map(|i| (i * 37) % 100));
+        btree.validate();
+
+        let entries = btree.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        assert_eq!(entries, (0..100).map(|k| (k, k * 10)).collect::<Vec<_>>());
+
+        let keys = btree.keys().rev().copied().collect::<Vec<_>>();
+        assert_eq!(keys, (0..100).rev().collect::<Vec<_>>());
+
+        let values = btree.values().copied().collect::<Vec<_>>();
+        assert_eq!(values, (0..100).map(|k| k * 10).collect::<Vec<_>>());
+
+        assert_eq!(btree.first_key_value(), Some((&0, &0)));
+        assert_eq!(btree.last_key_value(), Some((&99, &990)));
+        assert_eq!((&btree).into_iter().count(), 100);
+    }
+
+    #[test]
+    fn range_queries() {
+        let btree = btree_with_keys((0..50).map(|i| i * 2));
+
+        let keys = btree.range(10..20).map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(keys, vec![10, 12, 14, 16, 18]);
+
+        let keys = btree.range(11..=20).map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(keys, vec![12, 14, 16, 18, 20]);
+
+        let keys = btree.range((Bound::Excluded(10), Bound::Excluded(16))).rev().map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(keys, vec![14, 12]);
+
+        let keys = btree.range(..5).map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(keys, vec![0, 2, 4]);
+
+        let keys = btree.range(95..).map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(keys, vec![96, 98]);
+
+        assert_eq!(btree.range(13..14).count(), 0);
+        assert_eq!(btree.range(200..).count(), 0);
+    }
+
+    #[test]
+    fn iterate_from_both_ends() {
+        let btree = btree_with_keys(1..=7);
+        let mut iter = btree.iter();
+
+        assert_eq!(iter.next().map(|(k, _)| *k), Some(1));
+        assert_eq!(iter.next_back().map(|(k, _)| *k), Some(7));
+        assert_eq!(iter.next_back().map(|(k, _)| *k), Some(6));
+        assert_eq!(iter.next().map(|(k, _)| *k), Some(2));
+        assert_eq!(iter.next().map(|(k, _)| *k), Some(3));
+        assert_eq!(iter.next_back().map(|(k, _)| *k), Some(5));
+        assert_eq!(iter.next().map(|(k, _)| *k), Some(4));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn iterate_empty_tree() {
+        let btree = BTree::<u32, i32>::new(4);
+        assert_eq!(btree.iter().count(), 0);
+        assert_eq!(btree.range(1..10).count(), 0);
+        assert_eq!(btree.first_key_value(), None);
+        assert_eq!(btree.last_key_value(), None);
+    }
+
+    #[test]
+    fn matches_btreemap_after_mixed_operations() {
+        for max_degree in 4..8 {
+            let mut btree = BTree::<u32, u32>::new(max_degree);
+            let mut expected = BTreeMap::new();
+
+            let mut seed: u32 = 7;
+            for _ in 0..2000 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let key = (seed >> 16) % 300;
+                if seed.is_multiple_of(3) {
+                    assert_eq!(btree.delete(&key), expected.remove(&key));
+                } else if let Entry::Vacant(e) = expected.entry(key) {
+                    e.insert(seed);
+                    btree.insert(key, seed);
+                }
+            }
+            btree.validate();
+
+            assert!(btree.iter().eq(expected.iter()));
+            assert!(btree.range(50..150).rev().eq(expected.range(50..150).rev()));
+            assert_eq!(btree.len(), expected.len());
+        }
+    }
+
+    #[test]
+    fn insert_overwrites_existing_keys() {
+        let mut btree = btree_with_keys(0..20);
+        assert_eq!(btree.insert(7, 700), Some(70));
+        assert_eq!(btree.insert(7, 7000), Some(700));
+        assert_eq!(btree.insert(20, 200), None);
+        btree.validate();
+
+        assert_eq!(btree.find(&7), Some(&7000));
+        assert_eq!(btree.iter().count(), 21);
+    }
+
+    #[test]
+    fn insert_if_absent_keeps_existing_values() {
+        let mut btree = btree_with_keys(0..20);
+        assert!(!btree.insert_if_absent(7, 700));
+        assert!(btree.insert_if_absent(25, 250));
+        btree.validate();
+
+        assert_eq!(btree.find(&7), Some(&70));
+        assert_eq!(btree.find(&25), Some(&250));
+    }
+
+    #[test]
+    fn try_insert_rejects_duplicate_keys() {
+        let mut btree = btree_with_keys(0..20);
+        assert_eq!(btree.try_insert(7, 700), Err(DuplicateKeyError { key: 7, value: 700 }));
+        assert_eq!(btree.try_insert(30, 300), Ok(()));
+        btree.validate();
+
+        assert_eq!(btree.find(&7), Some(&70));
+        assert_eq!(btree.find(&30), Some(&300));
+    }
+
+    #[test]
+    fn from_sorted_iter_builds_a_valid_tree() {
+        for max_degree in 4..10 {
+            for count in [0, 1, max_degree - 1, max_degree, 2 * max_degree + 1, 1000] {
+                let btree = BTree::from_sorted_iter(max_degree, (0..count as u32).map(|k| (k, k * 10)));
+                if count > 0 {
+                    btree.validate();
+                }
+                assert!(btree.iter().map(|(k, v)| (*k, *v)).eq((0..count as u32).map(|k| (k, k * 10))));
+            }
+        }
+
+        // the loaded tree supports all operations
+        let mut btree = BTree::from_sorted_iter(4, (0..100).map(|k| (k * 2, k)));
+        let mut expected = (0..100).map(|k| (k * 2, k)).collect::<BTreeMap<_, _>>();
+        for key in (0..200).step_by(3) {
+            if key % 2 == 0 {
+                assert_eq!(btree.delete(&key), expected.remove(&key));
+            } else {
+                assert_eq!(btree.insert(key, key), expected.insert(key, key));
+            }
+            btree.validate();
+        }
+        assert!(btree.iter().eq(expected.iter()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Keys must be sorted")]
+    fn from_sorted_iter_rejects_unsorted_keys() {
+        BTree::from_sorted_iter(4, [(1, 1), (3, 3), (2, 2)]);
+    }
+
+    #[test]
+    fn collect_and_extend() {
+        let mut btree = [(5, "e"), (1, "a"), (3, "x"), (3, "c")].into_iter().collect::<BTree<u32, &str>>();
+        btree.validate();
+        assert!(btree.iter().map(|(k, v)| (*k, *v)).eq([(1, "a"), (3, "c"), (5, "e")]));
+        assert_eq!(btree.max_degree, DEFAULT_MAX_DEGREE);
+
+        // few entries are inserted, many entries rebuild the tree
+        btree.extend([(4, "d"), (1, "z"), (2, "b")]);
+        btree.validate();
+        assert!(btree.iter().map(|(k, v)| (*k, *v)).eq([(1, "z"), (2, "b"), (3, "c"), (4, "d"), (5, "e")]));
+        assert_eq!(btree.len(), 5);
+
+        let mut btree = btree_with_keys(0..50);
+        btree.extend((25..100).rev().map(|k| (k, k)));
+        btree.validate();
+        let expected = (0..25).map(|k| (k, k * 10)).chain((25..100).map(|k| (k, k)));
+        assert!(btree.iter().map(|(k, v)| (*k, *v)).eq(expected));
+        assert_eq!(btree.len(), 100);
+
+        btree.extend([(7, 0), (150, 150), (150, 151)]);
+        btree.validate();
+        assert_eq!(btree.find(&7), Some(&0));
+        assert_eq!(btree.find(&150), Some(&151));
+        assert_eq!(btree.len(), 101);
+        assert_eq!(btree.delete(&7), Some(0));
+        assert_eq!(btree.delete(&7), None);
+        assert_eq!(btree.len(), 100);
+    }
+
+    #[test]
+    fn string_keys() {
+        let mut btree = BTree::<String, usize>::new(4);
+        let names = ["mallory", "alice", "trent", "bob", "eve", "carol", "dave", "peggy", "victor", "oscar"];
+        for (i, name) in names.iter().enumerate() {
+            btree.insert(name.to_string(), i);
+        }
+        btree.validate();
+
+        assert_eq!(btree.find(&"eve".to_string()), Some(&4));
+        assert_eq!(btree.find(&"zoe".to_string()), None);
+
+        let mut sorted = names.to_vec();
+        sorted.sort();
+        assert!(btree.keys().eq(sorted.iter().map(|n| n.to_string()).collect::<Vec<_>>().iter()));
+
+        assert_eq!(btree.delete(&"alice".to_string()), Some(1));
+        assert_eq!(btree.first_key_value(), Some((&"bob".to_string(), &3)));
+        btree.validate();
+    }
+
+    #[test]
+    fn composite_and_signed_keys() {
+        let mut btree = BTree::<(i64, u8), &str>::new(5);
+        btree.insert((-1_700_000_000, 2), "c");
+        btree.insert((-1_700_000_000, 1), "b");
+        btree.insert((i64::MIN, 0), "a");
+        btree.insert((1_700_000_000, 0), "e");
+        btree.insert((0, 9), "d");
+        btree.insert((i64::MAX, 0), "f");
+        btree.validate();
+
+        assert!(btree.values().copied().eq(["a", "b", "c", "d", "e", "f"]));
+
+        let range = btree.range((-1_700_000_000, 0)..(0, 0)).map(|(_, v)| *v).collect::<Vec<_>>();
+        assert_eq!(range, vec!["b", "c"]);
+    }
+}